@@ -17,6 +17,7 @@ use serde::Serialize;
 use tokio::{sync::Semaphore, task, time};
 use walkdir::{DirEntry, WalkDir};
 
+use super::EvictionStats;
 use crate::image::{ImageOutput, ProcessOptions};
 
 #[derive(Clone)]
@@ -29,6 +30,8 @@ struct Inner {
     sema: Semaphore,
     max_size: u64,
     cur_size: AtomicU64,
+    evictions: AtomicU64,
+    evicted_bytes: AtomicU64,
 }
 
 impl DiskCache {
@@ -43,6 +46,8 @@ impl DiskCache {
                 sema: Semaphore::new(128),
                 max_size,
                 cur_size: AtomicU64::new(0),
+                evictions: AtomicU64::new(0),
+                evicted_bytes: AtomicU64::new(0),
             }),
         };
         task::spawn_blocking(move || std::fs::create_dir_all(path)).await??;
@@ -64,6 +69,14 @@ impl DiskCache {
         Ok(())
     }
 
+    /// Returns the files removed by the cleaner to stay within the maximum size.
+    pub fn eviction_stats(&self) -> EvictionStats {
+        EvictionStats {
+            evictions: self.inner.evictions.load(Ordering::Relaxed),
+            evicted_bytes: self.inner.evicted_bytes.load(Ordering::Relaxed),
+        }
+    }
+
     fn start_cleaner(&self) {
         let this = self.clone();
         task::spawn(async move {
@@ -134,6 +147,8 @@ impl DiskCache {
         for (entry, meta) in candidates.into_iter().take(10) {
             let size = meta.len();
             if std::fs::remove_file(entry.path()).is_ok() {
+                self.inner.evictions.fetch_add(1, Ordering::Relaxed);
+                self.inner.evicted_bytes.fetch_add(size, Ordering::Relaxed);
                 removed += size;
                 if removed >= to_remove {
                     break;
@@ -189,7 +204,7 @@ impl DiskCache {
     }
 
     fn get_inner(path: PathBuf) -> Result<Option<ImageOutput>> {
-        let data = std::fs::read(path).map(Some).or_else(|err| {
+        let data = std::fs::read(&path).map(Some).or_else(|err| {
             if err.kind() == std::io::ErrorKind::NotFound {
                 Ok(None)
             } else {
@@ -209,6 +224,9 @@ impl DiskCache {
         }
 
         let mut output: ImageOutput = serde_json::from_slice(&data[4..4 + meta_length])?;
+        if let Ok(modified) = std::fs::metadata(&path).and_then(|meta| meta.modified()) {
+            output.created = modified;
+        }
         let data = Bytes::from(data);
         output.buf = data.slice(4 + meta_length..);
         Ok(Some(output))
@@ -232,8 +250,14 @@ impl DiskCache {
     }
 
     fn get_file_path(&self, input: &str, ops: ProcessOptions) -> PathBuf {
+        self.inner.dir.join(Self::relative_path(input, ops))
+    }
+
+    /// Returns the path of the cache entry for the input and options, relative
+    /// to the cache directory.
+    pub fn relative_path(input: &str, ops: ProcessOptions) -> PathBuf {
         let hash = Self::get_hash(input, ops).to_hex();
-        let mut path = self.inner.dir.clone();
+        let mut path = PathBuf::new();
         path.push(&hash.as_str()[hash.len() - 1..]);
         path.push(&hash.as_str()[hash.len() - 3..hash.len() - 1]);
         path.push(hash.as_str());
@@ -281,3 +305,53 @@ fn metadata_sort_key((_, meta): &(DirEntry, Metadata)) -> Option<SystemTime> {
         .or_else(|| meta.modified().ok())
         .or_else(|| meta.created().ok())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{ImageType, InputImageType};
+
+    #[tokio::test]
+    async fn test_eviction_stats() {
+        // The cache is created without its cleaner, so that the test controls
+        // when files are removed.
+        let dir = std::env::temp_dir().join(format!("imaged-disk-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = DiskCache {
+            inner: Arc::new(Inner {
+                dir: dir.clone(),
+                sema: Semaphore::new(1),
+                max_size: 1024,
+                cur_size: AtomicU64::new(0),
+                evictions: AtomicU64::new(0),
+                evicted_bytes: AtomicU64::new(0),
+            }),
+        };
+        let output = ImageOutput {
+            buf: vec![0; 256].into(),
+            img_type: ImageType::Png,
+            width: 1,
+            height: 1,
+            orig_size: 256,
+            orig_type: InputImageType::Png,
+            orig_width: 1,
+            orig_height: 1,
+            created: SystemTime::now(),
+        };
+        let ops = ProcessOptions::default();
+        for i in 0..8 {
+            let input = format!("img-{i}");
+            cache.set(&input, ops, output.clone()).await.unwrap();
+        }
+        assert!(cache.inner.cur_size.load(Ordering::Acquire) > 1024);
+
+        // Filling past capacity removes files until it's back within it.
+        cache.clean().await;
+        let stats = cache.eviction_stats();
+        assert!(stats.evictions > 0);
+        assert!(stats.evicted_bytes > 256 * stats.evictions);
+        assert!(cache.inner.cur_size.load(Ordering::Acquire) <= 1024);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
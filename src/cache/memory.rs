@@ -1,7 +1,9 @@
 use std::{hash::Hash, sync::Mutex};
 
 use lru::LruCache;
+use serde::Serialize;
 
+use super::EvictionStats;
 use crate::image::{ImageOutput, ProcessOptions};
 
 pub struct MemoryCache {
@@ -19,6 +21,8 @@ impl MemoryCache {
                 lru: LruCache::unbounded(),
                 max: max_bytes,
                 size: 0,
+                evictions: EvictionStats::default(),
+                replacements: 0,
             }),
         }
     }
@@ -42,6 +46,7 @@ impl MemoryCache {
                 .size
                 .checked_sub(val.buf.len())
                 .expect("overflow replacing item in memory lru");
+            guard.replacements += 1;
         }
         while guard.size > guard.max {
             if let Some((_, val)) = guard.lru.pop_lru() {
@@ -49,17 +54,38 @@ impl MemoryCache {
                     .size
                     .checked_sub(val.buf.len())
                     .expect("overflow removing from memory lru");
+                guard.evictions.evictions += 1;
+                guard.evictions.evicted_bytes += val.buf.len() as u64;
             } else {
                 return;
             }
         }
     }
+
+    pub fn stats(&self) -> MemoryCacheStats {
+        let guard = self.mu.lock().unwrap();
+        MemoryCacheStats {
+            evictions: guard.evictions,
+            replacements: guard.replacements,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MemoryCacheStats {
+    /// The least recently used entries removed to stay within the maximum size.
+    #[serde(flatten)]
+    pub evictions: EvictionStats,
+    /// The number of entries overwritten by a newer image for the same key.
+    pub replacements: u64,
 }
 
 struct Inner {
     lru: LruCache<Key, ImageOutput>,
     max: usize,
     size: usize,
+    evictions: EvictionStats,
+    replacements: u64,
 }
 
 #[derive(Eq, Hash, PartialEq)]
@@ -67,3 +93,47 @@ struct Key {
     input: String,
     options: ProcessOptions,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::image::{ImageType, InputImageType};
+
+    fn output(len: usize) -> ImageOutput {
+        ImageOutput {
+            buf: vec![0; len].into(),
+            img_type: ImageType::Png,
+            width: 1,
+            height: 1,
+            orig_size: len as u64,
+            orig_type: InputImageType::Png,
+            orig_width: 1,
+            orig_height: 1,
+            created: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = MemoryCache::new(10);
+        let ops = ProcessOptions::default();
+        cache.set("a", ops, output(4));
+        cache.set("b", ops, output(4));
+        // Replacing an entry isn't counted as an eviction.
+        cache.set("b", ops, output(5));
+        let stats = cache.stats();
+        assert_eq!(stats.replacements, 1);
+        assert_eq!(stats.evictions.evictions, 0);
+
+        // Filling past capacity evicts the least recently used entries.
+        cache.set("c", ops, output(6));
+        let stats = cache.stats();
+        assert_eq!(stats.evictions.evictions, 2);
+        assert_eq!(stats.evictions.evicted_bytes, 9);
+        assert_eq!(stats.replacements, 1);
+        assert!(cache.get("a", ops).is_none() && cache.get("b", ops).is_none());
+        assert!(cache.get("c", ops).is_some());
+    }
+}
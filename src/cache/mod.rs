@@ -1,2 +1,13 @@
 pub mod disk;
 pub mod memory;
+pub mod source;
+
+use serde::Serialize;
+
+/// The number of entries, and their total size in bytes, that were removed
+/// from a cache to keep it within its maximum size.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct EvictionStats {
+    pub evictions: u64,
+    pub evicted_bytes: u64,
+}
@@ -0,0 +1,121 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use lru::LruCache;
+
+/// A short-lived cache of downloaded source images, keyed by URL. This allows
+/// a metadata request followed by an image request (or vice versa) to share a
+/// single fetch from the origin.
+pub struct SourceCache {
+    mu: Mutex<Inner>,
+    ttl: Duration,
+}
+
+impl SourceCache {
+    pub fn new(max_bytes: usize, ttl: Duration) -> Self {
+        assert!(
+            max_bytes > 0,
+            "maximum bytes for source cache must be greater than 0"
+        );
+        SourceCache {
+            mu: Mutex::new(Inner {
+                lru: LruCache::unbounded(),
+                max: max_bytes,
+                size: 0,
+            }),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Option<Bytes> {
+        let mut guard = self.mu.lock().unwrap();
+        let entry = guard.lru.get(url)?;
+        if entry.created.elapsed() <= self.ttl {
+            return Some(entry.buf.clone());
+        }
+
+        if let Some(entry) = guard.lru.pop(url) {
+            guard.size = guard
+                .size
+                .checked_sub(entry.buf.len())
+                .expect("overflow removing expired item from source lru");
+        }
+        None
+    }
+
+    pub fn set(&self, url: &str, buf: Bytes) {
+        let mut guard = self.mu.lock().unwrap();
+        if buf.len() > guard.max {
+            return;
+        }
+
+        guard.size += buf.len();
+        let entry = Entry {
+            buf,
+            created: Instant::now(),
+        };
+        if let Some(old) = guard.lru.put(url.to_owned(), entry) {
+            guard.size = guard
+                .size
+                .checked_sub(old.buf.len())
+                .expect("overflow replacing item in source lru");
+        }
+        while guard.size > guard.max {
+            if let Some((_, old)) = guard.lru.pop_lru() {
+                guard.size = guard
+                    .size
+                    .checked_sub(old.buf.len())
+                    .expect("overflow removing from source lru");
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+struct Inner {
+    lru: LruCache<String, Entry>,
+    max: usize,
+    size: usize,
+}
+
+struct Entry {
+    buf: Bytes,
+    created: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl() {
+        let cache = SourceCache::new(1024, Duration::from_millis(50));
+        cache.set("a", Bytes::from_static(b"aaaa"));
+        assert_eq!(cache.get("a").unwrap(), "aaaa");
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get("a").is_none());
+        // The expired entry no longer counts towards the size.
+        assert_eq!(cache.mu.lock().unwrap().size, 0);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let cache = SourceCache::new(8, Duration::from_secs(60));
+        cache.set("a", Bytes::from_static(b"aaaa"));
+        cache.set("b", Bytes::from_static(b"bbbb"));
+        cache.set("c", Bytes::from_static(b"cc"));
+        // The least recently used entry is evicted.
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some() && cache.get("c").is_some());
+
+        // Entries larger than the cache aren't stored.
+        cache.set("d", Bytes::from_static(b"ddddddddd"));
+        assert!(cache.get("d").is_none());
+        assert_eq!(cache.mu.lock().unwrap().size, 6);
+    }
+}
@@ -0,0 +1,108 @@
+const PNG_SIGNATURE_LEN: usize = 8;
+// The length of the IHDR chunk, including the length, type, and CRC fields.
+const PNG_IHDR_LEN: usize = 25;
+
+/// Sets the pixel density in the JFIF header of the encoded JPEG, inserting
+/// the header if it doesn't exist.
+pub fn set_jpeg(mut buf: Vec<u8>, dpi: u32) -> Vec<u8> {
+    let dpi = dpi.min(u16::MAX as u32) as u16;
+
+    if buf.len() >= 18 && buf[2..4] == [0xFF, 0xE0] && &buf[6..11] == b"JFIF\0" {
+        buf[13] = 1; // Units are dots per inch.
+        buf[14..16].copy_from_slice(&dpi.to_be_bytes());
+        buf[16..18].copy_from_slice(&dpi.to_be_bytes());
+        return buf;
+    }
+
+    let mut app0 = Vec::with_capacity(18);
+    app0.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+    app0.extend_from_slice(b"JFIF\0");
+    app0.extend_from_slice(&[1, 1, 1]);
+    app0.extend_from_slice(&dpi.to_be_bytes());
+    app0.extend_from_slice(&dpi.to_be_bytes());
+    app0.extend_from_slice(&[0, 0]);
+    buf.splice(2..2, app0);
+    buf
+}
+
+/// Inserts a pHYs chunk with the pixel density into the encoded PNG, directly
+/// after the IHDR chunk.
+pub fn set_png(mut buf: Vec<u8>, dpi: u32) -> Vec<u8> {
+    let pos = PNG_SIGNATURE_LEN + PNG_IHDR_LEN;
+    if buf.len() < pos || &buf[12..16] != b"IHDR" {
+        return buf;
+    }
+
+    // PNG stores the density in pixels per meter.
+    let ppm = (dpi as f64 / 0.0254).round() as u32;
+    let mut data = Vec::with_capacity(13);
+    data.extend_from_slice(b"pHYs");
+    data.extend_from_slice(&ppm.to_be_bytes());
+    data.extend_from_slice(&ppm.to_be_bytes());
+    data.push(1); // Units are meters.
+
+    let mut chunk = Vec::with_capacity(21);
+    chunk.extend_from_slice(&9u32.to_be_bytes());
+    chunk.extend_from_slice(&data);
+    chunk.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+    buf.splice(pos..pos, chunk);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // returns the JFIF density units and x and y densities.
+    fn jfif_density(buf: &[u8]) -> (u8, u16, u16) {
+        assert_eq!(&buf[..4], [0xFF, 0xD8, 0xFF, 0xE0]);
+        assert_eq!(&buf[6..11], b"JFIF\0");
+        (
+            buf[13],
+            u16::from_be_bytes([buf[14], buf[15]]),
+            u16::from_be_bytes([buf[16], buf[17]]),
+        )
+    }
+
+    #[test]
+    fn test_set_jpeg() {
+        // A JPEG without a JFIF header gets one inserted after the SOI marker.
+        let jpeg = vec![0xFF, 0xD8, 0xFF, 0xDB, 0x00, 0x02, 0xFF, 0xD9];
+        let out = set_jpeg(jpeg.clone(), 300);
+        assert_eq!(jfif_density(&out), (1, 300, 300));
+        assert_eq!(u16::from_be_bytes([out[4], out[5]]), 16);
+        assert_eq!(&out[20..], &jpeg[2..]);
+
+        // An existing header is updated in place.
+        let mut with_jfif = out.clone();
+        with_jfif[13] = 0;
+        let out = set_jpeg(with_jfif, 72);
+        assert_eq!(jfif_density(&out), (1, 72, 72));
+        assert_eq!(out.len(), jpeg.len() + 18);
+    }
+
+    #[test]
+    fn test_set_png() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let out = set_png(png.clone(), 300);
+        let pos = PNG_SIGNATURE_LEN + PNG_IHDR_LEN;
+        assert_eq!(&out[pos..pos + 8], b"\0\0\0\x09pHYs");
+        // 300 dpi is 11811 pixels per meter.
+        let ppm = 11811u32.to_be_bytes();
+        assert_eq!(&out[pos + 8..pos + 12], ppm);
+        assert_eq!(&out[pos + 12..pos + 16], ppm);
+        assert_eq!(out[pos + 16], 1);
+        assert_eq!(out.len(), png.len() + 21);
+        // The chunk's CRC is valid, so the image still decodes.
+        image::load_from_memory(&out).unwrap();
+
+        // Buffers that aren't PNGs are left alone.
+        assert_eq!(set_png(b"not a png".to_vec(), 300), b"not a png");
+    }
+}
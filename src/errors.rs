@@ -0,0 +1,198 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+/// A stable, machine-readable code identifying a client-facing error, which
+/// is returned in the x-error-code header regardless of the message language.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    UnsupportedFormat,
+    DownloadFailed,
+    Timeout,
+    Overloaded,
+    OutputTooLarge,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::DownloadFailed => "download_failed",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Overloaded => "overloaded",
+            ErrorCode::OutputTooLarge => "output_too_large",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    // returns the translated message for the error, or None if the error's own
+    // (English) message should be used.
+    fn message(self, lang: Lang) -> Option<&'static str> {
+        match (lang, self) {
+            (Lang::En, _) => None,
+            (Lang::Fr, ErrorCode::Unauthorized) => Some("requête non autorisée"),
+            (Lang::Fr, ErrorCode::UnsupportedFormat) => Some("format d'image non pris en charge"),
+            (Lang::Fr, ErrorCode::DownloadFailed) => {
+                Some("impossible de télécharger l'image source")
+            }
+            (Lang::Fr, ErrorCode::Timeout) => Some("la requête a expiré"),
+            (Lang::Fr, ErrorCode::Overloaded) => Some("le serveur est surchargé"),
+            (Lang::Fr, ErrorCode::OutputTooLarge) => {
+                Some("l'image générée dépasse la taille maximale")
+            }
+            (Lang::Fr, ErrorCode::BadRequest | ErrorCode::Internal) => None,
+        }
+    }
+}
+
+/// The languages that error messages are translated to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lang {
+    En,
+    Fr,
+}
+
+impl Lang {
+    fn as_str(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Fr => "fr",
+        }
+    }
+
+    // returns the supported language with the highest quality value in the
+    // accept-language header, defaulting to English.
+    fn negotiate(accept: Option<&HeaderValue>) -> Self {
+        let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+            return Lang::En;
+        };
+        let mut best = (Lang::En, 0.0);
+        for range in accept.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().unwrap_or_default();
+            let primary = tag.split('-').next().unwrap_or_default();
+            let lang = if primary.eq_ignore_ascii_case("en") {
+                Lang::En
+            } else if primary.eq_ignore_ascii_case("fr") {
+                Lang::Fr
+            } else {
+                continue;
+            };
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if q > best.1 {
+                best = (lang, q);
+            }
+        }
+        best.0
+    }
+}
+
+/// Replaces the message of error responses with a translation in the client's
+/// preferred language, when one exists. The error code is left unchanged.
+pub async fn localize(request: Request, next: Next) -> Response {
+    let lang = Lang::negotiate(request.headers().get(header::ACCEPT_LANGUAGE));
+    let mut res = next.run(request).await;
+    let Some(message) = res
+        .extensions()
+        .get::<ErrorCode>()
+        .and_then(|code| code.message(lang))
+    else {
+        return res;
+    };
+
+    let headers = res.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert(
+        header::CONTENT_LANGUAGE,
+        HeaderValue::from_static(lang.as_str()),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(message.len()));
+    *res.body_mut() = Body::from(message);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, middleware, response::IntoResponse, routing, Router};
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate =
+            |accept: &str| Lang::negotiate(Some(&HeaderValue::from_str(accept).unwrap()));
+        assert_eq!(Lang::negotiate(None), Lang::En);
+        assert_eq!(negotiate("fr-CA"), Lang::Fr);
+        assert_eq!(negotiate("de, fr;q=0.5"), Lang::Fr);
+        assert_eq!(negotiate("fr;q=0.5, en-US;q=0.8"), Lang::En);
+        assert_eq!(negotiate("en, fr"), Lang::En);
+        assert_eq!(negotiate("fr;q=0, de"), Lang::En);
+        assert_eq!(negotiate("*"), Lang::En);
+    }
+
+    #[tokio::test]
+    async fn test_localize() {
+        let app = Router::new()
+            .route(
+                "/",
+                routing::get(|| async {
+                    let mut res =
+                        (StatusCode::GATEWAY_TIMEOUT, "request timed out").into_response();
+                    res.headers_mut().insert(
+                        "x-error-code",
+                        HeaderValue::from_static(ErrorCode::Timeout.as_str()),
+                    );
+                    res.extensions_mut().insert(ErrorCode::Timeout);
+                    res
+                }),
+            )
+            .layer(middleware::from_fn(localize));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let get = |lang: &'static str| {
+            let req = client.get(&url).header("accept-language", lang).send();
+            async move {
+                let res = req.await.unwrap();
+                let code = res.headers()["x-error-code"].to_str().unwrap().to_owned();
+                (res.status(), code, res.text().await.unwrap())
+            }
+        };
+
+        let en = get("en-US").await;
+        let fr = get("fr-FR, en;q=0.5").await;
+        assert_eq!(
+            en,
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                "timeout".to_owned(),
+                "request timed out".to_owned()
+            )
+        );
+        assert_eq!(
+            fr,
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                "timeout".to_owned(),
+                "la requête a expiré".to_owned()
+            )
+        );
+    }
+}
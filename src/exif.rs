@@ -1,8 +1,12 @@
 use std::io::Cursor;
 
-use exif::{Exif, In, Reader, Tag, Value};
+use exif::{Context, DateTime, Exif, In, Reader, Tag, Value};
+use memchr::memmem;
 use serde::Serialize;
 
+/// The tag of the IFD0 entry pointing to the GPS IFD.
+const GPS_IFD_POINTER: u16 = 0x8825;
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Data {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -12,10 +16,16 @@ pub struct Data {
     #[serde(skip_serializing_if = "Option::is_none")]
     software: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    lens_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_time_original: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     orientation: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     f_number: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    focal_length: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     iso: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     exposure_time: Option<String>,
@@ -27,6 +37,15 @@ pub struct Data {
     altitude: Option<f64>,
 }
 
+impl Data {
+    /// Removes all location fields, keeping the remaining camera data.
+    pub fn strip_gps(&mut self) {
+        self.latitude = None;
+        self.longitude = None;
+        self.altitude = None;
+    }
+}
+
 pub struct ExifData {
     exif: Exif,
 }
@@ -45,8 +64,11 @@ impl ExifData {
             make: self.get_make(),
             model: self.get_model(),
             software: self.get_software(),
+            lens_model: self.get_lens_model(),
+            date_time_original: self.get_date_time_original(),
             orientation: self.get_orientation(),
             f_number: self.get_f_number(),
+            focal_length: self.get_focal_length(),
             iso: self.get_iso(),
             exposure_time: self.get_exposure_time(),
             latitude: self.get_latitude(),
@@ -55,6 +77,31 @@ impl ExifData {
         }
     }
 
+    /// Returns true if the EXIF data contains any GPS fields.
+    pub fn has_gps(&self) -> bool {
+        self.exif
+            .fields()
+            .any(|field| field.tag.context() == Context::Gps)
+    }
+
+    /// Returns a copy of the image with the GPS fields removed from its EXIF
+    /// data, keeping the remaining fields and the layout of the image. Returns
+    /// None if the EXIF data can't be located in the image.
+    pub fn strip_gps_from(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        let tiff = self.exif.buf();
+        let start = memmem::find(buf, tiff)?;
+        let end = start + tiff.len();
+        let mut out = buf.to_vec();
+        clear_gps_ifd(&mut out[start..end])?;
+
+        // PNG chunks are checksummed, so the eXIf chunk's CRC is updated.
+        if start >= 4 && &out[start - 4..start] == b"eXIf" && out.len() >= end + 4 {
+            let crc = crc32fast::hash(&out[start - 4..end]);
+            out[end..end + 4].copy_from_slice(&crc.to_be_bytes());
+        }
+        Some(out)
+    }
+
     pub fn get_orientation(&self) -> Option<u32> {
         self.get_field_u32(Tag::Orientation)
     }
@@ -71,6 +118,20 @@ impl ExifData {
         self.get_field_string(Tag::Software)
     }
 
+    fn get_lens_model(&self) -> Option<String> {
+        self.get_field_string(Tag::LensModel)
+    }
+
+    fn get_date_time_original(&self) -> Option<String> {
+        self.get_field_date_time(Tag::DateTimeOriginal, Tag::OffsetTimeOriginal)
+            .or_else(|| self.get_field_date_time(Tag::DateTime, Tag::OffsetTime))
+    }
+
+    fn get_focal_length(&self) -> Option<f32> {
+        self.get_field_rational(Tag::FocalLength)
+            .map(|(num, denom)| num as f32 / denom as f32)
+    }
+
     fn get_f_number(&self) -> Option<f32> {
         self.get_field_rational(Tag::FNumber)
             .map(|(num, denom)| num as f32 / denom as f32)
@@ -98,7 +159,7 @@ impl ExifData {
                     if let exif::Value::Ascii(n) = &field.value {
                         if let Some(s) = n.first() {
                             if s.starts_with(b"S") {
-                                return v * -1.0;
+                                return -v;
                             }
                         }
                     }
@@ -115,7 +176,7 @@ impl ExifData {
                     if let exif::Value::Ascii(n) = &field.value {
                         if let Some(s) = n.first() {
                             if s.starts_with(b"W") {
-                                return v * -1.0;
+                                return -v;
                             }
                         }
                     }
@@ -129,7 +190,7 @@ impl ExifData {
         self.get_float64(Tag::GPSAltitude)
             .map(|v| {
                 if let Some(1) = self.get_field_u32(Tag::GPSAltitudeRef) {
-                    v * -1.0
+                    -v
                 } else {
                     v
                 }
@@ -162,6 +223,35 @@ impl ExifData {
         None
     }
 
+    // returns the date and time as an ISO-8601 string, including the offset if
+    // one is present in the provided offset tag.
+    fn get_field_date_time(&self, tag: Tag, offset_tag: Tag) -> Option<String> {
+        let field = self.exif.get_field(tag, In::PRIMARY)?;
+        let Value::Ascii(v) = &field.value else {
+            return None;
+        };
+        let mut dt = DateTime::from_ascii(v.first()?).ok()?;
+
+        if let Some(field) = self.exif.get_field(offset_tag, In::PRIMARY) {
+            if let Value::Ascii(v) = &field.value {
+                if let Some(offset) = v.first() {
+                    _ = dt.parse_offset(offset);
+                }
+            }
+        }
+
+        let mut out = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+        );
+        if let Some(offset) = dt.offset {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.unsigned_abs();
+            out.push_str(&format!("{sign}{:02}:{:02}", offset / 60, offset % 60));
+        }
+        Some(out)
+    }
+
     fn get_field_rational(&self, tag: Tag) -> Option<(u32, u32)> {
         self.exif.get_field(tag, In::PRIMARY).and_then(|field| {
             if let Value::Rational(v) = &field.value {
@@ -188,3 +278,124 @@ impl ExifData {
         })
     }
 }
+
+// empties the GPS IFD of the provided TIFF structure, zeroing its entries and
+// the values they point to so that no location data remains in the bytes.
+fn clear_gps_ifd(tiff: &mut [u8]) -> Option<()> {
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let read_u16 = |tiff: &[u8], offset: usize| {
+        let v = tiff.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(v)
+        } else {
+            u16::from_le_bytes(v)
+        } as usize)
+    };
+    let read_u32 = |tiff: &[u8], offset: usize| {
+        let v = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(v)
+        } else {
+            u32::from_le_bytes(v)
+        } as usize)
+    };
+
+    let ifd0 = read_u32(tiff, 4)?;
+    let pointer = (0..read_u16(tiff, ifd0)?)
+        .map(|i| ifd0 + 2 + i * 12)
+        .find(|&entry| read_u16(tiff, entry) == Some(GPS_IFD_POINTER as usize))?;
+    let gps_ifd = read_u32(tiff, pointer + 8)?;
+    let count = read_u16(tiff, gps_ifd)?;
+    for i in 0..count {
+        let entry = gps_ifd + 2 + i * 12;
+        let size =
+            value_size(read_u16(tiff, entry + 2)?).checked_mul(read_u32(tiff, entry + 4)?)?;
+        if size > 4 {
+            let offset = read_u32(tiff, entry + 8)?;
+            zero(tiff, offset, size)?;
+        }
+    }
+    // An IFD with no entries is still valid, so the pointer is left in place.
+    zero(tiff, gps_ifd, 2 + count * 12)
+}
+
+// returns the size in bytes of a single value of the TIFF field type.
+fn value_size(field_type: usize) -> usize {
+    match field_type {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+// zeroes the range of the buffer, returning None if it's out of bounds.
+fn zero(buf: &mut [u8], offset: usize, len: usize) -> Option<()> {
+    buf.get_mut(offset..offset.checked_add(len)?)?.fill(0);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{experimental::Writer, Field, Rational};
+
+    use super::*;
+
+    // returns a TIFF structure containing the provided fields.
+    fn tiff(fields: &[Field]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut buf = Cursor::new(Vec::new());
+        writer.write(&mut buf, false).unwrap();
+        buf.into_inner()
+    }
+
+    fn field(tag: Tag, value: Value) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        }
+    }
+
+    fn ascii(v: &str) -> Value {
+        Value::Ascii(vec![v.as_bytes().to_vec()])
+    }
+
+    #[test]
+    fn test_capture_fields() {
+        let buf = tiff(&[
+            field(Tag::DateTime, ascii("2024:01:01 00:00:00")),
+            field(Tag::DateTimeOriginal, ascii("2023:05:06 07:08:09")),
+            field(Tag::OffsetTimeOriginal, ascii("-04:30")),
+            field(Tag::LensModel, ascii("RF24-70mm F2.8 L IS USM")),
+            field(
+                Tag::FocalLength,
+                Value::Rational(vec![Rational { num: 71, denom: 2 }]),
+            ),
+        ]);
+        let data = ExifData::new(&buf).unwrap().get_data();
+        assert_eq!(
+            data.date_time_original.as_deref(),
+            Some("2023-05-06T07:08:09-04:30")
+        );
+        assert_eq!(data.lens_model.as_deref(), Some("RF24-70mm F2.8 L IS USM"));
+        assert_eq!(data.focal_length, Some(35.5));
+
+        // The modification time is used when the original time is missing.
+        let buf = tiff(&[field(Tag::DateTime, ascii("2024:01:02 03:04:05"))]);
+        let data = ExifData::new(&buf).unwrap().get_data();
+        assert_eq!(
+            data.date_time_original.as_deref(),
+            Some("2024-01-02T03:04:05")
+        );
+        assert_eq!((data.lens_model, data.focal_length), (None, None));
+    }
+}
@@ -1,33 +1,162 @@
-use std::{fmt::Write, sync::Arc, time::SystemTime};
+use std::{
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
-use reqwest::Client;
-use tokio::sync::Semaphore;
+use arc_swap::ArcSwapOption;
+use bytes::Bytes;
+use reqwest::{header::HeaderValue, Client, Url};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::task;
 
 use crate::{
-    cache::{disk::DiskCache, memory::MemoryCache},
-    image::{ImageMetadata, ImageOutput, ImageProccessor, MetadataOptions, ProcessOptions},
-    signature::Verifier,
+    cache::{
+        disk::DiskCache,
+        memory::{MemoryCache, MemoryCacheStats},
+        source::SourceCache,
+        EvictionStats,
+    },
+    image::{
+        self, ImageMetadata, ImageOutput, ImageProccessor, ImageType, MetadataOptions,
+        ProcessOptions,
+    },
+    limiter::{HostLimiter, QueueStats, QueuedSemaphore},
+    resolver,
+    server::FieldCase,
+    signature::{KeyStats, Verifier},
     singleflight::Group,
+    timing::ServerTiming,
 };
 
+/// The prefix of source URLs that are read from the local root.
+const LOCAL_PREFIX: &str = "local:";
+
 pub struct Handler {
     pub mem_cache: Option<MemoryCache>,
     pub disk_cache: Option<DiskCache>,
+    pub source_cache: Option<SourceCache>,
     pub client: Client,
     pub group: Group<Key, Arc<Result<ImageResponse>>>,
+    pub downloads: Group<String, Arc<Result<Bytes>>>,
     pub processor: ImageProccessor,
-    pub semaphore: Semaphore,
-    pub verifier: Option<Verifier>,
+    pub download_semaphore: QueuedSemaphore,
+    pub host_limiter: Option<HostLimiter>,
+    pub verifier: ArcSwapOption<Verifier>,
+    pub config: HandlerConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct HandlerConfig {
+    /// The maximum number of concurrent downloads from origins.
+    pub download_concurrency: usize,
+    /// The maximum number of concurrent downloads from a single host.
+    pub host_concurrency: Option<usize>,
+    /// The maximum duration of a single request.
+    pub request_timeout: Duration,
+    /// The key required to use the signing endpoint.
+    pub admin_key: Option<String>,
+    /// The file containing the verification keys, reloaded on SIGHUP.
+    pub verify_keys_file: Option<PathBuf>,
+    /// The URL schemes that source images can be fetched with.
+    pub allowed_schemes: Vec<String>,
+    /// Whether origins may respond with an application/octet-stream content
+    /// type, in addition to image types.
+    pub allow_octet_stream: bool,
+    /// Whether to reject source urls with IP addresses that aren't publicly
+    /// routable.
+    pub deny_private_ips: bool,
+    /// The maximum length of a source image URL.
+    pub max_url_len: usize,
+    /// The directory that "local:" source images are read from, if enabled.
+    pub local_root: Option<PathBuf>,
+    /// How long to wait for in-flight requests when shutting down, or None to
+    /// wait indefinitely.
+    pub shutdown_timeout: Option<Duration>,
+    /// Whether to set security headers, like nosniff, on all responses.
+    pub security_headers: bool,
+    /// Whether to reject out-of-range parameters, rather than clamping them.
+    pub strict_params: bool,
+    /// The largest blur sigma that can be requested, which is at most
+    /// image::MAX_BLUR.
+    pub max_blur: u32,
+    /// The output format used when none is requested, or None to use the
+    /// source image's format.
+    pub default_output_format: Option<ImageType>,
+    /// The formats that images may be encoded to, or None to allow all.
+    pub allowed_output_formats: Option<Vec<ImageType>>,
+    /// The format used in place of a disallowed one. Requests for disallowed
+    /// formats are rejected if this isn't set.
+    pub fallback_output_format: Option<ImageType>,
+    /// The casing of metadata field names when none is requested.
+    pub metadata_case: FieldCase,
+}
+
+/// An error caused by an invalid request, rather than a failure while handling
+/// it.
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+
+impl Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
+impl std::error::Error for BadRequest {}
+
+/// An error caused by a failure to fetch the source image from its origin.
+#[derive(Debug)]
+pub struct DownloadFailed(pub String);
+
+impl Display for DownloadFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DownloadFailed {}
+
 #[derive(Clone)]
 pub struct ImageResponse {
     pub cache_result: Option<CacheResult>,
+    /// The path of the image in the disk cache, relative to its directory.
+    pub cache_path: Option<PathBuf>,
+    /// The options used to produce the image, including applied defaults.
+    pub options: ProcessOptions,
     pub output: ImageOutput,
     pub timing: ServerTiming,
 }
 
+impl ImageResponse {
+    /// Returns the mimetype of the image, which is the source's when it's
+    /// passed through untouched.
+    pub fn mimetype(&self) -> &'static str {
+        if self.options.passthrough {
+            self.output.orig_type.mimetype()
+        } else {
+            self.output.img_type.mimetype()
+        }
+    }
+}
+
+/// The saturation of the processing and download queues, and the evictions
+/// from each enabled cache.
+#[derive(Serialize)]
+pub struct Stats {
+    pub processing: QueueStats,
+    pub downloads: QueueStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_cache: Option<MemoryCacheStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_cache: Option<EvictionStats>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signature_keys: Vec<KeyStats>,
+}
+
 pub struct MetadataResponse {
     pub metadata: ImageMetadata,
     pub timing: ServerTiming,
@@ -37,25 +166,109 @@ impl Handler {
     pub fn new(
         mem_cache: Option<MemoryCache>,
         disk_cache: Option<DiskCache>,
+        source_cache: Option<SourceCache>,
         client: Client,
         processor: ImageProccessor,
-        concurrency: usize,
         verifier: Option<Verifier>,
+        config: HandlerConfig,
     ) -> Self {
-        assert!(concurrency > 0);
+        assert!(config.download_concurrency > 0);
         Self {
             mem_cache,
             disk_cache,
+            source_cache,
             client,
             group: Group::new(),
+            downloads: Group::new(),
             processor,
-            semaphore: Semaphore::new(concurrency),
-            verifier,
+            download_semaphore: QueuedSemaphore::new(config.download_concurrency),
+            host_limiter: config.host_concurrency.map(HostLimiter::new),
+            verifier: ArcSwapOption::from_pointee(verifier),
+            config,
         }
     }
 
+    /// Reloads the verification keys from the configured key file, replacing
+    /// the existing keys without interrupting in-flight requests.
+    pub fn reload_keys(&self) -> Result<()> {
+        let Some(path) = &self.config.verify_keys_file else {
+            return Err(anyhow!("no verification key file configured"));
+        };
+        let verifier = Verifier::from_file(path)?;
+        self.verifier.store(Some(Arc::new(verifier)));
+        Ok(())
+    }
+
+    /// Returns the signature for the provided path and query, if the provided
+    /// admin key is valid.
+    pub fn sign(&self, admin_key: Option<&str>, path: &str, query: Option<&str>) -> Result<String> {
+        let verifier = self.verifier.load();
+        let (Some(expected), Some(verifier)) = (&self.config.admin_key, &*verifier) else {
+            return Err(anyhow!("signing is not enabled"));
+        };
+
+        let Some(admin_key) = admin_key else {
+            return Err(anyhow!("admin key must be provided"));
+        };
+        // Compare digests so the comparison time doesn't depend on the key.
+        if Sha256::digest(admin_key) != Sha256::digest(expected) {
+            return Err(anyhow!("invalid admin key provided"));
+        }
+
+        verifier.sign(path, query)
+    }
+
+    /// Returns the signature for the provided path and query, or None if
+    /// signatures aren't required.
+    pub fn signature(&self, path: &str, query: Option<&str>) -> Result<Option<String>> {
+        let verifier = self.verifier.load();
+        verifier
+            .as_ref()
+            .map(|verifier| verifier.sign(path, query))
+            .transpose()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            processing: self.processor.queue_stats(),
+            downloads: self.download_semaphore.stats(),
+            mem_cache: self.mem_cache.as_ref().map(MemoryCache::stats),
+            disk_cache: self.disk_cache.as_ref().map(DiskCache::eviction_stats),
+            signature_keys: self
+                .verifier
+                .load()
+                .as_ref()
+                .map(|verifier| verifier.stats())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the deadline for a request, allowing the client to request a
+    /// shorter (but not longer) timeout in seconds.
+    pub fn timeout(&self, requested: Option<u64>) -> Duration {
+        requested
+            .map(|secs| Duration::from_secs(secs.max(1)))
+            .map_or(self.config.request_timeout, |timeout| {
+                timeout.min(self.config.request_timeout)
+            })
+    }
+
+    /// Rejects source URLs that exceed the maximum length. This should be
+    /// called before doing any other work for a request.
+    pub fn check_url_len(&self, url: &str) -> Result<()> {
+        if url.len() > self.config.max_url_len {
+            return Err(BadRequest(format!(
+                "url exceeds the maximum length of {} bytes",
+                self.config.max_url_len
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn verify(&self, path: &str, query: Option<&str>, sig: Option<&str>) -> Result<()> {
-        let Some(verifier) = &self.verifier else {
+        let verifier = self.verifier.load();
+        let Some(verifier) = &*verifier else {
             return Ok(());
         };
 
@@ -66,21 +279,52 @@ impl Handler {
         verifier.verify(path, query, sig.as_bytes())
     }
 
+    pub fn allows_format(&self, img_type: ImageType) -> bool {
+        self.config
+            .allowed_output_formats
+            .as_ref()
+            .is_none_or(|formats| formats.contains(&img_type))
+    }
+
+    /// Returns the output format to use in place of the provided one: the
+    /// format itself if it's allowed, otherwise the fallback format. Returns an
+    /// error if the format isn't allowed and there's no fallback.
+    pub fn check_output_format(&self, out_type: ImageType) -> Result<ImageType> {
+        if self.allows_format(out_type) {
+            return Ok(out_type);
+        }
+        self.config
+            .fallback_output_format
+            .ok_or_else(|| BadRequest(format!("output format not allowed: {out_type}")).into())
+    }
+
     /// This method has to return an Arc<Result<_>> because of the use of
     /// singleflight, which requires the output implement the Clone trait.
     pub async fn get_image(
         &self,
         url: &str,
-        options: ProcessOptions,
-        should_cache: bool,
+        mut options: ProcessOptions,
+        cache: CacheMode,
     ) -> Arc<Result<ImageResponse>> {
+        if let Some(out_type) = options.out_type {
+            match self.check_output_format(out_type) {
+                Ok(out_type) => options.out_type = Some(out_type),
+                Err(err) => return Arc::new(Err(err)),
+            }
+        }
+        let options = options.canonical();
+        // Requests that skip the caches don't join in-flight requests, which
+        // may return a cached image.
+        if !cache.read {
+            return Arc::new(self.get_image_inner(url, options, cache).await);
+        }
         let key = Key {
             input: url.to_owned(),
             options,
         };
         self.group
             .run(&key, || async {
-                Arc::new(self.get_image_inner(url, options, should_cache).await)
+                Arc::new(self.get_image_inner(url, options, cache).await)
             })
             .await
     }
@@ -89,60 +333,56 @@ impl Handler {
         &self,
         url: &str,
         options: ProcessOptions,
-        should_cache: bool,
+        cache: CacheMode,
     ) -> Result<ImageResponse> {
-        let _permit = self.semaphore.acquire().await?;
-
         let mut timing = ServerTiming::new();
-
-        if let Some(cache) = &self.mem_cache {
-            let start = SystemTime::now();
-            let output = cache.get(url, options);
-            timing.push("mem_cache_get", start);
-            if let Some(output) = output {
-                return Ok(ImageResponse {
-                    cache_result: Some(CacheResult::Hit),
-                    output,
-                    timing,
-                });
-            }
+        if let Some(res) = self.get_cached(url, options, cache, &mut timing).await {
+            return Ok(res);
         }
 
-        if let Some(cache) = &self.disk_cache {
-            let start = SystemTime::now();
-            let output = cache.get(url, options).await;
-            timing.push("disk_cache_get", start);
-            if let Ok(Some(output)) = output {
-                if let (Some(mem_cache), true) = (&self.mem_cache, should_cache) {
-                    let start = SystemTime::now();
-                    mem_cache.set(url, options, output.clone());
-                    timing.push("mem_cache_put", start);
+        let start = SystemTime::now();
+        let body = self.get_orig_image(url, cache).await?;
+        timing.push("download", start);
+
+        // Without an output format, the source's format is used, which is only
+        // known once it's been downloaded. Passthrough requests always return
+        // the source's format.
+        let mut options = options;
+        let mut process_options = options;
+        if let (None, false) = (options.out_type, options.passthrough) {
+            if let Some(out_type) = image::source_output_type(&body, &options) {
+                // Options that don't apply to the source's format are dropped,
+                // so that equivalent requests share a cache entry.
+                let normalized = options.for_output_type(out_type);
+                if normalized != options {
+                    if let Some(res) = self.get_cached(url, normalized, cache, &mut timing).await {
+                        return Ok(res);
+                    }
+                    options = normalized;
+                }
+
+                let allowed = self.check_output_format(out_type)?;
+                process_options = options;
+                if allowed != out_type {
+                    process_options.out_type = Some(allowed);
                 }
-                return Ok(ImageResponse {
-                    cache_result: Some(CacheResult::Hit),
-                    output,
-                    timing,
-                });
             }
         }
 
         let start = SystemTime::now();
-        let body = self.get_orig_image(url).await?;
-        timing.push("download", start);
-
-        let start = SystemTime::now();
-        let output = self.processor.process_image(body, options).await?;
+        let (output, stages) = self.processor.process_image(body, process_options).await?;
         timing.push("process", start);
+        timing.extend(stages);
 
-        if let (Some(cache), true) = (&self.mem_cache, should_cache) {
+        if let (Some(mem_cache), true) = (&self.mem_cache, cache.write) {
             let start = SystemTime::now();
-            cache.set(url, options, output.clone());
+            mem_cache.set(url, options, output.clone());
             timing.push("mem_cache_put", start);
         }
 
-        if let (Some(cache), true) = (&self.disk_cache, should_cache) {
+        if let (Some(disk_cache), true) = (&self.disk_cache, cache.write) {
             let start = SystemTime::now();
-            _ = cache.set(url, options, output.clone()).await;
+            _ = disk_cache.set(url, options, output.clone()).await;
             timing.push("disk_cache_put", start);
         }
 
@@ -151,35 +391,220 @@ impl Handler {
 
         Ok(ImageResponse {
             cache_result,
+            cache_path: self.cache_path(url, options),
+            options: self.processor.resolve_options(options, output.img_type),
             output,
             timing,
         })
     }
 
-    pub async fn get_metadata(&self, url: &str, thumbhash: bool) -> Result<MetadataResponse> {
-        let _permit = self.semaphore.acquire().await?;
+    // returns the image from the memory or disk cache, if present and allowed.
+    async fn get_cached(
+        &self,
+        url: &str,
+        options: ProcessOptions,
+        cache: CacheMode,
+        timing: &mut ServerTiming,
+    ) -> Option<ImageResponse> {
+        if !cache.read {
+            return None;
+        }
+
+        let mut output = None;
+        if let Some(mem_cache) = &self.mem_cache {
+            let start = SystemTime::now();
+            output = mem_cache.get(url, options);
+            timing.push("mem_cache_get", start);
+        }
+
+        if let (None, Some(disk_cache)) = (&output, &self.disk_cache) {
+            let start = SystemTime::now();
+            output = disk_cache.get(url, options).await.ok().flatten();
+            timing.push("disk_cache_get", start);
+            if let (Some(output), Some(mem_cache), true) = (&output, &self.mem_cache, cache.write) {
+                let start = SystemTime::now();
+                mem_cache.set(url, options, output.clone());
+                timing.push("mem_cache_put", start);
+            }
+        }
+
+        let output = output?;
+        Some(ImageResponse {
+            cache_result: Some(CacheResult::Hit),
+            cache_path: self.cache_path(url, options),
+            options: self.processor.resolve_options(options, output.img_type),
+            output,
+            timing: std::mem::replace(timing, ServerTiming::new()),
+        })
+    }
+
+    fn cache_path(&self, url: &str, options: ProcessOptions) -> Option<PathBuf> {
+        self.disk_cache
+            .as_ref()
+            .map(|_| DiskCache::relative_path(url, options))
+    }
 
+    pub async fn get_metadata(&self, url: &str, ops: MetadataOptions) -> Result<MetadataResponse> {
         let mut timing = ServerTiming::new();
 
         let start = SystemTime::now();
-        let body = self.get_orig_image(url).await?;
+        let body = self.get_orig_image(url, CacheMode::default()).await?;
         timing.push("download", start);
 
         let start = SystemTime::now();
-        let ops = MetadataOptions::new(thumbhash);
         let metadata = self.processor.metadata(body, ops).await?;
         timing.push("process", start);
 
         Ok(MetadataResponse { metadata, timing })
     }
 
-    async fn get_orig_image(&self, url: &str) -> Result<bytes::Bytes> {
-        let res = self.client.get(url).send().await?;
+    /// Returns the source image, sharing a single download between concurrent
+    /// requests and reusing or caching recently downloaded images when allowed.
+    async fn get_orig_image(&self, url: &str, cache: CacheMode) -> Result<Bytes> {
+        if let (Some(root), Some(path)) = (&self.config.local_root, url.strip_prefix(LOCAL_PREFIX))
+        {
+            return read_local(root, path).await;
+        }
+        self.check_url(url)?;
+
+        if let (Some(source_cache), true) = (&self.source_cache, cache.read) {
+            if let Some(buf) = source_cache.get(url) {
+                return Ok(buf);
+            }
+        }
+
+        let key = url.to_owned();
+        let res = self
+            .downloads
+            .run(&key, || async {
+                let res = self.download(url).await;
+                if let (Ok(buf), Some(source_cache), true) = (&res, &self.source_cache, cache.write)
+                {
+                    source_cache.set(url, buf.clone());
+                }
+                Arc::new(res)
+            })
+            .await;
+        // The error is shared between requests, so it's copied while keeping
+        // whether it was caused by the request or the origin.
+        match &*res {
+            Ok(buf) => Ok(buf.clone()),
+            Err(err) if err.is::<BadRequest>() => Err(BadRequest(err.to_string()).into()),
+            Err(err) => Err(DownloadFailed(err.to_string()).into()),
+        }
+    }
+
+    fn check_url(&self, url: &str) -> Result<()> {
+        let parsed = Url::parse(url).map_err(|err| BadRequest(format!("invalid url: {err}")))?;
+        if !self
+            .config
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme.eq_ignore_ascii_case(parsed.scheme()))
+        {
+            return Err(BadRequest(format!("url scheme not allowed: {}", parsed.scheme())).into());
+        }
+        if self.config.deny_private_ips {
+            resolver::check_host(&parsed).map_err(|err| BadRequest(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    async fn download(&self, url: &str) -> Result<Bytes> {
+        // Wait for the host's limit before taking a global permit, so that
+        // requests queued for a slow host don't block other hosts.
+        let _host_permit = match (&self.host_limiter, Url::parse(url)?.host_str()) {
+            (Some(limiter), Some(host)) => Some(limiter.acquire(host).await?),
+            _ => None,
+        };
+        let _permit = self.download_semaphore.acquire().await?;
+        let res = self.client.get(url).send().await.map_err(origin_error)?;
         if res.status() != reqwest::StatusCode::OK {
             return Err(anyhow!("received status code: {}", res.status()));
         }
+        self.check_content_type(res.headers().get("content-type"))?;
 
-        res.bytes().await.map_err(Into::into)
+        let body = res.bytes().await.map_err(origin_error)?;
+        image::check_source_len(&body)?;
+        Ok(body)
+    }
+
+    // ensures that the origin's content type looks like an image, so that
+    // error pages fail with a clear message. The image format itself is still
+    // determined from the response body.
+    fn check_content_type(&self, content_type: Option<&HeaderValue>) -> Result<()> {
+        let Some(content_type) = content_type else {
+            return Ok(());
+        };
+        let content_type = content_type.to_str().unwrap_or_default();
+        let mimetype = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if mimetype.starts_with("image/")
+            || (self.config.allow_octet_stream && mimetype == "application/octet-stream")
+        {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "origin returned a non-image content type: {content_type}"
+        ))
+    }
+}
+
+/// Reads a source image from the path relative to the local root. Only plain
+/// relative paths are allowed, so that reads can't escape the root.
+async fn read_local(root: &Path, path: &str) -> Result<Bytes> {
+    let rel = Path::new(path);
+    if path.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(BadRequest(format!("invalid local path: {path}")).into());
+    }
+
+    let full = root.join(rel);
+    let buf = task::spawn_blocking(move || std::fs::read(full))
+        .await?
+        .map_err(|err| anyhow!("unable to read local image: {err}"))?;
+    image::check_source_len(&buf)?;
+    Ok(Bytes::from(buf))
+}
+
+// distinguishes an origin that couldn't be reached from one that stalled
+// while sending its response. Redirects rejected by the client's redirect
+// policy are reported as bad requests.
+fn origin_error(err: reqwest::Error) -> anyhow::Error {
+    if err.is_redirect() {
+        let reason =
+            std::error::Error::source(&err).map_or_else(|| err.to_string(), ToString::to_string);
+        return BadRequest(format!("unable to follow redirect: {reason}")).into();
+    }
+    if !err.is_timeout() {
+        return err.into();
+    }
+    if err.is_connect() {
+        anyhow!("timed out connecting to origin")
+    } else {
+        anyhow!("timed out reading from origin")
+    }
+}
+
+/// Controls whether a request uses and populates the caches.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheMode {
+    /// Whether cached images and recently downloaded sources can be used.
+    pub read: bool,
+    /// Whether the resulting image is added to the caches.
+    pub write: bool,
+}
+
+impl Default for CacheMode {
+    fn default() -> Self {
+        CacheMode {
+            read: true,
+            write: true,
+        }
     }
 }
 
@@ -198,51 +623,511 @@ impl CacheResult {
     }
 }
 
-#[derive(Clone)]
-pub struct ServerTiming {
-    vals: Vec<TimingValue>,
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Key {
+    input: String,
+    options: ProcessOptions,
 }
 
-#[derive(Clone)]
-struct TimingValue {
-    name: &'static str,
-    dur: f32,
-}
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        io::Cursor,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
 
-impl ServerTiming {
-    fn new() -> Self {
-        Self {
-            vals: Vec::with_capacity(6),
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::image::{DefaultQuality, ProcessorConfig};
+
+    pub fn config() -> HandlerConfig {
+        HandlerConfig {
+            download_concurrency: 1,
+            host_concurrency: None,
+            request_timeout: Duration::from_secs(10),
+            admin_key: None,
+            verify_keys_file: None,
+            allowed_schemes: vec!["http".to_owned(), "https".to_owned()],
+            allow_octet_stream: false,
+            deny_private_ips: false,
+            max_url_len: 4096,
+            local_root: None,
+            shutdown_timeout: None,
+            security_headers: true,
+            strict_params: false,
+            max_blur: image::MAX_BLUR,
+            default_output_format: None,
+            allowed_output_formats: None,
+            fallback_output_format: None,
+            metadata_case: FieldCase::default(),
         }
     }
 
-    fn push(&mut self, name: &'static str, start: SystemTime) {
-        let dur = Self::ms_since(start);
-        self.vals.push(TimingValue { name, dur });
+    pub fn processor() -> ImageProccessor {
+        ImageProccessor::new(
+            1,
+            ProcessorConfig {
+                max_pixels: 100_000_000,
+                default_quality: DefaultQuality::default(),
+                avif_threads: 1,
+                max_queue: None,
+                max_output_bytes: None,
+                max_frames: 100,
+                truncate_frames: false,
+                max_blur: image::MAX_BLUR,
+            },
+        )
+    }
+
+    pub fn handler(config: HandlerConfig, verifier: Option<Verifier>) -> Handler {
+        Handler::new(
+            None,
+            None,
+            None,
+            Client::new(),
+            processor(),
+            verifier,
+            config,
+        )
+    }
+
+    // writes a small PNG to a new local root, returning the root.
+    pub fn local_png(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("imaged-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("img.png"), png()).unwrap();
+        root
+    }
+
+    // serves every request with the provided content type and body, returning
+    // the origin's url and the number of requests served.
+    pub async fn origin(content_type: &'static str, body: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/img", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                while !req.windows(4).any(|v| v == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+                served.fetch_add(1, Ordering::SeqCst);
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                _ = stream.write_all(head.as_bytes()).await;
+                _ = stream.write_all(&body).await;
+            }
+        });
+        (url, requests)
+    }
+
+    pub fn png() -> Vec<u8> {
+        let mut buf = Vec::new();
+        ::image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut buf), ::image::ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_check_output_format() {
+        let config = HandlerConfig {
+            allowed_output_formats: Some(vec![ImageType::Png, ImageType::Webp]),
+            ..config()
+        };
+        let state = handler(config.clone(), None);
+        assert_eq!(
+            state.check_output_format(ImageType::Webp).unwrap(),
+            ImageType::Webp
+        );
+        let err = state.check_output_format(ImageType::Avif).unwrap_err();
+        assert!(err.is::<BadRequest>());
+
+        let state = handler(
+            HandlerConfig {
+                fallback_output_format: Some(ImageType::Webp),
+                ..config
+            },
+            None,
+        );
+        assert_eq!(
+            state.check_output_format(ImageType::Avif).unwrap(),
+            ImageType::Webp
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_format_allowlist() {
+        let root = local_png("allowlist");
+        let config = HandlerConfig {
+            local_root: Some(root.clone()),
+            allowed_output_formats: Some(vec![ImageType::Webp]),
+            ..config()
+        };
+        let options = ProcessOptions {
+            width: Some(2),
+            ..Default::default()
+        };
+
+        // A re-encode in the source's (disallowed) format is rejected.
+        let state = handler(config.clone(), None);
+        let res = state
+            .get_image("local:img.png", options, CacheMode::default())
+            .await;
+        let err = res.as_ref().as_ref().err().unwrap();
+        assert!(err.is::<BadRequest>());
+
+        // Or uses the fallback format when there is one.
+        let state = handler(
+            HandlerConfig {
+                fallback_output_format: Some(ImageType::Webp),
+                ..config
+            },
+            None,
+        );
+        let res = state
+            .get_image("local:img.png", options, CacheMode::default())
+            .await;
+        let res = res.as_ref().as_ref().unwrap();
+        assert_eq!(res.output.img_type, ImageType::Webp);
+        assert_eq!(res.output.width, 2);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_source_format_cache_key() {
+        let root = local_png("cache-key");
+        let config = HandlerConfig {
+            local_root: Some(root.clone()),
+            ..config()
+        };
+        let mut state = handler(config, None);
+        state.mem_cache = Some(MemoryCache::new(1 << 20));
+
+        // The quality doesn't apply to a PNG source, so both requests share a
+        // cache entry.
+        let options = ProcessOptions {
+            width: Some(2),
+            quality: Some(image::Quality::Value(80)),
+            ..Default::default()
+        };
+        let res = state
+            .get_image("local:img.png", options, CacheMode::default())
+            .await;
+        let res = res.as_ref().as_ref().unwrap();
+        assert!(matches!(res.cache_result, Some(CacheResult::Miss)));
+
+        let options = ProcessOptions {
+            quality: None,
+            ..options
+        };
+        let res = state
+            .get_image("local:img.png", options, CacheMode::default())
+            .await;
+        let res = res.as_ref().as_ref().unwrap();
+        assert!(matches!(res.cache_result, Some(CacheResult::Hit)));
+
+        std::fs::remove_dir_all(root).unwrap();
     }
 
-    pub fn header(&self) -> String {
-        let mut out = String::with_capacity(128);
-        for val in &self.vals {
-            if !out.is_empty() {
-                out.push(',');
+    #[tokio::test]
+    async fn test_origin_timeouts() {
+        // A resolver that never resolves, so that connecting times out.
+        struct Pending;
+        impl reqwest::dns::Resolve for Pending {
+            fn resolve(&self, _: reqwest::dns::Name) -> reqwest::dns::Resolving {
+                Box::pin(std::future::pending())
             }
-            _ = write!(&mut out, "{};dur={:.1}", val.name, val.dur);
         }
-        out
+        let client = Client::builder()
+            .dns_resolver(Arc::new(Pending))
+            .connect_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let err = client.get("http://origin.test/").send().await.unwrap_err();
+        assert_eq!(
+            origin_error(err).to_string(),
+            "timed out connecting to origin"
+        );
+
+        // An origin that accepts the connection but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let client = Client::builder()
+            .read_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let err = client
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            origin_error(err).to_string(),
+            "timed out reading from origin"
+        );
     }
 
-    fn ms_since(start: SystemTime) -> f32 {
-        SystemTime::now()
-            .duration_since(start)
-            .unwrap_or_default()
-            .as_secs_f32()
-            * 1000.0
+    #[tokio::test]
+    async fn test_no_store() {
+        let root = std::env::temp_dir().join(format!("imaged-no-store-{}", std::process::id()));
+        let state = Handler::new(
+            Some(MemoryCache::new(1 << 20)),
+            Some(DiskCache::new(root.clone(), 1 << 20).await.unwrap()),
+            Some(SourceCache::new(1 << 20, Duration::from_secs(60))),
+            Client::new(),
+            processor(),
+            None,
+            config(),
+        );
+        let (url, requests) = origin("image/png", png()).await;
+        let options = ProcessOptions {
+            width: Some(2),
+            out_type: Some(ImageType::Png),
+            ..Default::default()
+        };
+        let cached = |state: &Handler| {
+            let key = options.canonical();
+            (
+                state.mem_cache.as_ref().unwrap().get(&url, key).is_some(),
+                state.source_cache.as_ref().unwrap().get(&url).is_some(),
+            )
+        };
+
+        let no_store = CacheMode {
+            read: false,
+            write: false,
+        };
+        let res = state.get_image(&url, options, no_store).await;
+        assert!(res.as_ref().is_ok());
+        assert_eq!(cached(&state), (false, false));
+        let disk = state.disk_cache.as_ref().unwrap();
+        assert!(disk.get(&url, options.canonical()).await.unwrap().is_none());
+
+        // Requests that are allowed to write populate every cache.
+        let res = state.get_image(&url, options, CacheMode::default()).await;
+        assert!(res.as_ref().is_ok());
+        assert_eq!(cached(&state), (true, true));
+        assert!(disk.get(&url, options.canonical()).await.unwrap().is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_dir_all(root).unwrap();
     }
-}
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Key {
-    input: String,
-    options: ProcessOptions,
+    #[tokio::test]
+    async fn test_shared_download() {
+        let state = Handler::new(
+            None,
+            None,
+            Some(SourceCache::new(1 << 20, Duration::from_secs(60))),
+            Client::new(),
+            processor(),
+            None,
+            config(),
+        );
+        let (url, requests) = origin("image/png", png()).await;
+
+        let metadata = state.get_metadata(&url, MetadataOptions::default()).await;
+        assert_eq!(metadata.unwrap().metadata.width, 4);
+        let options = ProcessOptions {
+            width: Some(2),
+            ..Default::default()
+        };
+        let res = state.get_image(&url, options, CacheMode::default()).await;
+        assert_eq!(res.as_ref().as_ref().unwrap().output.width, 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_url_scheme() {
+        let state = handler(config(), None);
+        for url in [
+            "file:///etc/passwd",
+            "ftp://example.com/a.png",
+            "gopher://example.com/a.png",
+        ] {
+            let err = state
+                .get_metadata(url, MetadataOptions::default())
+                .await
+                .err()
+                .unwrap();
+            assert!(err.is::<BadRequest>(), "{url}: {err}");
+            assert!(err.to_string().starts_with("url scheme not allowed: "));
+        }
+        let err = state.check_url("not a url").unwrap_err();
+        assert!(err.is::<BadRequest>());
+
+        // Schemes are matched case-insensitively, and more can be allowed.
+        assert!(state.check_url("HTTPS://example.com/a.png").is_ok());
+        let state = handler(
+            HandlerConfig {
+                allowed_schemes: vec!["ftp".to_owned()],
+                ..config()
+            },
+            None,
+        );
+        assert!(state.check_url("ftp://example.com/a.png").is_ok());
+        assert!(state.check_url("http://example.com/a.png").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_origin_content_type() {
+        let state = handler(config(), None);
+        let (url, _) = origin("text/html; charset=utf-8", b"<html></html>".to_vec()).await;
+        let err = state
+            .get_metadata(&url, MetadataOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<DownloadFailed>());
+        assert_eq!(
+            err.to_string(),
+            "origin returned a non-image content type: text/html; charset=utf-8"
+        );
+
+        // The format is detected from the body, not the content type.
+        let (url, _) = origin("image/jpeg", png()).await;
+        let res = state.get_metadata(&url, MetadataOptions::default()).await;
+        let format = res.unwrap().metadata.format;
+        assert!(matches!(format, image::InputImageType::Png));
+
+        let content_type = |v: &'static str| Some(HeaderValue::from_static(v));
+        assert!(state.check_content_type(None).is_ok());
+        assert!(state
+            .check_content_type(content_type("IMAGE/PNG").as_ref())
+            .is_ok());
+        let octet_stream = content_type("application/octet-stream");
+        assert!(state.check_content_type(octet_stream.as_ref()).is_err());
+        let state = handler(
+            HandlerConfig {
+                allow_octet_stream: true,
+                ..config()
+            },
+            None,
+        );
+        assert!(state.check_content_type(octet_stream.as_ref()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_source() {
+        let state = handler(config(), None);
+        for body in [Vec::new(), png()[..5].to_vec()] {
+            let len = body.len();
+            let (url, _) = origin("image/png", body).await;
+            let err = state
+                .get_metadata(&url, MetadataOptions::default())
+                .await
+                .err()
+                .unwrap();
+            assert!(err.is::<DownloadFailed>());
+            assert_eq!(
+                err.to_string(),
+                format!("empty or truncated source: received {len} bytes")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_concurrency() {
+        // An origin that accepts connections but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                streams.push(listener.accept().await.unwrap());
+            }
+        });
+
+        // More downloads than processing workers can be in flight at once,
+        // without taking any processing capacity.
+        let config = HandlerConfig {
+            download_concurrency: 3,
+            ..config()
+        };
+        let state = Arc::new(handler(config, None));
+        let tasks: Vec<_> = (0..4)
+            .map(|i| {
+                let state = state.clone();
+                let url = format!("http://{addr}/{i}.png");
+                tokio::spawn(async move {
+                    _ = state.get_metadata(&url, MetadataOptions::default()).await;
+                })
+            })
+            .collect();
+        for _ in 0..100 {
+            if state.stats().downloads.waiting == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let stats = state.stats();
+        assert_eq!(stats.downloads.available, 0);
+        assert_eq!(stats.downloads.waiting, 1);
+        assert_eq!(stats.processing.available, 1);
+        assert_eq!(stats.processing.waiting, 0);
+        tasks.iter().for_each(|task| task.abort());
+    }
+
+    #[tokio::test]
+    async fn test_local_root() {
+        let root = local_png("local-root");
+        let state = handler(
+            HandlerConfig {
+                local_root: Some(root.clone()),
+                ..config()
+            },
+            None,
+        );
+        let res = state
+            .get_metadata("local:img.png", MetadataOptions::default())
+            .await;
+        assert_eq!(res.unwrap().metadata.width, 4);
+
+        // Paths must be relative and stay within the root.
+        for url in [
+            "local:",
+            "local:../img.png",
+            "local:a/../../img.png",
+            "local:/etc/passwd",
+        ] {
+            let err = state
+                .get_metadata(url, MetadataOptions::default())
+                .await
+                .err()
+                .unwrap();
+            assert!(err.is::<BadRequest>(), "{url}: {err}");
+        }
+        let err = state
+            .get_metadata("local:missing.png", MetadataOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("unable to read local image: "));
+
+        // Without a root, "local:" isn't an allowed scheme.
+        let err = handler(config(), None)
+            .get_metadata("local:img.png", MetadataOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<BadRequest>());
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
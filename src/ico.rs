@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageFormat};
+
+const HEADER_LEN: usize = 6;
+const ENTRY_LEN: usize = 16;
+
+struct Entry {
+    width: u32,
+    height: u32,
+    bits_per_pixel: u16,
+    pos: usize,
+    offset: usize,
+    length: usize,
+}
+
+impl Entry {
+    fn size(&self) -> u32 {
+        self.width.max(self.height)
+    }
+}
+
+/// Decodes a single image from the ICO container. With no size provided, the
+/// largest embedded image is chosen. Otherwise, the smallest image that is at
+/// least the provided size is used, falling back to the largest.
+pub fn decode(raw: &[u8], size: Option<u32>) -> Result<DynamicImage> {
+    let entries = read_entries(raw)?;
+    let entry = select_entry(&entries, size).ok_or_else(|| anyhow!("ico: no images found"))?;
+
+    let data = entry
+        .offset
+        .checked_add(entry.length)
+        .and_then(|end| raw.get(entry.offset..end))
+        .ok_or_else(|| anyhow!("ico: image data is out of bounds"))?;
+
+    // Rebuild a container holding only the selected image so that the image
+    // crate's decoder doesn't make its own choice.
+    let mut buf = Vec::with_capacity(HEADER_LEN + ENTRY_LEN + data.len());
+    buf.extend_from_slice(&[0, 0, 1, 0, 1, 0]);
+    buf.extend_from_slice(&raw[entry.pos..entry.pos + 12]);
+    buf.extend_from_slice(&((HEADER_LEN + ENTRY_LEN) as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+
+    image::load_from_memory_with_format(&buf, ImageFormat::Ico).map_err(Into::into)
+}
+
+fn read_entries(raw: &[u8]) -> Result<Vec<Entry>> {
+    if raw.len() < HEADER_LEN {
+        return Err(anyhow!("ico: header is too short"));
+    }
+    let count = u16::from_le_bytes([raw[4], raw[5]]) as usize;
+
+    (0..count)
+        .map(|i| {
+            let pos = HEADER_LEN + i * ENTRY_LEN;
+            let entry = raw
+                .get(pos..pos + ENTRY_LEN)
+                .ok_or_else(|| anyhow!("ico: directory is truncated"))?;
+            Ok(Entry {
+                width: dimension(entry[0]),
+                height: dimension(entry[1]),
+                bits_per_pixel: u16::from_le_bytes([entry[6], entry[7]]),
+                pos,
+                length: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize,
+                offset: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize,
+            })
+        })
+        .collect()
+}
+
+// A stored dimension of 0 means 256 pixels.
+fn dimension(v: u8) -> u32 {
+    if v == 0 {
+        256
+    } else {
+        v as u32
+    }
+}
+
+fn select_entry(entries: &[Entry], size: Option<u32>) -> Option<&Entry> {
+    let largest = entries
+        .iter()
+        .max_by_key(|entry| (entry.size(), entry.bits_per_pixel));
+
+    let Some(size) = size else {
+        return largest;
+    };
+
+    entries
+        .iter()
+        .filter(|entry| entry.size() >= size)
+        .min_by_key(|entry| (entry.size(), u16::MAX - entry.bits_per_pixel))
+        .or(largest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::image::{source_output_type, ImageType, ProcessOptions};
+
+    // returns an ICO holding a PNG encoded image for each of the sizes.
+    fn ico(sizes: &[u32]) -> Vec<u8> {
+        let images: Vec<Vec<u8>> = sizes
+            .iter()
+            .map(|&size| {
+                let img = RgbaImage::from_pixel(size, size, Rgba([size as u8, 0, 0, 255]));
+                let mut buf = Cursor::new(Vec::new());
+                img.write_to(&mut buf, ImageFormat::Png).unwrap();
+                buf.into_inner()
+            })
+            .collect();
+
+        let mut buf = vec![0, 0, 1, 0];
+        buf.extend_from_slice(&(sizes.len() as u16).to_le_bytes());
+        let mut offset = HEADER_LEN + ENTRY_LEN * sizes.len();
+        for (&size, data) in sizes.iter().zip(&images) {
+            let dim = if size >= 256 { 0 } else { size as u8 };
+            buf.extend_from_slice(&[dim, dim, 0, 0, 1, 0, 32, 0]);
+            buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += data.len();
+        }
+        for data in &images {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_decode() {
+        let raw = ico(&[16, 48, 32]);
+        // ICO sources are detected, and output as PNG by default.
+        assert_eq!(
+            source_output_type(&raw, &ProcessOptions::default()),
+            Some(ImageType::Png)
+        );
+
+        // The largest image is chosen by default.
+        let img = decode(&raw, None).unwrap();
+        assert_eq!(img.dimensions(), (48, 48));
+        assert_eq!(img.get_pixel(0, 0), Rgba([48, 0, 0, 255]));
+
+        // Otherwise, the smallest image that is at least the requested size.
+        assert_eq!(decode(&raw, Some(20)).unwrap().dimensions(), (32, 32));
+        assert_eq!(decode(&raw, Some(16)).unwrap().dimensions(), (16, 16));
+        assert_eq!(decode(&raw, Some(64)).unwrap().dimensions(), (48, 48));
+
+        assert!(decode(&raw[..HEADER_LEN + ENTRY_LEN], None).is_err());
+        assert!(decode(&ico(&[]), None).is_err());
+    }
+}
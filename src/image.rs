@@ -1,21 +1,42 @@
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    fmt::Display,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose::STANDARD, Engine as _};
+use color_quant::NeuQuant;
 use image::{
-    codecs::{avif::AvifEncoder, png::PngEncoder, tiff::TiffEncoder},
+    codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder},
     error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
+    imageops::FilterType,
     DynamicImage, GenericImageView, ImageError, ImageFormat, ImageResult,
 };
+use ravif::{Img, RGB8, RGBA8};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Semaphore;
-
-use crate::exif;
+use tiff::encoder::{colortype, compression as tiff_compression};
+
+use crate::{
+    density, exif,
+    handler::BadRequest,
+    ico, isobmff,
+    limiter::{QueueStats, QueuedSemaphore},
+    timing::ServerTiming,
+    xmp,
+};
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum InputImageType {
     Avif,
+    Ico,
     Jpeg,
     Png,
     Tiff,
@@ -23,8 +44,17 @@ pub enum InputImageType {
 }
 
 impl InputImageType {
+    pub const ALL: [Self; 6] = [
+        Self::Avif,
+        Self::Ico,
+        Self::Jpeg,
+        Self::Png,
+        Self::Tiff,
+        Self::Webp,
+    ];
+
     fn determine_image_type(buf: &[u8]) -> Option<Self> {
-        if buf.len() < 12 {
+        if buf.len() < MIN_IMAGE_LEN {
             return None;
         }
 
@@ -44,6 +74,11 @@ impl InputImageType {
             return Some(Self::Tiff);
         }
 
+        const ICO: &[u8; 4] = b"\x00\x00\x01\x00";
+        if buf.starts_with(ICO) {
+            return Some(Self::Ico);
+        }
+
         const WEBP: &[u8; 4] = b"\x57\x45\x42\x50";
         if buf[8..].starts_with(WEBP) {
             return Some(Self::Webp);
@@ -56,14 +91,31 @@ impl InputImageType {
 
         None
     }
+
+    pub fn mimetype(self) -> &'static str {
+        match self {
+            Self::Ico => "image/x-icon",
+            _ => ImageType::from(self).mimetype(),
+        }
+    }
+
+    // returns true if the format can be served to browsers as-is.
+    fn is_web_compatible(self) -> bool {
+        match self {
+            Self::Avif | Self::Jpeg | Self::Png | Self::Webp => true,
+            Self::Ico | Self::Tiff => false,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ImageType {
     Avif,
+    #[serde(alias = "jpg")]
     Jpeg,
     Png,
+    #[serde(alias = "tif")]
     Tiff,
     Webp,
 }
@@ -72,6 +124,7 @@ impl From<InputImageType> for ImageType {
     fn from(value: InputImageType) -> Self {
         match value {
             InputImageType::Avif => Self::Avif,
+            InputImageType::Ico => Self::Png,
             InputImageType::Jpeg => Self::Jpeg,
             InputImageType::Png => Self::Png,
             InputImageType::Tiff => Self::Tiff,
@@ -97,12 +150,13 @@ impl ImageType {
         }
     }
 
+    /// Parses the format name, also accepting the common file extensions.
     pub fn parse(s: &str) -> Option<Self> {
         match s {
             "avif" => Some(Self::Avif),
-            "jpeg" => Some(Self::Jpeg),
+            "jpeg" | "jpg" => Some(Self::Jpeg),
             "png" => Some(Self::Png),
-            "tiff" => Some(Self::Tiff),
+            "tiff" | "tif" => Some(Self::Tiff),
             "webp" => Some(Self::Webp),
             _ => None,
         }
@@ -118,15 +172,37 @@ impl ImageType {
         }
     }
 
+    fn is_lossless(self) -> bool {
+        matches!(self, ImageType::Png | ImageType::Tiff)
+    }
+
+    fn supports_alpha(self) -> bool {
+        match self {
+            ImageType::Avif | ImageType::Png | ImageType::Tiff | ImageType::Webp => true,
+            ImageType::Jpeg => false,
+        }
+    }
+
     fn default_quality(self) -> u32 {
         match self {
             ImageType::Avif => 50,
             ImageType::Jpeg | ImageType::Png | ImageType::Tiff | ImageType::Webp => 75,
         }
     }
+
+    // returns the built-in numeric quality for the named quality preset.
+    fn preset_quality(self, preset: Quality) -> u32 {
+        match (self, preset) {
+            (ImageType::Avif, Quality::Low) => 30,
+            (ImageType::Avif, Quality::High) => 70,
+            (_, Quality::Low) => 50,
+            (_, Quality::High) => 85,
+            _ => self.default_quality(),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize)]
 pub struct ProcessOptions {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub width: Option<u32>,
@@ -135,9 +211,355 @@ pub struct ProcessOptions {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub out_type: Option<ImageType>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub quality: Option<u32>,
+    pub quality: Option<Quality>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub blur: Option<u32>,
+    /// A blur sigma as a percentage of the image's width, which takes
+    /// precedence over blur.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blur_pct: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blur_type: Option<BlurType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<FilterOrder>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius: Option<Radius>,
+    /// Only resize when the source exceeds the requested dimensions.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub if_larger: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop: Option<Crop>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpi: Option<u32>,
+    /// Replaces the output with a solid image of the source's average color.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub average: bool,
+    /// Ignores the EXIF orientation, leaving the pixels as they're stored.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skip_orient: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub png_level: Option<PngLevel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tiff_compression: Option<TiffCompression>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avif_depth: Option<u8>,
+    /// Encodes WebP images losslessly after the near-lossless preprocessing
+    /// level, from 0 (the most preprocessing) to 100 (none).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub near_lossless: Option<u32>,
+    /// Decodes as much of a truncated JPEG as possible, rather than failing.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    /// Returns the source image untouched, in any supported format. This only
+    /// applies when no other processing is requested.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub passthrough: bool,
+    /// Removes the EXIF GPS fields from source images that are returned
+    /// without being re-encoded. Re-encoded images never include EXIF data.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub strip_gps: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TiffCompression {
+    None,
+    Lzw,
+    #[default]
+    Deflate,
+    Packbits,
+}
+
+/// The PNG compression effort, trading CPU time for smaller files.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngLevel {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngLevel {
+    /// Parses either a named level or a numeric level from 0 to 9.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fast" => Some(Self::Fast),
+            "default" => Some(Self::Default),
+            "best" => Some(Self::Best),
+            _ => match s.parse::<u32>().ok()? {
+                0..=3 => Some(Self::Fast),
+                4..=6 => Some(Self::Default),
+                7..=9 => Some(Self::Best),
+                _ => None,
+            },
+        }
+    }
+
+    fn compression(self) -> CompressionType {
+        match self {
+            PngLevel::Fast => CompressionType::Fast,
+            PngLevel::Default => CompressionType::Default,
+            PngLevel::Best => CompressionType::Best,
+        }
+    }
+}
+
+// The denominator used for relative crop values, which are stored as integers
+// so that they can be used in cache keys.
+const CROP_SCALE: u64 = 1_000_000;
+
+/// A rectangle that the image is cropped to before resizing.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Crop {
+    /// A rectangle in source pixels.
+    Pixels {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// A rectangle relative to the source dimensions, in units of 1/CROP_SCALE.
+    Relative {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Crop {
+    /// Parses a relative crop from four comma-separated fractions in the form
+    /// "x,y,w,h". Returns None if the rectangle isn't within the image.
+    pub fn parse_relative(s: &str) -> Option<Self> {
+        let vals = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let &[x, y, width, height] = vals.as_slice() else {
+            return None;
+        };
+        if [x, y, width, height]
+            .iter()
+            .any(|v| !(0.0..=1.0).contains(v))
+        {
+            return None;
+        }
+
+        // Validate using the scaled values to avoid floating point error.
+        let scale = |v: f64| (v * CROP_SCALE as f64).round() as u32;
+        let (x, y, width, height) = (scale(x), scale(y), scale(width), scale(height));
+        let max = CROP_SCALE as u32;
+        if width == 0 || height == 0 || x + width > max || y + height > max {
+            return None;
+        }
+        Some(Self::Relative {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    // returns the crop rectangle in pixels, clamped to the provided image
+    // dimensions.
+    fn rect(self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (x, y, crop_width, crop_height) = match self {
+            Self::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => (x, y, width, height),
+            Self::Relative {
+                x,
+                y,
+                width: w,
+                height: h,
+            } => {
+                let scale = |v: u32, size: u32| ((v as u64 * size as u64) / CROP_SCALE) as u32;
+                (
+                    scale(x, width),
+                    scale(y, height),
+                    scale(w, width),
+                    scale(h, height),
+                )
+            }
+        };
+
+        let x = x.min(width.saturating_sub(1));
+        let y = y.min(height.saturating_sub(1));
+        let crop_width = crop_width.min(width - x).max(1);
+        let crop_height = crop_height.min(height - y).max(1);
+        (x, y, crop_width, crop_height)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Quality {
+    Value(u32),
+    /// Searches for the lowest quality that is perceptually close to the
+    /// source image.
+    Auto,
+    Low,
+    Medium,
+    High,
+    /// Uses lossless encoding where the output format supports it, and the
+    /// maximum quality otherwise.
+    Lossless,
+}
+
+impl Quality {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Self::Auto),
+            "low" => Some(Self::Low),
+            "medium" => Some(Self::Medium),
+            "high" => Some(Self::High),
+            "lossless" => Some(Self::Lossless),
+            _ => s.parse().ok().map(|v: u32| Self::Value(v.clamp(1, 100))),
+        }
+    }
+}
+
+// Numeric qualities serialize as plain numbers, as they did before the named
+// qualities were added, so that existing disk cache keys are unchanged.
+impl Serialize for Quality {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Quality::Value(v) => serializer.serialize_u32(*v),
+            Quality::Auto => serializer.serialize_str("auto"),
+            Quality::Low => serializer.serialize_str("low"),
+            Quality::Medium => serializer.serialize_str("medium"),
+            Quality::High => serializer.serialize_str("high"),
+            Quality::Lossless => serializer.serialize_str("lossless"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Radius {
+    Pixels(u32),
+    /// The largest possible radius, producing a circle for square images.
+    Max,
+}
+
+impl Radius {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "max" => Some(Self::Max),
+            _ => s.parse().ok().filter(|&v| v > 0).map(Self::Pixels),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlurType {
+    #[default]
+    Gaussian,
+    Box,
+}
+
+/// Whether filters, like blur, are applied before or after resizing.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOrder {
+    #[default]
+    ResizeFirst,
+    FilterFirst,
+}
+
+impl ProcessOptions {
+    /// Returns true if no transformations have been requested.
+    pub fn is_noop(&self) -> bool {
+        ProcessOptions {
+            strip_gps: false,
+            ..*self
+        } == Self::default()
+    }
+
+    /// Returns the options for a low quality image placeholder: a tiny,
+    /// heavily blurred WebP image. A box blur is used, as it's much faster and
+    /// looks the same at this size.
+    pub fn lqip(skip_orient: bool) -> Self {
+        ProcessOptions {
+            width: Some(LQIP_SIZE),
+            height: Some(LQIP_SIZE),
+            out_type: Some(ImageType::Webp),
+            quality: Some(Quality::Value(LQIP_QUALITY)),
+            blur_pct: Some(LQIP_BLUR_PCT),
+            blur_type: Some(BlurType::Box),
+            skip_orient,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the options with defaults and options that have no effect
+    /// removed, so that equivalent requests share a cache key.
+    pub fn canonical(mut self) -> Self {
+        self.width = self.width.filter(|&v| v > 0);
+        self.height = self.height.filter(|&v| v > 0);
+        let has_size = self.width.is_some() || self.height.is_some();
+        self.blur_pct = self.blur_pct.filter(|&v| v > 0);
+        self.blur = self.blur.filter(|&v| v > 0 && self.blur_pct.is_none());
+        self.if_larger &= has_size;
+
+        let has_blur = self.blur.is_some() || self.blur_pct.is_some();
+        self.blur_type = self
+            .blur_type
+            .filter(|&v| has_blur && v != BlurType::default());
+        self.order = self
+            .order
+            .filter(|&v| has_blur && v != FilterOrder::default());
+
+        // Encoder options only apply to their own format, which may also be
+        // the source format when the output format isn't set.
+        self.png_level = self.png_level.filter(|&v| v != PngLevel::default());
+        self.tiff_compression = self
+            .tiff_compression
+            .filter(|&v| v != TiffCompression::default());
+        self.avif_depth = self.avif_depth.filter(|&v| v != 8);
+        if let Some(out_type) = self.out_type {
+            self = self.for_output_type(out_type);
+        }
+        // Options that only affect decoding don't prevent a passthrough.
+        self.passthrough &= ProcessOptions {
+            passthrough: false,
+            skip_orient: false,
+            partial: false,
+            ..self
+        }
+        .is_noop();
+        if self.passthrough {
+            self.skip_orient = false;
+            self.partial = false;
+        }
+        self
+    }
+
+    /// Returns the options with those that don't apply to the output format
+    /// removed. Used to canonicalize requests without an output format once
+    /// the source image's format is known.
+    pub fn for_output_type(mut self, out_type: ImageType) -> Self {
+        // PNG and TIFF are always lossless, so the quality is ignored.
+        if out_type.is_lossless() {
+            self.quality = None;
+        }
+        self.png_level = self.png_level.filter(|_| out_type == ImageType::Png);
+        self.tiff_compression = self
+            .tiff_compression
+            .filter(|_| out_type == ImageType::Tiff);
+        self.avif_depth = self.avif_depth.filter(|_| out_type == ImageType::Avif);
+        self.near_lossless = self.near_lossless.filter(|_| out_type == ImageType::Webp);
+        // Near-lossless images are encoded losslessly, ignoring the quality.
+        if self.near_lossless.is_some() {
+            self.quality = None;
+        }
+        self
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -151,16 +573,52 @@ pub struct ImageOutput {
     pub orig_type: InputImageType,
     pub orig_width: u32,
     pub orig_height: u32,
+    /// The time that the image was generated. For disk cache entries, this is
+    /// restored from the file's modification time.
+    #[serde(skip, default = "SystemTime::now")]
+    pub created: SystemTime,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct MetadataOptions {
     pub thumbhash: bool,
+    pub blurhash: Option<BlurhashComponents>,
+    pub dominant: Option<DominantOptions>,
+    pub strip_gps: bool,
+    pub skip_orient: bool,
+    /// Read the dimensions from the image header when possible, rather than
+    /// decoding the image. Options that require the pixels are ignored.
+    pub dimensions_only: bool,
+    /// Return the metadata that doesn't require the pixels when the image
+    /// can't be decoded, rather than failing.
+    pub allow_partial: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DominantOptions {
+    pub palette: Option<usize>,
+}
+
+impl DominantOptions {
+    pub fn new(palette: Option<u32>) -> Self {
+        DominantOptions {
+            palette: palette.map(|v| v.clamp(1, 16) as usize),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BlurhashComponents {
+    pub x: u32,
+    pub y: u32,
 }
 
-impl MetadataOptions {
-    pub fn new(thumbhash: bool) -> Self {
-        MetadataOptions { thumbhash }
+impl BlurhashComponents {
+    pub fn new(x: Option<u32>, y: Option<u32>) -> Self {
+        BlurhashComponents {
+            x: x.unwrap_or(4).clamp(1, 9),
+            y: y.unwrap_or(3).clamp(1, 9),
+        }
     }
 }
 
@@ -173,54 +631,312 @@ pub struct ImageMetadata {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thumbhash: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<exif::Data>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xmp: Option<xmp::Data>,
+    /// The image couldn't be decoded, so only the fields that can be read
+    /// without the pixel data are included.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessorConfig {
+    /// The maximum number of pixels a source image may declare before it's
+    /// rejected without being decoded.
+    pub max_pixels: u64,
+    /// The quality used for each lossy format when none is requested.
+    pub default_quality: DefaultQuality,
+    /// The number of threads a single AVIF encode can use.
+    pub avif_threads: usize,
+    /// The maximum number of images waiting for a worker before new images
+    /// are rejected, or None to wait indefinitely.
+    pub max_queue: Option<usize>,
+    /// The maximum size of an encoded output image in bytes. Larger images are
+    /// re-encoded at a lower quality with quality=auto, and otherwise rejected
+    /// with an OutputTooLarge error.
+    pub max_output_bytes: Option<usize>,
+    /// The maximum number of frames an animated source may contain.
+    pub max_frames: usize,
+    /// Drops the frames beyond the maximum, rather than rejecting the image.
+    pub truncate_frames: bool,
+    /// The largest blur sigma applied, from either an absolute or relative
+    /// blur, which is at most MAX_BLUR.
+    pub max_blur: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultQuality {
+    pub avif: u32,
+    pub jpeg: u32,
+    pub webp: u32,
+}
+
+impl Default for DefaultQuality {
+    fn default() -> Self {
+        DefaultQuality {
+            avif: ImageType::Avif.default_quality(),
+            jpeg: ImageType::Jpeg.default_quality(),
+            webp: ImageType::Webp.default_quality(),
+        }
+    }
+}
+
+impl DefaultQuality {
+    fn get(&self, img_type: ImageType) -> u32 {
+        match img_type {
+            ImageType::Avif => self.avif,
+            ImageType::Jpeg => self.jpeg,
+            ImageType::Webp => self.webp,
+            ImageType::Png | ImageType::Tiff => img_type.default_quality(),
+        }
+    }
+
+    // returns the quality for the named preset. Medium is the configured
+    // default, with low and high offset from it by the same amount as the
+    // built-in presets are from the built-in default.
+    fn preset(&self, img_type: ImageType, preset: Quality) -> u32 {
+        let offset = img_type.preset_quality(preset) as i32 - img_type.default_quality() as i32;
+        (self.get(img_type) as i32 + offset).clamp(1, 100) as u32
+    }
+
+    // returns the quality to encode with, resolving presets and defaults to
+    // numeric values.
+    fn resolve(&self, img_type: ImageType, quality: Option<Quality>) -> Quality {
+        match quality {
+            None => Quality::Value(self.get(img_type)),
+            Some(preset @ (Quality::Low | Quality::Medium | Quality::High)) => {
+                Quality::Value(self.preset(img_type, preset))
+            }
+            Some(quality) => quality,
+        }
+    }
 }
 
 pub struct ImageProccessor {
-    semaphore: Semaphore,
+    semaphore: QueuedSemaphore,
+    config: ProcessorConfig,
 }
 
 impl ImageProccessor {
-    pub fn new(num_workers: usize) -> Self {
+    pub fn new(num_workers: usize, config: ProcessorConfig) -> Self {
         let num_workers = num_workers.max(1);
         ImageProccessor {
-            semaphore: Semaphore::new(num_workers),
+            semaphore: QueuedSemaphore::new(num_workers).with_max_waiting(config.max_queue),
+            config,
+        }
+    }
+
+    /// Returns the number of idle workers and of images waiting for one.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.semaphore.stats()
+    }
+
+    /// Returns the options with the output format and, for lossy formats, the
+    /// default quality that were applied to the image filled in.
+    pub fn resolve_options(&self, mut ops: ProcessOptions, out_type: ImageType) -> ProcessOptions {
+        let quality = Quality::Value(self.config.default_quality.get(out_type));
+        ops.out_type = Some(out_type);
+        if !out_type.is_lossless() {
+            ops.quality = Some(ops.quality.unwrap_or(quality));
         }
+        ops
     }
 
-    pub async fn process_image(&self, b: bytes::Bytes, ops: ProcessOptions) -> Result<ImageOutput> {
+    /// Returns the processed image along with the timing of each stage.
+    pub async fn process_image(
+        &self,
+        b: bytes::Bytes,
+        ops: ProcessOptions,
+    ) -> Result<(ImageOutput, ServerTiming)> {
         let _permit = self.semaphore.acquire().await?;
-        tokio::task::spawn_blocking(move || process_image_inner(b, ops)).await?
+        let config = self.config;
+
+        // The blocking task can't be aborted, so signal it to stop at the next
+        // stage if this future is dropped (e.g. the request timed out or the
+        // client disconnected). The task may also wait in the blocking pool's
+        // queue, so it checks before decoding too.
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _guard = CancelOnDrop(cancelled.clone());
+
+        tokio::task::spawn_blocking(move || {
+            let mut timing = ServerTiming::new();
+            let output = process_image_inner(b, ops, config, &cancelled, &mut timing)?;
+            Ok((output, timing))
+        })
+        .await?
     }
 
     pub async fn metadata(&self, b: bytes::Bytes, ops: MetadataOptions) -> Result<ImageMetadata> {
         let _permit = self.semaphore.acquire().await?;
-        tokio::task::spawn_blocking(move || metadata_inner(b, ops)).await?
+        let config = self.config;
+        tokio::task::spawn_blocking(move || metadata_inner(b, ops, config)).await?
     }
 }
 
-fn process_image_inner(b: bytes::Bytes, ops: ProcessOptions) -> Result<ImageOutput> {
+fn process_image_inner(
+    b: bytes::Bytes,
+    mut ops: ProcessOptions,
+    config: ProcessorConfig,
+    cancelled: &AtomicBool,
+    timing: &mut ServerTiming,
+) -> Result<ImageOutput> {
+    // Reject options that can't be encoded before doing any decode work.
+    if let Some(out_type) = ops
+        .out_type
+        .filter(|v| ops.radius.is_some() && !v.supports_alpha())
+    {
+        return Err(BadRequest(format!(
+            "output format {out_type} does not support transparency, which is required by radius"
+        ))
+        .into());
+    }
+    check_source_len(&b)?;
+    let img_type = type_from_raw(&b)?;
+    let b = limit_frames(img_type, b, config)?;
     let body = b.as_ref();
     let data = exif::ExifData::new(body);
-    let img_type = type_from_raw(body)?;
+    check_dimensions(img_type, body, config)?;
+    let orientation = get_orientation(img_type, body, &data);
+    let should_orient = !ops.skip_orient && orientation.is_some_and(|v| v != 1);
+
+    // Drop the resize entirely when the source is small enough, which may
+    // allow the original image to be returned untouched.
+    if ops.if_larger && !should_orient {
+        if let Some((width, height)) = probe_dimensions(img_type, body) {
+            if !exceeds_target(&ops, width, height) {
+                ops.width = None;
+                ops.height = None;
+                ops.if_larger = false;
+            }
+        }
+    }
+
+    // Only sources returned here keep their EXIF data, including any embedded
+    // thumbnail, as they're returned as-is. Re-encoded images have no EXIF.
+    if ops.passthrough || (ops.is_noop() && img_type.is_web_compatible() && !should_orient) {
+        // Formats without a header probe are only decoded for their dimensions
+        // when the source was explicitly requested.
+        let dimensions = match probe_dimensions(img_type, body) {
+            None if ops.passthrough => Some(decode_image(img_type, body, None)?.dimensions()),
+            dimensions => dimensions,
+        };
+        // Sources with GPS fields that can't be removed in place are
+        // re-encoded instead, which drops all EXIF data.
+        let buf = match &data {
+            Some(data) if ops.strip_gps && data.has_gps() => {
+                data.strip_gps_from(body).map(bytes::Bytes::from)
+            }
+            _ => Some(b.clone()),
+        };
+        if let (Some((width, height)), Some(buf)) = (dimensions, buf) {
+            return Ok(ImageOutput {
+                buf,
+                img_type: img_type.into(),
+                width,
+                height,
+                orig_size: body.len() as u64,
+                orig_type: img_type,
+                orig_width: width,
+                orig_height: height,
+                created: SystemTime::now(),
+            });
+        }
+    }
+
+    check_cancelled(cancelled)?;
+    // Only a single frame is processed, as animations are decoded to their
+    // first frame. Resizing is parallelized by the image crate instead.
+    let start = SystemTime::now();
+    let img = if ops.partial && matches!(img_type, InputImageType::Jpeg) {
+        decode_jpeg(body, true)?
+    } else {
+        decode_image(img_type, body, ops.width.max(ops.height))?
+    };
+    timing.push("decode", start);
+    check_cancelled(cancelled)?;
 
-    let img = decode_image(img_type, body)?;
-    let img = auto_orient(&data, img);
+    let start = SystemTime::now();
+    let img = if should_orient {
+        auto_orient(orientation, img)
+    } else {
+        img
+    };
     let (orig_width, orig_height) = img.dimensions();
+    timing.push("orient", start);
+
+    let img = if let Some(crop) = ops.crop {
+        let start = SystemTime::now();
+        let (x, y, width, height) = crop.rect(orig_width, orig_height);
+        let img = img.crop_imm(x, y, width, height);
+        timing.push("crop", start);
+        img
+    } else {
+        img
+    };
 
-    let mut out_img = resize(&img, ops.width, ops.height);
-    let (width, height) = out_img.dimensions();
+    let filter_first = ops.order.unwrap_or_default() == FilterOrder::FilterFirst;
+    let img = if filter_first {
+        let start = SystemTime::now();
+        let img = apply_filters(img, &ops, config.max_blur);
+        timing.push("filter", start);
+        check_cancelled(cancelled)?;
+        img
+    } else {
+        img
+    };
 
-    if let Some(blur) = ops.blur {
-        let sigma = blur.min(100) as f32;
-        out_img = out_img.blur(sigma);
+    let start = SystemTime::now();
+    let (crop_width, crop_height) = img.dimensions();
+    let mut out_img = if ops.average {
+        solid_average(&img, ops.width.unwrap_or(1), ops.height.unwrap_or(1))
+    } else if ops.if_larger && !exceeds_target(&ops, crop_width, crop_height) {
+        img
+    } else {
+        resize(&img, ops.width, ops.height)
+    };
+    let (width, height) = out_img.dimensions();
+    timing.push("resize", start);
+    check_cancelled(cancelled)?;
+
+    if !filter_first {
+        let start = SystemTime::now();
+        out_img = apply_filters(out_img, &ops, config.max_blur);
+        timing.push("filter", start);
+        check_cancelled(cancelled)?;
     }
 
-    let out_type = ops.out_type.unwrap_or_else(|| img_type.into());
-    let quality = ops
-        .quality
-        .map_or_else(|| out_type.default_quality(), |v| v.clamp(1, 100));
-    let buf = encode_image(&out_img, out_type, quality)?;
+    if let Some(radius) = ops.radius {
+        let start = SystemTime::now();
+        out_img = round_corners(&out_img, radius);
+        timing.push("mask", start);
+    }
+    // Encoding is usually the most expensive stage, so don't start it for a
+    // request that has already gone away.
+    check_cancelled(cancelled)?;
+
+    let start = SystemTime::now();
+    let out_type = ops
+        .out_type
+        .unwrap_or_else(|| default_output_type(img_type, &ops));
+    let encode_ops = EncodeOptions {
+        quality: config.default_quality.resolve(out_type, ops.quality),
+        dpi: ops.dpi,
+        png_level: ops.png_level.unwrap_or_default(),
+        tiff_compression: ops.tiff_compression.unwrap_or_default(),
+        avif_threads: config.avif_threads,
+        avif_depth: ops.avif_depth.unwrap_or(8),
+        near_lossless: ops.near_lossless,
+        max_bytes: config.max_output_bytes,
+    };
+    let buf = encode_image(&out_img, out_type, encode_ops)?;
+    timing.push("encode", start);
 
     Ok(ImageOutput {
         buf: bytes::Bytes::from(buf),
@@ -231,9 +947,73 @@ fn process_image_inner(b: bytes::Bytes, ops: ProcessOptions) -> Result<ImageOutp
         orig_type: img_type,
         orig_width,
         orig_height,
+        created: SystemTime::now(),
     })
 }
 
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+fn check_cancelled(cancelled: &AtomicBool) -> Result<()> {
+    if cancelled.load(Ordering::Acquire) {
+        return Err(anyhow!("image processing was cancelled"));
+    }
+    Ok(())
+}
+
+/// The largest blur sigma applied, with larger values being clamped.
+pub const MAX_BLUR: u32 = 100;
+
+/// The largest blur sigma, as a percentage of the image's width, applied.
+pub const MAX_BLUR_PCT: u32 = 25;
+
+/// Returns the largest blur percentage allowed with the configured maximum
+/// blur, which is MAX_BLUR_PCT scaled down by the same proportion.
+pub fn max_blur_pct(max_blur: u32) -> u32 {
+    MAX_BLUR_PCT * max_blur.min(MAX_BLUR) / MAX_BLUR
+}
+
+// The longest side, blur and quality of low quality image placeholders.
+const LQIP_SIZE: u32 = 32;
+const LQIP_BLUR_PCT: u32 = 10;
+const LQIP_QUALITY: u32 = 40;
+
+/// The minimum number of bytes needed to identify an image's format, and so
+/// the size below which a source must be empty or truncated.
+pub const MIN_IMAGE_LEN: usize = 12;
+
+pub fn check_source_len(b: &[u8]) -> Result<()> {
+    if b.len() < MIN_IMAGE_LEN {
+        return Err(anyhow!(
+            "empty or truncated source: received {} bytes",
+            b.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the format that the source image is encoded to when no output
+/// format is requested, or None if the source's format isn't supported.
+pub fn source_output_type(b: &[u8], ops: &ProcessOptions) -> Option<ImageType> {
+    InputImageType::determine_image_type(b).map(|img_type| default_output_type(img_type, ops))
+}
+
+// returns the source's own format, unless it can't represent the requested
+// processing.
+fn default_output_type(img_type: InputImageType, ops: &ProcessOptions) -> ImageType {
+    let out_type = ImageType::from(img_type);
+    if ops.radius.is_some() && !out_type.supports_alpha() {
+        ImageType::Png
+    } else {
+        out_type
+    }
+}
+
 fn type_from_raw(b: &[u8]) -> ImageResult<InputImageType> {
     InputImageType::determine_image_type(b).ok_or_else(|| {
         ImageError::Unsupported(UnsupportedError::from_format_and_kind(
@@ -243,10 +1023,135 @@ fn type_from_raw(b: &[u8]) -> ImageResult<InputImageType> {
     })
 }
 
-fn decode_image(img_type: InputImageType, raw: &[u8]) -> Result<DynamicImage> {
+// rejects images whose declared dimensions exceed the configured maximum number
+// of pixels, before any pixel data is allocated.
+fn check_dimensions(img_type: InputImageType, raw: &[u8], config: ProcessorConfig) -> Result<()> {
+    let max_pixels = config.max_pixels;
+    let Some((width, height)) = probe_dimensions(img_type, raw) else {
+        return Ok(());
+    };
+    if width as u64 * height as u64 > max_pixels {
+        return Err(anyhow!(
+            "image dimensions {width}x{height} exceed the maximum of {max_pixels} pixels"
+        ));
+    }
+    Ok(())
+}
+
+// rejects animated images with more than the configured maximum number of
+// frames, or drops the extra frames when truncation is enabled. Frames are
+// counted from the container's chunks without decoding them, as only the first
+// frame is ever decoded.
+fn limit_frames(
+    img_type: InputImageType,
+    b: bytes::Bytes,
+    config: ProcessorConfig,
+) -> Result<bytes::Bytes> {
+    let InputImageType::Webp = img_type else {
+        return Ok(b);
+    };
+    let Some(chunks) = b.get(12..).map(RiffChunks) else {
+        return Ok(b);
+    };
+    let max_frames = config.max_frames;
+    let frames = chunks.filter(|(kind, _)| kind == b"ANMF").count();
+    if frames <= max_frames {
+        return Ok(b);
+    }
+    if !config.truncate_frames {
+        return Err(BadRequest(format!(
+            "image contains {frames} frames, exceeding the maximum of {max_frames}"
+        ))
+        .into());
+    }
+
+    Ok(truncate_frames(&b, max_frames)?.into())
+}
+
+// returns the WebP image with only its first `max_frames` animation frames.
+fn truncate_frames(b: &[u8], max_frames: usize) -> Result<Vec<u8>> {
+    let header = b.get(..12).ok_or_else(|| anyhow!("invalid webp image"))?;
+    let mut out = header.to_vec();
+    let mut kept = 0;
+    for (kind, chunk) in RiffChunks(&b[12..]) {
+        if &kind == b"ANMF" {
+            if kept == max_frames {
+                continue;
+            }
+            kept += 1;
+        }
+        out.extend_from_slice(chunk);
+    }
+    let size = u32::try_from(out.len() - 8)?;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    Ok(out)
+}
+
+/// An iterator over the type and raw bytes, including the header and any
+/// padding, of each chunk in a RIFF container's body.
+struct RiffChunks<'a>(&'a [u8]);
+
+impl<'a> Iterator for RiffChunks<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let buf = self.0;
+        let kind: [u8; 4] = buf.get(..4)?.try_into().ok()?;
+        let size = u32::from_le_bytes(buf.get(4..8)?.try_into().ok()?) as usize;
+        // Chunks are padded to an even size.
+        let end = (8 + size + (size & 1)).min(buf.len());
+        self.0 = &buf[end..];
+        Some((kind, &buf[..end]))
+    }
+}
+
+// reads the image dimensions from the header without decoding the image.
+fn probe_dimensions(img_type: InputImageType, raw: &[u8]) -> Option<(u32, u32)> {
+    match img_type {
+        InputImageType::Jpeg => {
+            let header = turbojpeg::Decompressor::new().ok()?.read_header(raw).ok()?;
+            Some((header.width as u32, header.height as u32))
+        }
+        InputImageType::Png => image::ImageReader::with_format(Cursor::new(raw), ImageFormat::Png)
+            .into_dimensions()
+            .ok(),
+        InputImageType::Tiff => {
+            image::ImageReader::with_format(Cursor::new(raw), ImageFormat::Tiff)
+                .into_dimensions()
+                .ok()
+        }
+        InputImageType::Webp => probe_webp_dimensions(raw),
+        InputImageType::Avif | InputImageType::Ico => None,
+    }
+}
+
+fn probe_webp_dimensions(raw: &[u8]) -> Option<(u32, u32)> {
+    let chunk = raw.get(12..16)?;
+    let data = raw.get(20..30)?;
+    match chunk {
+        b"VP8 " => {
+            let width = u16::from_le_bytes([data[6], data[7]]) & 0x3fff;
+            let height = u16::from_le_bytes([data[8], data[9]]) & 0x3fff;
+            Some((width as u32, height as u32))
+        }
+        b"VP8L" => {
+            let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => {
+            let width = u32::from_le_bytes([data[4], data[5], data[6], 0]);
+            let height = u32::from_le_bytes([data[7], data[8], data[9], 0]);
+            Some((width + 1, height + 1))
+        }
+        _ => None,
+    }
+}
+
+fn decode_image(img_type: InputImageType, raw: &[u8], size: Option<u32>) -> Result<DynamicImage> {
     match img_type {
         InputImageType::Avif => decode_avif(raw),
-        InputImageType::Jpeg => decode_jpeg(raw),
+        InputImageType::Ico => ico::decode(raw, size),
+        InputImageType::Jpeg => decode_jpeg(raw, false),
         InputImageType::Png => decode_png(raw),
         InputImageType::Tiff => decode_tiff(raw),
         InputImageType::Webp => decode_webp(raw),
@@ -257,11 +1162,106 @@ fn decode_avif(raw: &[u8]) -> Result<DynamicImage> {
     libavif_image::read(raw).map_err(Into::into)
 }
 
-fn decode_jpeg(raw: &[u8]) -> Result<DynamicImage> {
-    let img: image::RgbImage = decompress_jpeg_internal(raw)?;
+// decodes the JPEG, returning the rows that could be decoded (with the rest
+// left gray) if partial is true and the image is truncated or corrupt. The
+// header must still be valid.
+fn decode_jpeg(raw: &[u8], partial: bool) -> Result<DynamicImage> {
+    let header = turbojpeg::Decompressor::new()?.read_header(raw)?;
+    if matches!(
+        header.colorspace,
+        turbojpeg::Colorspace::CMYK | turbojpeg::Colorspace::YCCK
+    ) {
+        return decode_jpeg_cmyk(raw, partial);
+    }
+
+    let img: image::RgbImage = decompress_jpeg_internal(raw, partial)?;
     Ok(image::DynamicImage::from(img))
 }
 
+// decodes a CMYK (or YCCK) JPEG, converting the pixels to RGB. JPEGs written
+// by Adobe applications store inverted CMYK values, which is indicated by the
+// presence of an Adobe APP14 marker.
+fn decode_jpeg_cmyk(raw: &[u8], partial: bool) -> Result<DynamicImage> {
+    let mut decompressor = turbojpeg::Decompressor::new()?;
+    let header = decompressor.read_header(raw)?;
+    let inverted = has_adobe_marker(raw);
+
+    let fill = cmyk_fill(partial, inverted);
+    let format = turbojpeg::PixelFormat::CMYK;
+    let pitch = header.width * format.size();
+    let mut cmyk = fill.repeat(header.width * header.height);
+    let image = turbojpeg::Image {
+        pixels: &mut cmyk[..],
+        width: header.width,
+        pitch,
+        height: header.height,
+        format,
+    };
+    if let Err(err) = decompressor.decompress(raw, image) {
+        if !partial {
+            return Err(err.into());
+        }
+    }
+
+    let rgb = cmyk_to_rgb(&cmyk, inverted);
+    let img = image::RgbImage::from_raw(header.width as u32, header.height as u32, rgb)
+        .ok_or_else(|| anyhow!("unable to convert cmyk image"))?;
+    Ok(DynamicImage::from(img))
+}
+
+// returns the CMYK pixel that the image is filled with before decoding. Partial
+// images are filled with gray, like RGB JPEGs, so that any rows that can't be
+// decoded are left gray.
+fn cmyk_fill(partial: bool, inverted: bool) -> [u8; 4] {
+    match (partial, inverted) {
+        (false, _) => [0; 4],
+        (true, false) => [0, 0, 0, 127],
+        (true, true) => [255, 255, 255, 128],
+    }
+}
+
+// converts CMYK pixels to RGB, where inverted pixels store 255 for no ink
+// rather than 0.
+fn cmyk_to_rgb(cmyk: &[u8], inverted: bool) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(cmyk.len() / 4 * 3);
+    for pixel in cmyk.chunks_exact(4) {
+        let [c, m, y, k] = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        let (c, m, y, k) = if inverted {
+            (c as u32, m as u32, y as u32, k as u32)
+        } else {
+            (
+                255 - c as u32,
+                255 - m as u32,
+                255 - y as u32,
+                255 - k as u32,
+            )
+        };
+        rgb.extend_from_slice(&[
+            ((c * k + 127) / 255) as u8,
+            ((m * k + 127) / 255) as u8,
+            ((y * k + 127) / 255) as u8,
+        ]);
+    }
+    rgb
+}
+
+fn has_adobe_marker(raw: &[u8]) -> bool {
+    let mut pos = 2;
+    while pos + 4 <= raw.len() && raw[pos] == 0xFF {
+        let marker = raw[pos + 1];
+        // Stop at the start of the image data.
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([raw[pos + 2], raw[pos + 3]]) as usize;
+        if marker == 0xEE && raw[pos + 4..].starts_with(b"Adobe") {
+            return true;
+        }
+        pos += 2 + len;
+    }
+    false
+}
+
 fn decode_png(raw: &[u8]) -> Result<DynamicImage> {
     image::load_from_memory_with_format(raw, ImageFormat::Png).map_err(Into::into)
 }
@@ -271,30 +1271,84 @@ fn decode_tiff(raw: &[u8]) -> Result<DynamicImage> {
 }
 
 fn decode_webp(raw: &[u8]) -> Result<DynamicImage> {
+    let features = webp::BitstreamFeatures::new(raw)
+        .ok_or_else(|| anyhow!("unable to decode image as webp"))?;
+    if features.has_animation() {
+        return decode_webp_animation(raw);
+    }
     webp::Decoder::new(raw)
         .decode()
         .ok_or_else(|| anyhow!("unable to decode image as webp"))
         .map(|v| v.to_image())
 }
 
-fn auto_orient(data: &Option<exif::ExifData>, img: DynamicImage) -> DynamicImage {
-    if let Some(data) = data {
-        if let Some(orientation) = data.get_orientation() {
-            return match orientation {
-                2 => img.fliph(),
-                3 => img.rotate180(),
-                4 => img.flipv(),
-                5 => img.rotate90().fliph(),
-                6 => img.rotate90(),
-                7 => img.rotate270().fliph(),
-                8 => img.rotate270(),
-                _ => img,
-            };
-        }
-    }
-    img
+// decodes the first frame of an animated webp. The simple decoder rejects
+// animations, so the frame is composited by the demuxing decoder instead. The
+// other frames are removed first, rather than decoding them only to be unused.
+fn decode_webp_animation(raw: &[u8]) -> Result<DynamicImage> {
+    let raw = truncate_frames(raw, 1)?;
+    let anim = webp::AnimDecoder::new(&raw)
+        .decode()
+        .map_err(|err| anyhow!("unable to decode animated webp: {err}"))?;
+    let frame = anim
+        .get_frame(0)
+        .ok_or_else(|| anyhow!("animated webp contains no frames"))?;
+    Ok(DynamicImage::from(&frame))
 }
 
+// returns the image's orientation as an EXIF orientation value. AVIF images
+// store their orientation as item properties, which take precedence over any
+// EXIF metadata.
+fn get_orientation(
+    img_type: InputImageType,
+    raw: &[u8],
+    data: &Option<exif::ExifData>,
+) -> Option<u32> {
+    match img_type {
+        InputImageType::Avif => isobmff::orientation(raw),
+        _ => data.as_ref().and_then(exif::ExifData::get_orientation),
+    }
+}
+
+fn auto_orient(orientation: Option<u32>, img: DynamicImage) -> DynamicImage {
+    match orientation {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
+
+// returns the dimensions of an image after the orientation is applied.
+fn oriented_dimensions(orientation: Option<u32>, width: u32, height: u32) -> (u32, u32) {
+    match orientation {
+        Some(5..=8) => (height, width),
+        _ => (width, height),
+    }
+}
+
+// returns a solid image of the provided dimensions, filled with the average
+// color of the source image.
+fn solid_average(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let pixel = *img.thumbnail_exact(1, 1).to_rgba8().get_pixel(0, 0);
+    DynamicImage::from(image::RgbaImage::from_pixel(width, height, pixel))
+}
+
+// returns true if the provided dimensions are larger than the requested width
+// or height.
+fn exceeds_target(ops: &ProcessOptions, width: u32, height: u32) -> bool {
+    ops.width.is_some_and(|target| width > target)
+        || ops.height.is_some_and(|target| height > target)
+}
+
+// resizes using the image crate's thumbnail functions, which keep no state
+// between calls, so there's no resizer to reuse across requests.
+// Downscaling averages each output pixel over the source pixels it covers,
+// which avoids the ringing of Lanczos filters for large reductions.
 fn resize(img: &DynamicImage, width: Option<u32>, height: Option<u32>) -> DynamicImage {
     let (width, height, should_crop) = get_img_dims(img, width, height);
     assert!(width > 0, "width must be greater than 0");
@@ -324,6 +1378,149 @@ fn resize(img: &DynamicImage, width: Option<u32>, height: Option<u32>) -> Dynami
     }
 }
 
+// applies the requested filters to the image, returning it unchanged if there
+// are none.
+fn apply_filters(img: DynamicImage, ops: &ProcessOptions, max_blur: u32) -> DynamicImage {
+    // A relative blur looks the same at any size, up to the maximum blur.
+    // Large blurs are still cheap, as they're applied to a downscaled image.
+    let sigma = match (ops.blur_pct, ops.blur) {
+        (Some(pct), _) => img.width() as f32 * pct.min(MAX_BLUR_PCT) as f32 / 100.0,
+        (None, Some(blur)) => blur as f32,
+        (None, None) => return img,
+    };
+    let sigma = sigma.min(max_blur.min(MAX_BLUR) as f32);
+    match ops.blur_type.unwrap_or_default() {
+        BlurType::Gaussian => blur_image(&img, sigma),
+        BlurType::Box => box_blur(&img, sigma),
+    }
+}
+
+// applies a gaussian blur to the image. Large blurs are applied to a downscaled
+// copy of the image which is then scaled back up, which is visually very close
+// to the exact blur but much faster.
+fn blur_image(img: &DynamicImage, sigma: f32) -> DynamicImage {
+    const MAX_EXACT_SIGMA: f32 = 8.0;
+
+    if sigma <= MAX_EXACT_SIGMA {
+        return img.blur(sigma);
+    }
+
+    let (width, height) = img.dimensions();
+    let factor = sigma / MAX_EXACT_SIGMA;
+    let small_width = ((width as f32 / factor).round() as u32).max(1);
+    let small_height = ((height as f32 / factor).round() as u32).max(1);
+
+    img.resize_exact(small_width, small_height, FilterType::Triangle)
+        .blur(MAX_EXACT_SIGMA)
+        .resize_exact(width, height, FilterType::Triangle)
+}
+
+// applies a box blur to the image with a radius that roughly matches the visual
+// radius of a gaussian blur with the provided sigma. This is a cheaper, lower
+// quality alternative to the gaussian blur.
+fn box_blur(img: &DynamicImage, sigma: f32) -> DynamicImage {
+    let radius = (sigma * 3_f32.sqrt()).round() as usize;
+    let mut out = match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => img.clone(),
+        _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+    };
+
+    let (width, height) = out.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let channels = out.color().channel_count() as usize;
+    let data: &mut [u8] = match &mut out {
+        DynamicImage::ImageLuma8(buf) => buf,
+        DynamicImage::ImageLumaA8(buf) => buf,
+        DynamicImage::ImageRgb8(buf) => buf,
+        DynamicImage::ImageRgba8(buf) => buf,
+        _ => return out,
+    };
+    box_blur_pass(data, channels, width, height, 1, width, radius);
+    box_blur_pass(data, channels, height, width, width, 1, radius);
+    out
+}
+
+// blurs each line of the image in place using a running sum over the window.
+// Pixels past the edges of a line are clamped to the edge pixel.
+fn box_blur_pass(
+    data: &mut [u8],
+    channels: usize,
+    len: usize,
+    lines: usize,
+    pixel_stride: usize,
+    line_stride: usize,
+    radius: usize,
+) {
+    if len == 0 || radius == 0 {
+        return;
+    }
+
+    let window = (2 * radius + 1) as u32;
+    let mut line = vec![0; len * channels];
+    for l in 0..lines {
+        for i in 0..len {
+            let src = (l * line_stride + i * pixel_stride) * channels;
+            line[i * channels..(i + 1) * channels].copy_from_slice(&data[src..src + channels]);
+        }
+
+        for c in 0..channels {
+            let value = |i: usize| line[i.min(len - 1) * channels + c] as u32;
+            let mut sum = value(0) * radius as u32 + (0..=radius).map(value).sum::<u32>();
+            for i in 0..len {
+                let dst = (l * line_stride + i * pixel_stride) * channels + c;
+                data[dst] = ((sum + window / 2) / window) as u8;
+                sum += value(i + radius + 1);
+                sum -= value(i.saturating_sub(radius));
+            }
+        }
+    }
+}
+
+// makes the corners of the image transparent using an anti-aliased, rounded
+// rectangle mask.
+fn round_corners(img: &DynamicImage, radius: Radius) -> DynamicImage {
+    let mut out = img.to_rgba8();
+    let (width, height) = out.dimensions();
+    let (w, h) = (width as f32, height as f32);
+    let (rx, ry) = match radius {
+        Radius::Pixels(r) => {
+            let r = (r as f32).min(w / 2.0).min(h / 2.0);
+            (r, r)
+        }
+        Radius::Max => (w / 2.0, h / 2.0),
+    };
+    if rx <= 0.0 || ry <= 0.0 {
+        return DynamicImage::ImageRgba8(out);
+    }
+
+    for (x, y, pixel) in out.enumerate_pixels_mut() {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let cx = if px < rx {
+            rx
+        } else if px > w - rx {
+            w - rx
+        } else {
+            continue;
+        };
+        let cy = if py < ry {
+            ry
+        } else if py > h - ry {
+            h - ry
+        } else {
+            continue;
+        };
+
+        let dist = (((px - cx) / rx).powi(2) + ((py - cy) / ry).powi(2)).sqrt();
+        let coverage = ((1.0 - dist) * rx.min(ry) + 0.5).clamp(0.0, 1.0);
+        pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+    }
+
+    DynamicImage::ImageRgba8(out)
+}
+
 fn get_img_dims(img: &DynamicImage, width: Option<u32>, height: Option<u32>) -> (u32, u32, bool) {
     if let (Some(width), Some(height)) = (width, height) {
         return (width, height, true);
@@ -348,94 +1545,630 @@ fn get_img_dims(img: &DynamicImage, width: Option<u32>, height: Option<u32>) ->
     (orig_width, orig_height, false)
 }
 
-fn encode_image(img: &DynamicImage, img_type: ImageType, quality: u32) -> Result<Vec<u8>> {
+// The minimum SSIM between the source and encoded images for quality=auto.
+const AUTO_QUALITY_TARGET: f64 = 0.98;
+// The maximum number of encodes attempted when searching for a quality.
+const AUTO_QUALITY_MAX_ATTEMPTS: usize = 5;
+const AUTO_QUALITY_MIN: u32 = 30;
+const AUTO_QUALITY_MAX: u32 = 95;
+
+/// An error returned when an encoded image exceeds the maximum output size.
+/// The request is valid, but the image can't be produced within the limit.
+#[derive(Debug)]
+pub struct OutputTooLarge(pub String);
+
+impl Display for OutputTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for OutputTooLarge {}
+
+// The options used when encoding the output image.
+#[derive(Clone, Copy, Debug)]
+struct EncodeOptions {
+    quality: Quality,
+    dpi: Option<u32>,
+    png_level: PngLevel,
+    tiff_compression: TiffCompression,
+    /// The number of threads used when encoding AVIF images.
+    avif_threads: usize,
+    /// The bit depth of AVIF images, either 8 or 10.
+    avif_depth: u8,
+    /// The near-lossless level of WebP images, which overrides the quality.
+    near_lossless: Option<u32>,
+    /// The maximum size of the encoded image in bytes.
+    max_bytes: Option<usize>,
+}
+
+// encodes the image, rejecting it if it exceeds the maximum size. Images with
+// an automatic quality are first retried at the lowest automatic quality.
+fn encode_image(img: &DynamicImage, img_type: ImageType, ops: EncodeOptions) -> Result<Vec<u8>> {
+    let buf = encode_image_unchecked(img, img_type, ops)?;
+    let Some(max_bytes) = ops.max_bytes.filter(|&max_bytes| buf.len() > max_bytes) else {
+        return Ok(buf);
+    };
+
+    if ops.quality == Quality::Auto && !img_type.is_lossless() {
+        if let Some(buf) = encode_within(img, img_type, ops, max_bytes)? {
+            return Ok(buf);
+        }
+    }
+
+    Err(OutputTooLarge(format!(
+        "encoded {img_type} image of {} bytes exceeds the maximum of {max_bytes} bytes",
+        buf.len()
+    ))
+    .into())
+}
+
+// returns the image encoded at the highest quality that fits within the
+// maximum size, or None if it doesn't fit at the minimum automatic quality.
+fn encode_within(
+    img: &DynamicImage,
+    img_type: ImageType,
+    ops: EncodeOptions,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>> {
+    let encode = |quality| {
+        let ops = EncodeOptions {
+            quality: Quality::Value(quality),
+            ..ops
+        };
+        encode_image_unchecked(img, img_type, ops)
+    };
+
+    // Try the minimum first, so that an image that can't fit only costs a
+    // single extra encode.
+    let mut best = encode(AUTO_QUALITY_MIN)?;
+    if best.len() > max_bytes {
+        return Ok(None);
+    }
+    let (mut low, mut high) = (AUTO_QUALITY_MIN + 1, AUTO_QUALITY_MAX);
+    for _ in 0..AUTO_QUALITY_MAX_ATTEMPTS {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let buf = encode(quality)?;
+        if buf.len() <= max_bytes {
+            best = buf;
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(Some(best))
+}
+
+fn encode_image_unchecked(
+    img: &DynamicImage,
+    img_type: ImageType,
+    ops: EncodeOptions,
+) -> Result<Vec<u8>> {
+    let buf = match img_type {
+        // PNG and TIFF are lossless, so the quality doesn't apply.
+        ImageType::Png => encode_png(img, ops.png_level)?,
+        ImageType::Tiff => return encode_tiff(img, ops.tiff_compression, ops.dpi),
+        ImageType::Webp if ops.near_lossless.is_some() => {
+            encode_webp(img, None, ops.near_lossless)?
+        }
+        _ => match ops.quality {
+            Quality::Value(quality) => {
+                encode_with_quality(img, img_type, quality.clamp(1, 100), &ops)
+            }
+            Quality::Auto => encode_auto(img, img_type, &ops),
+            Quality::Lossless => encode_lossless(img, img_type, &ops),
+            Quality::Low | Quality::Medium | Quality::High => {
+                encode_with_quality(img, img_type, img_type.preset_quality(ops.quality), &ops)
+            }
+        }?,
+    };
+
+    // AVIF and WebP have no standard way to store the pixel density.
+    Ok(match (img_type, ops.dpi) {
+        (ImageType::Jpeg, Some(dpi)) => density::set_jpeg(buf, dpi),
+        (ImageType::Png, Some(dpi)) => density::set_png(buf, dpi),
+        _ => buf,
+    })
+}
+
+fn encode_lossless(
+    img: &DynamicImage,
+    img_type: ImageType,
+    ops: &EncodeOptions,
+) -> Result<Vec<u8>> {
     match img_type {
-        ImageType::Avif => encode_avif(img, quality),
+        ImageType::Webp => encode_webp(img, Some(WEBP_LOSSLESS_EFFORT), Some(100)),
+        ImageType::Avif | ImageType::Jpeg => encode_with_quality(img, img_type, 100, ops),
+        ImageType::Png | ImageType::Tiff => {
+            encode_with_quality(img, img_type, img_type.default_quality(), ops)
+        }
+    }
+}
+
+// performs a binary search over the quality range, returning the smallest
+// output that meets the SSIM target within the attempt budget. Falls back to
+// the maximum quality when no attempt meets the target.
+fn encode_auto(img: &DynamicImage, img_type: ImageType, ops: &EncodeOptions) -> Result<Vec<u8>> {
+    let input_type = match img_type {
+        ImageType::Avif => InputImageType::Avif,
+        ImageType::Jpeg => InputImageType::Jpeg,
+        ImageType::Webp => InputImageType::Webp,
+        // Lossless formats ignore the quality.
+        ImageType::Png | ImageType::Tiff => {
+            return encode_with_quality(img, img_type, img_type.default_quality(), ops)
+        }
+    };
+
+    let source = img.to_luma8();
+    let (mut low, mut high) = (AUTO_QUALITY_MIN, AUTO_QUALITY_MAX);
+    let mut best = None;
+    for _ in 0..AUTO_QUALITY_MAX_ATTEMPTS {
+        if low > high {
+            break;
+        }
+        let quality = (low + high) / 2;
+        let buf = encode_with_quality(img, img_type, quality, ops)?;
+        let encoded = decode_image(input_type, &buf, None)?.to_luma8();
+        if ssim(&source, &encoded) >= AUTO_QUALITY_TARGET {
+            best = Some(buf);
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    match best {
+        Some(buf) => Ok(buf),
+        None => encode_with_quality(img, img_type, AUTO_QUALITY_MAX, ops),
+    }
+}
+
+// returns the mean structural similarity of two grayscale images, computed
+// over non-overlapping 8x8 windows.
+fn ssim(a: &image::GrayImage, b: &image::GrayImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = (a.width().min(b.width()), a.height().min(b.height()));
+    let (mut total, mut windows) = (0.0, 0);
+    for y0 in (0..height).step_by(WINDOW as usize) {
+        for x0 in (0..width).step_by(WINDOW as usize) {
+            let (x1, y1) = ((x0 + WINDOW).min(width), (y0 + WINDOW).min(height));
+            let n = ((x1 - x0) * (y1 - y0)) as f64;
+
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            let (mut sq_a, mut sq_b, mut prod) = (0.0, 0.0, 0.0);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let va = a.get_pixel(x, y).0[0] as f64;
+                    let vb = b.get_pixel(x, y).0[0] as f64;
+                    sum_a += va;
+                    sum_b += vb;
+                    sq_a += va * va;
+                    sq_b += vb * vb;
+                    prod += va * vb;
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sq_a / n - mean_a * mean_a;
+            let var_b = sq_b / n - mean_b * mean_b;
+            let cov = prod / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    if windows == 0 {
+        return 1.0;
+    }
+    total / windows as f64
+}
+
+fn encode_with_quality(
+    img: &DynamicImage,
+    img_type: ImageType,
+    quality: u32,
+    ops: &EncodeOptions,
+) -> Result<Vec<u8>> {
+    match img_type {
+        ImageType::Avif => encode_avif(img, quality, ops.avif_threads, ops.avif_depth),
         ImageType::Jpeg => encode_jpeg(img, quality),
-        ImageType::Png => encode_png(img, quality),
-        ImageType::Tiff => encode_tiff(img, quality),
-        ImageType::Webp => encode_webp(img, quality),
+        ImageType::Png => encode_png(img, PngLevel::default()),
+        ImageType::Tiff => encode_tiff(img, TiffCompression::default(), None),
+        ImageType::Webp => encode_webp(img, Some(quality), None),
     }
 }
 
-fn encode_avif(img: &DynamicImage, quality: u32) -> Result<Vec<u8>> {
-    let mut out = Vec::with_capacity(1 << 15);
-    let enc = AvifEncoder::new_with_speed_quality(&mut out, 8, quality as u8);
-    img.write_with_encoder(enc)?;
-    Ok(out)
+// encodes the image using ravif directly, rather than through the image crate,
+// which always encodes with a bit depth of 8.
+// The output is always a still image: animated sources are reduced to their
+// first frame when decoded, and ravif can't encode image sequences.
+fn encode_avif(img: &DynamicImage, quality: u32, threads: usize, depth: u8) -> Result<Vec<u8>> {
+    let quality = quality.clamp(1, 100) as f32;
+    let enc = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_alpha_quality(quality)
+        .with_speed(8)
+        .with_depth(Some(depth))
+        .with_num_threads(Some(threads.max(1)));
+
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let encoded = if img.color().has_alpha() {
+        let pixels = img
+            .to_rgba8()
+            .pixels()
+            .map(|&image::Rgba([r, g, b, a])| RGBA8::new(r, g, b, a))
+            .collect::<Vec<_>>();
+        enc.encode_rgba(Img::new(&pixels[..], width, height))?
+    } else {
+        let pixels = img
+            .to_rgb8()
+            .pixels()
+            .map(|&image::Rgb([r, g, b])| RGB8::new(r, g, b))
+            .collect::<Vec<_>>();
+        enc.encode_rgb(Img::new(&pixels[..], width, height))?
+    };
+    Ok(encoded.avif_file)
 }
 
 fn encode_jpeg(img: &DynamicImage, quality: u32) -> Result<Vec<u8>> {
-    let quality = quality as i32;
-    let out = match img {
-        DynamicImage::ImageRgb8(img) => {
-            compress_jpeg_internal(img, quality, turbojpeg::Subsamp::Sub2x2)
-        }
-        DynamicImage::ImageRgba8(img) => {
-            compress_jpeg_internal(img, quality, turbojpeg::Subsamp::Sub2x2)
-        }
-        _ => return Err(anyhow!("unable to encode image as jpeg")),
-    }?
+    let out = compress_jpeg_internal(
+        &jpeg_pixels(img),
+        quality as i32,
+        turbojpeg::Subsamp::Sub2x2,
+    )?
     .to_owned();
     Ok(out)
 }
 
-fn encode_png(img: &DynamicImage, _quality: u32) -> Result<Vec<u8>> {
+// returns the image as 8-bit RGB, as that's all that JPEG supports. Transparent
+// pixels are composited onto a background, and other variants, like 16-bit
+// images, are scaled down to 8 bits per channel.
+fn jpeg_pixels(img: &DynamicImage) -> Cow<'_, image::RgbImage> {
+    match img {
+        DynamicImage::ImageRgb8(img) => Cow::Borrowed(img),
+        DynamicImage::ImageRgba8(img) => Cow::Owned(flatten_alpha(img, JPEG_BACKGROUND)),
+        img if img.color().has_alpha() => {
+            Cow::Owned(flatten_alpha(&img.to_rgba8(), JPEG_BACKGROUND))
+        }
+        img => Cow::Owned(img.to_rgb8()),
+    }
+}
+
+// The color that transparent pixels are composited onto when encoding to a
+// format without an alpha channel.
+const JPEG_BACKGROUND: image::Rgb<u8> = image::Rgb([255, 255, 255]);
+
+// composites the image onto a solid background, blending partially transparent
+// pixels with the background rather than discarding their alpha.
+fn flatten_alpha(img: &image::RgbaImage, background: image::Rgb<u8>) -> image::RgbImage {
+    image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let image::Rgba([r, g, b, a]) = *img.get_pixel(x, y);
+        let blend = |c: u8, bg: u8| {
+            let (c, bg, a) = (c as u32, bg as u32, a as u32);
+            ((c * a + bg * (255 - a) + 127) / 255) as u8
+        };
+        let [br, bg, bb] = background.0;
+        image::Rgb([blend(r, br), blend(g, bg), blend(b, bb)])
+    })
+}
+
+// PNGs are always written losslessly in the image's own color type, without
+// palette quantization, so there's no quantization step to dither.
+fn encode_png(img: &DynamicImage, level: PngLevel) -> Result<Vec<u8>> {
     let mut out = Vec::with_capacity(1 << 15);
-    img.write_with_encoder(PngEncoder::new(&mut out))?;
+    let enc = PngEncoder::new_with_quality(&mut out, level.compression(), PngFilterType::Adaptive);
+    img.write_with_encoder(enc)?;
     Ok(out)
 }
 
-fn encode_tiff(img: &DynamicImage, _quality: u32) -> Result<Vec<u8>> {
-    let mut out = std::io::Cursor::new(Vec::with_capacity(1 << 15));
-    img.write_with_encoder(TiffEncoder::new(&mut out))?;
+fn encode_tiff(
+    img: &DynamicImage,
+    compression: TiffCompression,
+    dpi: Option<u32>,
+) -> Result<Vec<u8>> {
+    match compression {
+        TiffCompression::None => write_tiff(img, tiff_compression::Uncompressed, dpi),
+        TiffCompression::Lzw => write_tiff(img, tiff_compression::Lzw, dpi),
+        TiffCompression::Deflate => write_tiff(img, tiff_compression::Deflate::default(), dpi),
+        TiffCompression::Packbits => write_tiff(img, tiff_compression::Packbits, dpi),
+    }
+}
+
+fn write_tiff<D: tiff_compression::Compression>(
+    img: &DynamicImage,
+    compression: D,
+    dpi: Option<u32>,
+) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::with_capacity(1 << 15));
+    let mut enc = tiff::encoder::TiffEncoder::new(&mut out)?;
+
+    match img {
+        DynamicImage::ImageLuma8(img) => {
+            write_tiff_image::<colortype::Gray8, _, _>(&mut enc, img, compression, dpi)
+        }
+        DynamicImage::ImageLuma16(img) => {
+            write_tiff_image::<colortype::Gray16, _, _>(&mut enc, img, compression, dpi)
+        }
+        DynamicImage::ImageRgb16(img) => {
+            write_tiff_image::<colortype::RGB16, _, _>(&mut enc, img, compression, dpi)
+        }
+        DynamicImage::ImageRgba16(img) => {
+            write_tiff_image::<colortype::RGBA16, _, _>(&mut enc, img, compression, dpi)
+        }
+        img if img.color().has_alpha() => {
+            write_tiff_image::<colortype::RGBA8, _, _>(&mut enc, &img.to_rgba8(), compression, dpi)
+        }
+        img => {
+            write_tiff_image::<colortype::RGB8, _, _>(&mut enc, &img.to_rgb8(), compression, dpi)
+        }
+    }?;
+
     Ok(out.into_inner())
 }
 
-fn encode_webp(img: &DynamicImage, quality: u32) -> Result<Vec<u8>> {
-    Ok(webp::Encoder::from_image(img)
-        .map_err(|_| anyhow!("unable to encode image as webp"))?
-        .encode_simple(false, quality as f32)
+fn write_tiff_image<C, P, D>(
+    enc: &mut tiff::encoder::TiffEncoder<&mut Cursor<Vec<u8>>>,
+    img: &image::ImageBuffer<P, Vec<C::Inner>>,
+    compression: D,
+    dpi: Option<u32>,
+) -> Result<()>
+where
+    C: colortype::ColorType,
+    P: image::Pixel<Subpixel = C::Inner>,
+    D: tiff_compression::Compression,
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    let mut image =
+        enc.new_image_with_compression::<C, D>(img.width(), img.height(), compression)?;
+    if let Some(dpi) = dpi {
+        image.resolution(
+            tiff::tags::ResolutionUnit::Inch,
+            tiff::encoder::Rational { n: dpi, d: 1 },
+        );
+    }
+    image.write_data(img.as_raw())?;
+    Ok(())
+}
+
+// The quality of lossless WebP images, which controls the compression effort.
+const WEBP_LOSSLESS_EFFORT: u32 = 100;
+
+// encodes the image as WebP. When a near-lossless level is provided, the image
+// is encoded losslessly and the quality is the compression effort, with a
+// level of 100 disabling the near-lossless preprocessing. Without a quality,
+// the encoder's default is used.
+fn encode_webp(
+    img: &DynamicImage,
+    quality: Option<u32>,
+    near_lossless: Option<u32>,
+) -> Result<Vec<u8>> {
+    // Only include an alpha channel when the image has transparent pixels, as
+    // an opaque alpha channel needlessly increases the output size.
+    let converted;
+    let (buf, layout) = match img {
+        DynamicImage::ImageRgb8(img) => (img.as_raw(), webp::PixelLayout::Rgb),
+        DynamicImage::ImageRgba8(img) if !is_opaque(img) => (img.as_raw(), webp::PixelLayout::Rgba),
+        img => {
+            let rgba = img.to_rgba8();
+            if is_opaque(&rgba) {
+                converted = DynamicImage::ImageRgba8(rgba).into_rgb8().into_raw();
+                (&converted, webp::PixelLayout::Rgb)
+            } else {
+                converted = rgba.into_raw();
+                (&converted, webp::PixelLayout::Rgba)
+            }
+        }
+    };
+    let mut config =
+        webp::WebPConfig::new().map_err(|_| anyhow!("unable to create webp config"))?;
+    if let Some(quality) = quality {
+        config.quality = quality as f32;
+    }
+    if let Some(level) = near_lossless {
+        config.lossless = 1;
+        config.near_lossless = level.min(100) as i32;
+    }
+    Ok(webp::Encoder::new(buf, layout, img.width(), img.height())
+        .encode_advanced(&config)
         .map_err(|err| anyhow!(format!("webp: {:?}", err)))?
         .to_owned())
 }
 
-fn metadata_inner(buf: bytes::Bytes, ops: MetadataOptions) -> Result<ImageMetadata> {
+fn is_opaque(img: &image::RgbaImage) -> bool {
+    img.pixels().all(|p| p.0[3] == u8::MAX)
+}
+
+fn metadata_inner(
+    buf: bytes::Bytes,
+    ops: MetadataOptions,
+    config: ProcessorConfig,
+) -> Result<ImageMetadata> {
+    check_source_len(&buf)?;
     let format = type_from_raw(&buf)?;
+    let buf = limit_frames(format, buf, config)?;
+    check_dimensions(format, &buf, config)?;
     let exif_data = exif::ExifData::new(&buf);
-    let img = decode_image(format, &buf)?;
-    let img = auto_orient(&exif_data, img);
-    let (width, height) = img.dimensions();
-    let hash = if ops.thumbhash {
-        Some(get_thumbhash(img))
-    } else {
+    let orientation = if ops.skip_orient {
         None
+    } else {
+        get_orientation(format, &buf, &exif_data)
+    };
+
+    if ops.dimensions_only {
+        // Fall back to decoding for formats without a cheap header probe.
+        let (width, height) = match probe_dimensions(format, &buf) {
+            Some((width, height)) => oriented_dimensions(orientation, width, height),
+            None => auto_orient(orientation, decode_image(format, &buf, None)?).dimensions(),
+        };
+        return Ok(ImageMetadata {
+            format,
+            width,
+            height,
+            size: buf.len() as u64,
+            thumbhash: None,
+            blurhash: None,
+            dominant_color: None,
+            palette: None,
+            data: None,
+            xmp: None,
+            partial: false,
+        });
+    }
+
+    let data = exif_data.map(|exif_data| {
+        let mut data = exif_data.get_data();
+        if ops.strip_gps {
+            data.strip_gps();
+        }
+        data
+    });
+    let xmp = xmp::Data::new(&buf);
+
+    let img = match decode_image(format, &buf, None) {
+        Ok(img) => auto_orient(orientation, img),
+        Err(err) => {
+            // The header may still be readable when the pixel data is corrupt.
+            let dimensions = ops
+                .allow_partial
+                .then(|| probe_dimensions(format, &buf))
+                .flatten();
+            let Some((width, height)) = dimensions else {
+                return Err(err);
+            };
+            let (width, height) = oriented_dimensions(orientation, width, height);
+            return Ok(ImageMetadata {
+                format,
+                width,
+                height,
+                size: buf.len() as u64,
+                thumbhash: None,
+                blurhash: None,
+                dominant_color: None,
+                palette: None,
+                data,
+                xmp,
+                partial: true,
+            });
+        }
     };
+    let (width, height) = img.dimensions();
+
+    let (mut thumbhash, mut blurhash) = (None, None);
+    let (mut dominant_color, mut palette) = (None, None);
+    if ops.thumbhash || ops.blurhash.is_some() || ops.dominant.is_some() {
+        let small = small_rgba(img);
+        if ops.thumbhash {
+            thumbhash = Some(get_thumbhash(&small));
+        }
+        if let Some(components) = ops.blurhash {
+            blurhash = Some(get_blurhash(&small, components)?);
+        }
+        if let Some(dominant) = ops.dominant {
+            let mut colors = get_colors(&small);
+            dominant_color = colors.first().cloned();
+            if let Some(num) = dominant.palette {
+                colors.truncate(num);
+                palette = Some(colors);
+            }
+        }
+    }
 
     Ok(ImageMetadata {
         format,
         width,
         height,
         size: buf.len() as u64,
-        thumbhash: hash,
-        data: exif_data.map(|exif_data| exif_data.get_data()),
+        thumbhash,
+        blurhash,
+        dominant_color,
+        palette,
+        data,
+        xmp,
+        partial: false,
     })
 }
 
-fn get_thumbhash(mut img: DynamicImage) -> String {
+fn small_rgba(mut img: DynamicImage) -> image::RgbaImage {
     let (width, height) = img.dimensions();
     if width > 100 || height > 100 {
         img = img.thumbnail(100, 100);
     }
+    img.to_rgba8()
+}
+
+fn get_thumbhash(img: &image::RgbaImage) -> String {
     let (width, height) = img.dimensions();
-    let rgba = img.to_rgba8().into_raw();
-    let hash = thumbhash::rgba_to_thumb_hash(width as usize, height as usize, &rgba);
+    let hash = thumbhash::rgba_to_thumb_hash(width as usize, height as usize, img.as_raw());
     STANDARD.encode(hash)
 }
 
+/// Renders the base64 encoded thumbhash as a placeholder image in the provided
+/// format.
+pub fn render_thumbhash(hash: &str, img_type: ImageType) -> Result<Vec<u8>> {
+    let invalid = || BadRequest("invalid thumbhash provided".to_owned());
+    // A '+' in an unencoded query string is decoded as a space.
+    let hash = STANDARD
+        .decode(hash.trim().replace(' ', "+"))
+        .map_err(|_| invalid())?;
+    let (width, height, rgba) = thumbhash::thumb_hash_to_rgba(&hash).map_err(|_| invalid())?;
+    let img = image::RgbaImage::from_raw(width as u32, height as u32, rgba).ok_or_else(invalid)?;
+
+    let ops = EncodeOptions {
+        quality: Quality::Value(img_type.default_quality()),
+        dpi: None,
+        png_level: PngLevel::default(),
+        tiff_compression: TiffCompression::default(),
+        avif_threads: 1,
+        avif_depth: 8,
+        near_lossless: None,
+        max_bytes: None,
+    };
+    encode_image(&DynamicImage::from(img), img_type, ops)
+}
+
+fn get_blurhash(img: &image::RgbaImage, components: BlurhashComponents) -> Result<String> {
+    let (width, height) = img.dimensions();
+    blurhash::encode(components.x, components.y, width, height, img.as_raw()).map_err(Into::into)
+}
+
+/// Returns the colors of the image as hex strings, ordered from most to least
+/// common. Mostly transparent pixels are ignored.
+fn get_colors(img: &image::RgbaImage) -> Vec<String> {
+    const NUM_COLORS: usize = 64;
+
+    let quant = NeuQuant::new(10, NUM_COLORS, img.as_raw());
+    let mut counts = [0_usize; NUM_COLORS];
+    for pixel in img.as_raw().chunks_exact(4) {
+        if pixel[3] >= 128 {
+            counts[quant.index_of(pixel)] += 1;
+        }
+    }
+
+    let mut indexes = (0..NUM_COLORS)
+        .filter(|&i| counts[i] > 0)
+        .collect::<Vec<_>>();
+    indexes.sort_by_key(|&i| Reverse(counts[i]));
+
+    let map = quant.color_map_rgb();
+    indexes
+        .into_iter()
+        .map(|i| format!("#{}", hex::encode(&map[i * 3..i * 3 + 3])))
+        .collect()
+}
+
 // Copied from turbojpeg source in order to use our own version of the image crate.
 
-pub fn decompress_jpeg_internal<P>(jpeg_data: &[u8]) -> Result<image::ImageBuffer<P, Vec<u8>>>
+pub fn decompress_jpeg_internal<P>(
+    jpeg_data: &[u8],
+    partial: bool,
+) -> Result<image::ImageBuffer<P, Vec<u8>>>
 where
     P: JpegPixel + 'static,
 {
@@ -443,7 +2176,10 @@ where
     let header = decompressor.read_header(jpeg_data)?;
 
     let pitch = header.width * P::PIXEL_FORMAT.size();
-    let mut image_data = vec![0; pitch * header.height];
+    // Fill with gray when decoding partial images, so that any rows that
+    // can't be decoded are left gray.
+    let fill = if partial { 128 } else { 0 };
+    let mut image_data = vec![fill; pitch * header.height];
     let image = turbojpeg::Image {
         pixels: &mut image_data[..],
         width: header.width,
@@ -451,7 +2187,11 @@ where
         height: header.height,
         format: P::PIXEL_FORMAT,
     };
-    decompressor.decompress(jpeg_data, image)?;
+    if let Err(err) = decompressor.decompress(jpeg_data, image) {
+        if !partial {
+            return Err(err.into());
+        }
+    }
 
     let image_buf =
         image::ImageBuffer::from_raw(header.width as u32, header.height as u32, image_data)
@@ -477,6 +2217,9 @@ where
         format,
     };
 
+    // Restart markers aren't written, as the compressor has no restart interval
+    // setting. Setting one requires unsafe turbojpeg-sys calls on a separate
+    // handle, duplicating this compression path.
     let mut compressor = turbojpeg::Compressor::new()?;
     compressor.set_quality(quality)?;
     compressor.set_subsamp(subsamp)?;
@@ -500,3 +2243,1345 @@ impl JpegPixel for image::Rgba<u8> {
 impl JpegPixel for image::Luma<u8> {
     const PIXEL_FORMAT: turbojpeg::PixelFormat = turbojpeg::PixelFormat::GRAY;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ProcessorConfig {
+        ProcessorConfig {
+            max_pixels: 100_000_000,
+            default_quality: DefaultQuality::default(),
+            avif_threads: 1,
+            max_queue: None,
+            max_output_bytes: None,
+            max_frames: 100,
+            truncate_frames: false,
+            max_blur: MAX_BLUR,
+        }
+    }
+
+    fn process(b: Vec<u8>, ops: ProcessOptions) -> Result<ImageOutput> {
+        let cancelled = AtomicBool::new(false);
+        process_image_inner(
+            b.into(),
+            ops,
+            config(),
+            &cancelled,
+            &mut ServerTiming::new(),
+        )
+    }
+
+    fn png(img: &DynamicImage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    // returns a PNG with an eXIf chunk containing a camera make and a GPS
+    // latitude, stored as a little-endian TIFF structure.
+    fn png_with_gps() -> Vec<u8> {
+        fn entry(tiff: &mut Vec<u8>, tag: u16, field_type: u16, count: u32, value: [u8; 4]) {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&field_type.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(&value);
+        }
+
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0 at offset 8, with its values from offset 38.
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        entry(&mut tiff, 0x010f, 2, 6, 38u32.to_le_bytes());
+        entry(&mut tiff, 0x8825, 4, 1, 44u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"Canon\0");
+        // The GPS IFD at offset 44, with its values from offset 74.
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        entry(&mut tiff, 0x0001, 2, 2, *b"N\0\0\0");
+        entry(&mut tiff, 0x0002, 5, 3, 74u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        for (num, denom) in [(45u32, 1u32), (30, 1), (0, 1)] {
+            tiff.extend_from_slice(&num.to_le_bytes());
+            tiff.extend_from_slice(&denom.to_le_bytes());
+        }
+
+        png_with_exif(&DynamicImage::new_rgb8(4, 4), &tiff)
+    }
+
+    // returns a PNG with an eXIf chunk containing the TIFF structure.
+    fn png_with_exif(img: &DynamicImage, tiff: &[u8]) -> Vec<u8> {
+        let mut chunk = (tiff.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"eXIf");
+        chunk.extend_from_slice(tiff);
+        chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+        // Insert the chunk after the signature and IHDR chunk.
+        let mut buf = png(img);
+        buf.splice(33..33, chunk);
+        buf
+    }
+
+    fn exif_json(buf: &[u8]) -> serde_json::Value {
+        serde_json::to_value(exif::ExifData::new(buf).unwrap().get_data()).unwrap()
+    }
+
+    #[test]
+    fn test_strip_gps_output() {
+        let src = png_with_gps();
+        assert_eq!(exif_json(&src)["latitude"], 45.5);
+
+        // Without the option, the source is returned untouched.
+        let out = process(src.clone(), ProcessOptions::default()).unwrap();
+        assert_eq!(out.buf.as_ref(), src.as_slice());
+
+        let ops = ProcessOptions {
+            strip_gps: true,
+            ..Default::default()
+        }
+        .canonical();
+        let out = process(src.clone(), ops).unwrap();
+        assert_eq!(out.buf.len(), src.len());
+        assert!(!exif::ExifData::new(&out.buf).unwrap().has_gps());
+        let data = exif_json(&out.buf);
+        assert_eq!(data["make"], "Canon");
+        assert!(data.get("latitude").is_none());
+        // No trace of the coordinates remains in the bytes.
+        assert!(memchr::memmem::find(&out.buf, &[45, 0, 0, 0, 1, 0, 0, 0]).is_none());
+        // The chunk's checksum is still valid.
+        image::load_from_memory(&out.buf).unwrap();
+
+        let ops = ProcessOptions {
+            strip_gps: true,
+            passthrough: true,
+            ..Default::default()
+        }
+        .canonical();
+        let out = process(src, ops).unwrap();
+        assert!(!exif::ExifData::new(&out.buf).unwrap().has_gps());
+    }
+
+    #[test]
+    fn test_radius_requires_alpha() {
+        let src = png(&DynamicImage::new_rgb8(4, 4));
+        let ops = ProcessOptions {
+            out_type: Some(ImageType::Jpeg),
+            radius: Some(Radius::Max),
+            ..Default::default()
+        };
+        let err = process(src.clone(), ops).unwrap_err();
+        assert!(err.is::<BadRequest>());
+
+        // The options are rejected before the source is decoded, so even an
+        // undecodable source is a bad request and no stages are timed.
+        let mut timing = ServerTiming::new();
+        let err = process_image_inner(
+            bytes::Bytes::from_static(b"\x89PNG\r\n\x1a\nnot a png"),
+            ops,
+            config(),
+            &AtomicBool::new(false),
+            &mut timing,
+        )
+        .unwrap_err();
+        assert!(err.is::<BadRequest>());
+        assert_eq!(timing.header(), "");
+
+        // Without an output format, one that supports transparency is used.
+        let ops = ProcessOptions {
+            radius: Some(Radius::Max),
+            ..Default::default()
+        };
+        assert_eq!(process(src, ops).unwrap().img_type, ImageType::Png);
+    }
+
+    // returns an image of noise, which doesn't compress well.
+    fn noise(width: u32, height: u32) -> DynamicImage {
+        let mut state = 1u32;
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |_, _| {
+            let mut next = || {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            };
+            image::Rgb([next(), next(), next()])
+        }))
+    }
+
+    fn encode_ops(quality: Quality, max_bytes: Option<usize>) -> EncodeOptions {
+        EncodeOptions {
+            quality,
+            dpi: None,
+            png_level: PngLevel::default(),
+            tiff_compression: TiffCompression::default(),
+            avif_threads: 1,
+            avif_depth: 8,
+            near_lossless: None,
+            max_bytes,
+        }
+    }
+
+    #[test]
+    fn test_max_output_bytes() {
+        let img = noise(64, 64);
+        let size = |quality| {
+            let ops = encode_ops(Quality::Value(quality), None);
+            encode_image(&img, ImageType::Webp, ops).unwrap().len()
+        };
+        let (min, max) = (size(AUTO_QUALITY_MIN), size(AUTO_QUALITY_MAX));
+        assert!(min < max);
+
+        // The quality steps down to the highest that fits.
+        let max_bytes = (min + max) / 2;
+        let ops = encode_ops(Quality::Auto, Some(max_bytes));
+        let buf = encode_image(&img, ImageType::Webp, ops).unwrap();
+        assert!(buf.len() > min && buf.len() <= max_bytes, "{}", buf.len());
+
+        // An image that can't fit is rejected, as are lossless formats.
+        let ops = encode_ops(Quality::Auto, Some(min - 1));
+        let err = encode_image(&img, ImageType::Webp, ops).unwrap_err();
+        assert!(err.is::<OutputTooLarge>());
+        let ops = encode_ops(Quality::Auto, Some(1024));
+        let err = encode_image(&img, ImageType::Png, ops).unwrap_err();
+        assert!(err.is::<OutputTooLarge>());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let canonical = |ops: ProcessOptions| ops.canonical();
+
+        // Lossless formats ignore the quality.
+        let png = ProcessOptions {
+            out_type: Some(ImageType::Png),
+            ..Default::default()
+        };
+        let with_quality = ProcessOptions {
+            quality: Some(Quality::Value(80)),
+            ..png
+        };
+        assert_eq!(canonical(with_quality), canonical(png));
+
+        // Options without an effect, or at their defaults, are dropped.
+        let webp = ProcessOptions {
+            out_type: Some(ImageType::Webp),
+            width: Some(100),
+            ..Default::default()
+        };
+        let ignored = ProcessOptions {
+            height: Some(0),
+            blur_type: Some(BlurType::Box),
+            order: Some(FilterOrder::FilterFirst),
+            png_level: Some(PngLevel::Best),
+            avif_depth: Some(10),
+            ..webp
+        };
+        assert_eq!(canonical(ignored), canonical(webp));
+        let defaults = ProcessOptions {
+            blur: Some(0),
+            ..webp
+        };
+        assert_eq!(canonical(defaults), canonical(webp));
+
+        // Options that apply to the output format are kept.
+        let near_lossless = ProcessOptions {
+            near_lossless: Some(60),
+            ..webp
+        };
+        assert_ne!(canonical(near_lossless), canonical(webp));
+
+        // Without an output format, encoder options may apply to the source.
+        let source = ProcessOptions {
+            png_level: Some(PngLevel::Best),
+            quality: Some(Quality::Value(80)),
+            ..Default::default()
+        };
+        assert_eq!(canonical(source), source);
+        assert_eq!(
+            source.for_output_type(ImageType::Png),
+            ProcessOptions {
+                png_level: Some(PngLevel::Best),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            source.for_output_type(ImageType::Jpeg),
+            ProcessOptions {
+                quality: Some(Quality::Value(80)),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_blur_limit() {
+        let img = noise(200, 100);
+        let blur = |blur, blur_pct| {
+            let ops = ProcessOptions {
+                blur,
+                blur_pct,
+                ..Default::default()
+            };
+            apply_filters(img.clone(), &ops, 5).into_bytes()
+        };
+
+        // Both absolute and relative blurs are limited to the maximum sigma.
+        let max = blur(Some(5), None);
+        assert_eq!(blur(Some(MAX_BLUR), None), max);
+        assert_eq!(blur(None, Some(MAX_BLUR_PCT)), max);
+        assert_ne!(blur(Some(2), None), max);
+
+        assert_eq!(max_blur_pct(MAX_BLUR), MAX_BLUR_PCT);
+        assert_eq!(max_blur_pct(20), 5);
+    }
+
+    #[test]
+    fn test_near_lossless() {
+        // A smooth gradient with some noise, like a photo.
+        let noise = noise(64, 64).into_rgb8();
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            let n = noise.get_pixel(x, y).0[0] / 16;
+            image::Rgb([(x * 3) as u8 + n, (y * 3) as u8 + n, 128 + n])
+        }));
+        let lossless = encode_webp(&img, None, Some(100)).unwrap();
+        let near_lossless = encode_webp(&img, None, Some(40)).unwrap();
+        assert!(near_lossless.len() < lossless.len());
+        let decoded = decode_webp(&near_lossless).unwrap();
+        assert!(ssim(&img.to_luma8(), &decoded.to_luma8()) > 0.95);
+
+        // The quality doesn't apply to near-lossless images.
+        let ops = ProcessOptions {
+            out_type: Some(ImageType::Webp),
+            near_lossless: Some(40),
+            ..Default::default()
+        };
+        let with_quality = ProcessOptions {
+            quality: Some(Quality::Value(80)),
+            ..ops
+        };
+        assert_eq!(with_quality.canonical(), ops.canonical());
+    }
+
+    #[test]
+    fn test_decode_webp_animation() {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let frames = colors.map(|color| {
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb(color)))
+        });
+        let config = webp::WebPConfig::new().unwrap();
+        let mut encoder = webp::AnimEncoder::new(8, 8, &config);
+        for (i, frame) in frames.iter().enumerate() {
+            encoder.add_frame(webp::AnimFrame::from_image(frame, i as i32 * 100).unwrap());
+        }
+        let raw = encoder.encode().to_vec();
+
+        let count = |raw: &[u8]| {
+            RiffChunks(&raw[12..])
+                .filter(|(kind, _)| kind == b"ANMF")
+                .count()
+        };
+        assert_eq!(count(&raw), 3);
+        assert_eq!(count(&truncate_frames(&raw, 1).unwrap()), 1);
+
+        let img = decode_webp(&raw).unwrap().into_rgb8();
+        assert_eq!(img.dimensions(), (8, 8));
+        let pixel = img.get_pixel(4, 4).0;
+        assert!(
+            pixel[0] > 200 && pixel[1] < 50 && pixel[2] < 50,
+            "{pixel:?}"
+        );
+    }
+
+    #[test]
+    fn test_quality_presets() {
+        let builtin = DefaultQuality::default();
+        let resolve = |quality: &DefaultQuality, preset| match quality
+            .resolve(ImageType::Webp, Some(preset))
+        {
+            Quality::Value(v) => v,
+            quality => panic!("unresolved quality: {quality:?}"),
+        };
+        assert_eq!(resolve(&builtin, Quality::Low), 50);
+        assert_eq!(resolve(&builtin, Quality::Medium), 75);
+        assert_eq!(resolve(&builtin, Quality::High), 85);
+
+        // Presets follow the configured default.
+        let configured = DefaultQuality {
+            webp: 60,
+            ..builtin
+        };
+        assert_eq!(resolve(&configured, Quality::Low), 35);
+        assert_eq!(resolve(&configured, Quality::Medium), 60);
+        assert_eq!(resolve(&configured, Quality::High), 70);
+        let configured = DefaultQuality {
+            webp: 95,
+            ..builtin
+        };
+        assert_eq!(resolve(&configured, Quality::High), 100);
+
+        assert_eq!(
+            configured.resolve(ImageType::Webp, None),
+            Quality::Value(95)
+        );
+        assert_eq!(
+            configured.resolve(ImageType::Webp, Some(Quality::Auto)),
+            Quality::Auto
+        );
+    }
+
+    #[test]
+    fn test_timing_stages() {
+        let src = png(&noise(8, 8));
+        let stages = |ops: ProcessOptions| {
+            let mut timing = ServerTiming::new();
+            let cancelled = AtomicBool::new(false);
+            process_image_inner(src.clone().into(), ops, config(), &cancelled, &mut timing)
+                .unwrap();
+            timing.header()
+        };
+
+        let ops = ProcessOptions {
+            width: Some(4),
+            ..Default::default()
+        };
+        let header = stages(ops);
+        assert!(
+            header.contains("resize;") && header.contains("encode;"),
+            "{header}"
+        );
+        assert!(
+            !header.contains("crop;") && !header.contains("mask;"),
+            "{header}"
+        );
+
+        let header = stages(ProcessOptions {
+            crop: Some(Crop::Pixels {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 4,
+            }),
+            radius: Some(Radius::Pixels(1)),
+            ..ops
+        });
+        assert!(
+            header.contains("crop;") && header.contains("mask;"),
+            "{header}"
+        );
+    }
+
+    #[test]
+    fn test_quality_serialization() {
+        let ops = ProcessOptions {
+            width: Some(100),
+            quality: Some(Quality::Value(80)),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&ops).unwrap(),
+            r#"{"width":100,"quality":80}"#
+        );
+        let ops = ProcessOptions {
+            quality: Some(Quality::Auto),
+            ..ops
+        };
+        assert_eq!(
+            serde_json::to_string(&ops).unwrap(),
+            r#"{"width":100,"quality":"auto"}"#
+        );
+    }
+
+    #[test]
+    fn test_flatten_alpha() {
+        let img = image::RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => image::Rgba([255, 0, 0, 255]),
+            1 => image::Rgba([255, 0, 0, 128]),
+            _ => image::Rgba([0, 0, 255, 0]),
+        });
+        let flat = flatten_alpha(&img, JPEG_BACKGROUND);
+        // Opaque pixels are unchanged, semi-transparent ones are blended onto
+        // white, and fully transparent ones become white.
+        assert_eq!(flat.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+        assert_eq!(flat.get_pixel(1, 0), &image::Rgb([255, 127, 127]));
+        assert_eq!(flat.get_pixel(2, 0), &image::Rgb([255, 255, 255]));
+    }
+
+    fn metadata(buf: Vec<u8>, ops: MetadataOptions) -> Result<ImageMetadata> {
+        metadata_inner(buf.into(), ops, config())
+    }
+
+    #[test]
+    fn test_blurhash() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 100, |x, _| {
+            image::Rgb([(x * 255 / 199) as u8, 64, 128])
+        }));
+        let ops = MetadataOptions {
+            blurhash: Some(BlurhashComponents::new(None, None)),
+            ..Default::default()
+        };
+        let hash = metadata(png(&img), ops).unwrap().blurhash.unwrap();
+        // 1 size character, 1 maximum value character, 4 characters for the DC
+        // component, and 2 for each of the 4x3-1 AC components.
+        assert_eq!(hash.len(), 1 + 1 + 4 + 2 * 11);
+        let pixels = blurhash::decode(&hash, 4, 2, 1.0).unwrap();
+        // The horizontal gradient is preserved.
+        assert!(pixels[0] < pixels[12], "{pixels:?}");
+
+        let components = BlurhashComponents::new(Some(20), Some(0));
+        assert_eq!((components.x, components.y), (9, 1));
+
+        let ops = MetadataOptions::default();
+        assert!(metadata(png(&img), ops).unwrap().blurhash.is_none());
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        // A mostly blue image, with a red stripe and a transparent region.
+        let img =
+            DynamicImage::ImageRgba8(image::RgbaImage::from_fn(100, 100, |x, y| match (x, y) {
+                (0..10, _) => image::Rgba([220, 20, 20, 255]),
+                (_, 0..30) => image::Rgba([0, 255, 0, 0]),
+                _ => image::Rgba([20, 40, 230, 255]),
+            }));
+        let ops = MetadataOptions {
+            dominant: Some(DominantOptions::new(Some(2))),
+            ..Default::default()
+        };
+        let res = metadata(png(&img), ops).unwrap();
+
+        let rgb = |hex: &str| {
+            let v = hex::decode(hex.strip_prefix('#').unwrap()).unwrap();
+            [v[0] as i32, v[1] as i32, v[2] as i32]
+        };
+        let close = |hex: &str, want: [i32; 3]| {
+            let got = rgb(hex);
+            got.iter().zip(want).all(|(&a, b)| (a - b).abs() <= 16)
+        };
+        let dominant = res.dominant_color.unwrap();
+        assert!(close(&dominant, [20, 40, 230]), "{dominant}");
+        let palette = res.palette.unwrap();
+        assert_eq!(palette.len(), 2);
+        assert_eq!(palette[0], dominant);
+        assert!(close(&palette[1], [220, 20, 20]), "{palette:?}");
+    }
+
+    #[test]
+    fn test_decompression_bomb() {
+        let src = DynamicImage::new_rgb8(4, 4);
+        let (width, height) = (20_000u32, 20_000u32);
+
+        // A PNG with the IHDR dimensions replaced, and its CRC updated.
+        let mut png_bomb = png(&src);
+        png_bomb[16..20].copy_from_slice(&width.to_be_bytes());
+        png_bomb[20..24].copy_from_slice(&height.to_be_bytes());
+        let crc = crc32fast::hash(&png_bomb[12..29]);
+        png_bomb[29..33].copy_from_slice(&crc.to_be_bytes());
+
+        // A WebP with an extended header declaring the canvas size.
+        let mut webp_bomb = b"RIFF\0\0\0\0WEBPVP8X".to_vec();
+        webp_bomb.extend_from_slice(&10u32.to_le_bytes());
+        webp_bomb.extend_from_slice(&[0; 4]);
+        webp_bomb.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        webp_bomb.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        webp_bomb.extend_from_slice(&[0; 16]);
+
+        // A TIFF with the ImageWidth and ImageLength entries replaced.
+        let mut tiff_bomb = Vec::new();
+        src.write_to(&mut Cursor::new(&mut tiff_bomb), ImageFormat::Tiff)
+            .unwrap();
+        let ifd = u32::from_le_bytes(tiff_bomb[4..8].try_into().unwrap()) as usize;
+        let entries = u16::from_le_bytes([tiff_bomb[ifd], tiff_bomb[ifd + 1]]) as usize;
+        for i in 0..entries {
+            let entry = ifd + 2 + i * 12;
+            let value = match u16::from_le_bytes([tiff_bomb[entry], tiff_bomb[entry + 1]]) {
+                256 => width,
+                257 => height,
+                _ => continue,
+            };
+            // Store the dimension as a LONG value.
+            tiff_bomb[entry + 2..entry + 4].copy_from_slice(&4u16.to_le_bytes());
+            tiff_bomb[entry + 8..entry + 12].copy_from_slice(&value.to_le_bytes());
+        }
+
+        for bomb in [png_bomb, webp_bomb, tiff_bomb] {
+            let err = process(bomb.clone(), ProcessOptions::default()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "image dimensions 20000x20000 exceed the maximum of 100000000 pixels"
+            );
+            let err = metadata(bomb, MetadataOptions::default()).unwrap_err();
+            assert!(err.to_string().starts_with("image dimensions"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_noop_passthrough() {
+        let src = png(&noise(8, 8));
+        let out = process(src.clone(), ProcessOptions::default()).unwrap();
+        assert_eq!(out.buf, src);
+        assert_eq!(out.img_type, ImageType::Png);
+        assert_eq!((out.width, out.height), (8, 8));
+
+        // Any transformation re-encodes the image.
+        let ops = ProcessOptions {
+            width: Some(4),
+            ..Default::default()
+        };
+        assert_ne!(process(src.clone(), ops).unwrap().buf, src);
+
+        // TIFF isn't supported by browsers, so it's always re-encoded.
+        let mut tiff = Vec::new();
+        noise(8, 8)
+            .write_to(&mut Cursor::new(&mut tiff), ImageFormat::Tiff)
+            .unwrap();
+        assert_ne!(
+            process(tiff.clone(), ProcessOptions::default())
+                .unwrap()
+                .buf,
+            tiff
+        );
+    }
+
+    // returns the mean absolute difference between the images' channels.
+    fn mean_diff(a: &DynamicImage, b: &DynamicImage) -> f64 {
+        let (a, b) = (a.to_rgb8(), b.to_rgb8());
+        let total: u64 = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(&a, &b)| a.abs_diff(b) as u64)
+            .sum();
+        total as f64 / a.as_raw().len() as f64
+    }
+
+    fn timed<T>(f: impl FnOnce() -> T) -> (T, std::time::Duration) {
+        let start = std::time::Instant::now();
+        let out = f();
+        (out, start.elapsed())
+    }
+
+    #[test]
+    fn test_large_blur() {
+        // Large blocks and a gradient, which a large blur smooths out.
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(384, 256, |x, y| {
+            let block = if (x / 64 + y / 64) % 2 == 0 { 255 } else { 0 };
+            image::Rgb([block, (x * 255 / 383) as u8, y as u8])
+        }));
+        let sigma = 32.0;
+
+        let (exact, exact_time) = timed(|| img.blur(sigma));
+        let (fast, fast_time) = timed(|| blur_image(&img, sigma));
+        assert_eq!(fast.dimensions(), img.dimensions());
+        let diff = mean_diff(&exact, &fast);
+        assert!(diff < 4.0, "{diff}");
+        assert!(fast_time < exact_time, "{fast_time:?} >= {exact_time:?}");
+
+        // Small blurs are exact.
+        assert_eq!(mean_diff(&img.blur(2.0), &blur_image(&img, 2.0)), 0.0);
+    }
+
+    // returns the standard deviation of the image's channels.
+    fn std_dev(img: &DynamicImage) -> f64 {
+        let buf = img.to_rgb8();
+        let n = buf.as_raw().len() as f64;
+        let mean = buf.as_raw().iter().map(|&v| v as f64).sum::<f64>() / n;
+        let var = buf
+            .as_raw()
+            .iter()
+            .map(|&v| (v as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        var.sqrt()
+    }
+
+    #[test]
+    fn test_blur_type() {
+        let mut state = 0x2545_f491_u32;
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |_, _| {
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            };
+            image::Rgb([next(), next(), next()])
+        }));
+        let noise = std_dev(&img);
+        let sigma = 6.0;
+
+        let (gaussian, gaussian_time) = timed(|| blur_image(&img, sigma));
+        let (boxed, box_time) = timed(|| box_blur(&img, sigma));
+        for blurred in [&gaussian, &boxed] {
+            assert_eq!(blurred.dimensions(), img.dimensions());
+            let smoothed = std_dev(blurred);
+            assert!(smoothed < noise / 4.0, "{smoothed} vs {noise}");
+        }
+        assert!(
+            box_time < gaussian_time,
+            "{box_time:?} >= {gaussian_time:?}"
+        );
+
+        // The blur type is chosen by the options.
+        let ops = ProcessOptions {
+            blur: Some(sigma as u32),
+            blur_type: Some(BlurType::Box),
+            ..Default::default()
+        };
+        assert_eq!(mean_diff(&apply_filters(img, &ops, MAX_BLUR), &boxed), 0.0);
+    }
+
+    #[test]
+    fn test_webp_alpha() {
+        let decode = |buf: Vec<u8>| decode_webp(&buf).unwrap();
+        let mut rgba = image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 128, 255, 255]));
+        rgba.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+        let rgba = DynamicImage::ImageRgba8(rgba);
+
+        for near_lossless in [None, Some(60)] {
+            let out = decode(encode_webp(&rgba, Some(80), near_lossless).unwrap());
+            assert!(out.color().has_alpha());
+            assert_eq!(out.to_rgba8().get_pixel(0, 0).0[3], 0);
+            assert_eq!(out.to_rgba8().get_pixel(4, 4).0[3], 255);
+        }
+
+        // Opaque images don't get an alpha channel, even when they have one.
+        let rgb =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([0, 128, 255])));
+        let opaque = DynamicImage::ImageRgba8(rgb.to_rgba8());
+        for img in [rgb, opaque] {
+            let out = decode(encode_webp(&img, Some(80), None).unwrap());
+            assert!(!out.color().has_alpha());
+        }
+    }
+
+    #[test]
+    fn test_jpeg_pixels_16bit() {
+        // A 16-bit grayscale PNG, with a gradient between black and white.
+        let levels = [0, 0x4000, 0x8080, 0xffff];
+        let gray = image::ImageBuffer::from_fn(4, 1, |x, _| image::Luma([levels[x as usize]]));
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma16(gray)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let img = decode_png(&png).unwrap();
+        assert!(matches!(img, DynamicImage::ImageLuma16(_)));
+        let rgb = jpeg_pixels(&img);
+        let lum: Vec<u8> = rgb.pixels().map(|p| p.0[0]).collect();
+        assert_eq!(lum, [0, 64, 128, 255]);
+        assert!(rgb.pixels().all(|p| p.0[0] == p.0[1] && p.0[1] == p.0[2]));
+
+        // Deep images with alpha are flattened onto the background.
+        let pixel = image::Rgba([0u16, 0, 0, 0x8080]);
+        let img = DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(1, 1, pixel));
+        assert_eq!(jpeg_pixels(&img).get_pixel(0, 0).0, [127, 127, 127]);
+
+        // 8-bit RGB images are used as is.
+        let img = DynamicImage::new_rgb8(1, 1);
+        assert!(matches!(jpeg_pixels(&img), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_cmyk_to_rgb() {
+        // White, black, cyan, and 50% gray, with 0 meaning no ink.
+        let cmyk = [0, 0, 0, 0, 0, 0, 0, 255, 255, 0, 0, 0, 0, 0, 0, 128];
+        let want = [255, 255, 255, 0, 0, 0, 0, 255, 255, 127, 127, 127];
+        assert_eq!(cmyk_to_rgb(&cmyk, false), want);
+        // Adobe JPEGs store the same colors inverted.
+        let inverted: Vec<u8> = cmyk.iter().map(|v| 255 - v).collect();
+        assert_eq!(cmyk_to_rgb(&inverted, true), want);
+
+        // An APP14 "Adobe" segment before the scan marks the values as inverted.
+        let segment = |marker: u8, data: &[u8]| {
+            let mut out = vec![0xFF, marker];
+            out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+            out.extend_from_slice(data);
+            out
+        };
+        let jpeg = |segments: &[Vec<u8>]| {
+            let mut out = vec![0xFF, 0xD8];
+            segments.iter().for_each(|v| out.extend_from_slice(v));
+            out
+        };
+        let jfif = segment(0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        let adobe = segment(0xEE, b"Adobe\0\x64\0\0\0\0\x02");
+        let sos = segment(0xDA, &[0; 10]);
+        assert!(has_adobe_marker(&jpeg(&[
+            jfif.clone(),
+            adobe.clone(),
+            sos.clone()
+        ])));
+        assert!(!has_adobe_marker(&jpeg(&[jfif.clone(), sos.clone()])));
+        assert!(!has_adobe_marker(&jpeg(&[jfif, sos, adobe])));
+    }
+
+    #[test]
+    fn test_auto_quality() {
+        // returns the qualities that the search can try within the budget.
+        fn reachable(low: u32, high: u32, attempts: usize, out: &mut Vec<u32>) {
+            if attempts == 0 || low > high {
+                return;
+            }
+            let quality = (low + high) / 2;
+            out.push(quality);
+            reachable(low, quality - 1, attempts - 1, out);
+            reachable(quality + 1, high, attempts - 1, out);
+        }
+        let mut qualities = vec![AUTO_QUALITY_MAX];
+        reachable(
+            AUTO_QUALITY_MIN,
+            AUTO_QUALITY_MAX,
+            AUTO_QUALITY_MAX_ATTEMPTS,
+            &mut qualities,
+        );
+
+        let gradient = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        for img in [gradient, noise(64, 64)] {
+            let ops = encode_ops(Quality::Auto, None);
+            let buf = encode_auto(&img, ImageType::Webp, &ops).unwrap();
+            let quality = qualities
+                .iter()
+                .find(|&&q| encode_with_quality(&img, ImageType::Webp, q, &ops).unwrap() == buf);
+            assert!(quality.is_some());
+
+            let encoded = decode_webp(&buf).unwrap().to_luma8();
+            let score = ssim(&img.to_luma8(), &encoded);
+            assert!(
+                score >= AUTO_QUALITY_TARGET || quality == Some(&AUTO_QUALITY_MAX),
+                "{score}"
+            );
+        }
+
+        let img = noise(16, 16).to_luma8();
+        assert_eq!(ssim(&img, &img), 1.0);
+        assert!(ssim(&img, &image::GrayImage::new(16, 16)) < 0.1);
+    }
+
+    #[test]
+    fn test_if_larger() {
+        let small = png(&noise(8, 8));
+        let ops = ProcessOptions {
+            width: Some(100),
+            if_larger: true,
+            ..Default::default()
+        };
+        let out = process(small.clone(), ops).unwrap();
+        assert_eq!(out.buf, small);
+        assert_eq!((out.width, out.height), (8, 8));
+
+        // A small source may still be converted, but isn't upscaled.
+        let ops = ProcessOptions {
+            out_type: Some(ImageType::Webp),
+            ..ops
+        };
+        let out = process(small, ops).unwrap();
+        assert_eq!(out.img_type, ImageType::Webp);
+        assert_eq!((out.width, out.height), (8, 8));
+
+        let large = png(&noise(64, 32));
+        let ops = ProcessOptions {
+            width: Some(16),
+            if_larger: true,
+            ..Default::default()
+        };
+        let out = process(large, ops).unwrap();
+        assert_eq!((out.width, out.height), (16, 8));
+    }
+
+    // returns an 8x8 image with red, green, blue, and white quadrants, in
+    // reading order.
+    fn quadrants() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(8, 8, |x, y| {
+            match (x < 4, y < 4) {
+                (true, true) => image::Rgb([255, 0, 0]),
+                (false, true) => image::Rgb([0, 255, 0]),
+                (true, false) => image::Rgb([0, 0, 255]),
+                (false, false) => image::Rgb([255, 255, 255]),
+            }
+        }))
+    }
+
+    #[test]
+    fn test_pixel_crop() {
+        let src = png(&quadrants());
+        let decode = |out: ImageOutput| decode_png(&out.buf).unwrap().to_rgb8();
+
+        let crop = Crop::Pixels {
+            x: 4,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+        let ops = ProcessOptions {
+            crop: Some(crop),
+            ..Default::default()
+        };
+        let img = decode(process(src.clone(), ops).unwrap());
+        assert_eq!(img.dimensions(), (4, 4));
+        assert!(img.pixels().all(|p| p.0 == [0, 255, 0]));
+
+        // The crop is applied before resizing.
+        let ops = ProcessOptions {
+            width: Some(2),
+            ..ops
+        };
+        let img = decode(process(src, ops).unwrap());
+        assert_eq!(img.dimensions(), (2, 2));
+        assert!(img.pixels().all(|p| p.0 == [0, 255, 0]));
+
+        // Rectangles are clamped to the image.
+        let rect = |x, y, width, height| {
+            Crop::Pixels {
+                x,
+                y,
+                width,
+                height,
+            }
+            .rect(8, 8)
+        };
+        assert_eq!(rect(6, 6, 10, 10), (6, 6, 2, 2));
+        assert_eq!(rect(20, 0, 4, 4), (7, 0, 1, 4));
+    }
+
+    #[test]
+    fn test_relative_crop() {
+        let crop = Crop::parse_relative("0.25,0.25,0.5,0.5").unwrap();
+        let ops = ProcessOptions {
+            crop: Some(crop),
+            ..Default::default()
+        };
+        let out = process(png(&quadrants()), ops).unwrap();
+        let img = decode_png(&out.buf).unwrap().to_rgb8();
+        let want = quadrants().crop_imm(2, 2, 4, 4).to_rgb8();
+        assert_eq!(img, want);
+
+        // Rectangles must be within the image.
+        assert!(Crop::parse_relative("0.5,0.5,0.6,0.5").is_none());
+        assert!(Crop::parse_relative("0,0,0,1").is_none());
+        assert!(Crop::parse_relative("-0.1,0,0.5,0.5").is_none());
+        assert!(Crop::parse_relative("0,0,1").is_none());
+        assert!(Crop::parse_relative("0,0,1,1").is_some());
+    }
+
+    #[test]
+    fn test_tiff_dpi() {
+        let buf = encode_tiff(&noise(4, 4), TiffCompression::default(), Some(300)).unwrap();
+        let mut decoder = tiff::decoder::Decoder::new(Cursor::new(&buf)).unwrap();
+        let resolution = |decoder: &mut tiff::decoder::Decoder<_>, tag| {
+            decoder.get_tag(tag).unwrap().into_u32_vec().unwrap()
+        };
+        assert_eq!(
+            resolution(&mut decoder, tiff::tags::Tag::XResolution),
+            [300, 1]
+        );
+        assert_eq!(
+            resolution(&mut decoder, tiff::tags::Tag::YResolution),
+            [300, 1]
+        );
+        assert_eq!(
+            decoder
+                .get_tag_u32(tiff::tags::Tag::ResolutionUnit)
+                .unwrap(),
+            tiff::tags::ResolutionUnit::Inch.to_u16() as u32
+        );
+
+        // The density is only written when requested.
+        let ops = ProcessOptions {
+            out_type: Some(ImageType::Png),
+            dpi: Some(300),
+            ..Default::default()
+        };
+        let out = process(png(&noise(4, 4)), ops).unwrap();
+        assert!(out.buf.windows(4).any(|v| v == b"pHYs"));
+        let out = process(png(&noise(4, 4)), ProcessOptions { dpi: None, ..ops }).unwrap();
+        assert!(!out.buf.windows(4).any(|v| v == b"pHYs"));
+    }
+
+    #[test]
+    fn test_configured_default_quality() {
+        let img = noise(16, 16);
+        let config = ProcessorConfig {
+            default_quality: DefaultQuality {
+                webp: 40,
+                ..DefaultQuality::default()
+            },
+            ..config()
+        };
+        let ops = ProcessOptions {
+            out_type: Some(ImageType::Webp),
+            ..Default::default()
+        };
+        let out = process_image_inner(
+            png(&img).into(),
+            ops,
+            config,
+            &AtomicBool::new(false),
+            &mut ServerTiming::new(),
+        )
+        .unwrap();
+        assert_eq!(out.buf, encode_webp(&img, Some(40), None).unwrap());
+
+        // An explicit quality overrides the default.
+        let ops = ProcessOptions {
+            quality: Some(Quality::Value(90)),
+            ..ops
+        };
+        let out = process_image_inner(
+            png(&img).into(),
+            ops,
+            config,
+            &AtomicBool::new(false),
+            &mut ServerTiming::new(),
+        )
+        .unwrap();
+        assert_eq!(out.buf, encode_webp(&img, Some(90), None).unwrap());
+    }
+
+    #[test]
+    fn test_skip_orient() {
+        // A little-endian TIFF structure with only an orientation of 6, which
+        // rotates the image 90 degrees clockwise.
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&[6, 0, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        let src = png_with_exif(&noise(8, 4), &tiff);
+
+        let dimensions = |skip_orient| {
+            let ops = ProcessOptions {
+                out_type: Some(ImageType::Png),
+                skip_orient,
+                ..Default::default()
+            };
+            let out = process(src.clone(), ops).unwrap();
+            let img = decode_png(&out.buf).unwrap();
+            assert_eq!(img.dimensions(), (out.width, out.height));
+            img.dimensions()
+        };
+        assert_eq!(dimensions(false), (4, 8));
+        assert_eq!(dimensions(true), (8, 4));
+
+        let dimensions = |skip_orient| {
+            let ops = MetadataOptions {
+                skip_orient,
+                ..Default::default()
+            };
+            let metadata = metadata(src.clone(), ops).unwrap();
+            (metadata.width, metadata.height)
+        };
+        assert_eq!(dimensions(false), (4, 8));
+        assert_eq!(dimensions(true), (8, 4));
+    }
+
+    #[test]
+    fn test_png_level() {
+        // A smooth image with some noise, which compresses better with effort.
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 128, |x, y| {
+            image::Rgb([x as u8, y as u8, ((x * y) % 7) as u8])
+        }));
+        let fast = encode_png(&img, PngLevel::Fast).unwrap();
+        let best = encode_png(&img, PngLevel::Best).unwrap();
+        assert!(best.len() <= fast.len(), "{} > {}", best.len(), fast.len());
+        assert_eq!(decode_png(&best).unwrap().to_rgb8(), img.to_rgb8());
+
+        assert_eq!(PngLevel::parse("best"), Some(PngLevel::Best));
+        assert_eq!(PngLevel::parse("0"), Some(PngLevel::Fast));
+        assert_eq!(PngLevel::parse("5"), Some(PngLevel::Default));
+        assert_eq!(PngLevel::parse("9"), Some(PngLevel::Best));
+        assert_eq!(PngLevel::parse("10"), None);
+        assert_eq!(PngLevel::parse("smallest"), None);
+    }
+
+    #[test]
+    fn test_tiff_compression() {
+        // Gray blocks, so that there are runs of bytes for PackBits.
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            let v = ((x / 8 + y / 8) * 16) as u8;
+            image::Rgb([v, v, v])
+        }));
+        let encode = |compression| encode_tiff(&img, compression, None).unwrap();
+        let none = encode(TiffCompression::None);
+        for compression in [
+            TiffCompression::Lzw,
+            TiffCompression::Deflate,
+            TiffCompression::Packbits,
+        ] {
+            let buf = encode(compression);
+            assert!(buf.len() < none.len(), "{compression:?}");
+            assert_eq!(decode_tiff(&buf).unwrap().to_rgb8(), img.to_rgb8());
+        }
+        assert_eq!(decode_tiff(&none).unwrap().to_rgb8(), img.to_rgb8());
+    }
+
+    #[test]
+    fn test_truncated_source() {
+        let src = png(&noise(4, 4));
+        for len in [0, 5] {
+            let err = process(src[..len].to_vec(), ProcessOptions::default()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("empty or truncated source: received {len} bytes")
+            );
+            let err = metadata(src[..len].to_vec(), MetadataOptions::default()).unwrap_err();
+            assert!(err.to_string().starts_with("empty or truncated source"));
+        }
+    }
+
+    #[test]
+    fn test_avif_threads() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |x, y| {
+            image::Rgb([x as u8, y as u8, ((x + y) / 2) as u8])
+        }));
+        let encode = |threads| encode_avif(&img, 60, threads, 8).unwrap();
+
+        // Threads only speed up encoding, so the output (and its pixels) is
+        // the same as a single-threaded encode.
+        let single = encode(1);
+        assert_eq!(&single[4..12], b"ftypavif");
+        assert_eq!(encode(4), single);
+        assert_eq!(encode(4), single);
+    }
+
+    #[tokio::test]
+    async fn test_queue_stats() {
+        let processor = ImageProccessor::new(1, config());
+        assert_eq!(processor.queue_stats().available, 1);
+        let permit = processor.semaphore.acquire().await.unwrap();
+        assert_eq!(processor.queue_stats().available, 0);
+
+        // Images waiting for a worker are counted until they get one.
+        let b = png(&DynamicImage::new_rgb8(4, 4));
+        let mut task = Box::pin(processor.process_image(b.into(), ProcessOptions::default()));
+        let wait = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, &mut task).await.is_err());
+        assert_eq!(processor.queue_stats().waiting, 1);
+
+        drop(permit);
+        task.await.unwrap();
+        assert_eq!(processor.queue_stats().waiting, 0);
+        assert_eq!(processor.queue_stats().available, 1);
+    }
+
+    #[test]
+    fn test_filter_order() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(384, 256, |x, y| {
+            let v = if (x / 64 + y / 64) % 2 == 0 { 255 } else { 0 };
+            image::Rgb([v, v, v])
+        }));
+        let process = |order| {
+            let ops = ProcessOptions {
+                width: Some(24),
+                out_type: Some(ImageType::Png),
+                blur: Some(8),
+                order,
+                ..Default::default()
+            };
+            let out = process(png(&img), ops).unwrap();
+            assert_eq!((out.width, out.height), (24, 16));
+            image::load_from_memory(&out.buf).unwrap()
+        };
+
+        // Blurring at full size keeps the blocks, while blurring after the
+        // downscale flattens them.
+        let resize_first = process(None);
+        assert_eq!(process(Some(FilterOrder::ResizeFirst)), resize_first);
+        let filter_first = process(Some(FilterOrder::FilterFirst));
+        let diff = mean_diff(&resize_first, &filter_first);
+        assert!(diff > 20.0, "{diff}");
+        assert!(std_dev(&filter_first) > 2.0 * std_dev(&resize_first));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled() {
+        // Cancelled requests skip decoding and encoding entirely.
+        let src = png(&noise(8, 8));
+        let mut timing = ServerTiming::new();
+        let cancelled = AtomicBool::new(true);
+        let ops = ProcessOptions {
+            width: Some(4),
+            ..Default::default()
+        };
+        let err = process_image_inner(src.clone().into(), ops, config(), &cancelled, &mut timing)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "image processing was cancelled");
+        let header = timing.header();
+        assert!(!header.contains("decode;") && !header.contains("encode;"));
+
+        // Dropping the future signals the blocking task, and releases the
+        // worker's permit right away.
+        let flag = Arc::new(AtomicBool::new(false));
+        drop(CancelOnDrop(flag.clone()));
+        assert!(check_cancelled(&flag).is_err());
+
+        let processor = ImageProccessor::new(1, config());
+        let ops = ProcessOptions {
+            blur: Some(MAX_BLUR),
+            ..Default::default()
+        };
+        let mut task = Box::pin(processor.process_image(png(&noise(1024, 1024)).into(), ops));
+        let wait = std::time::Duration::from_millis(1);
+        assert!(tokio::time::timeout(wait, &mut task).await.is_err());
+        assert_eq!(processor.queue_stats().available, 0);
+        drop(task);
+        assert_eq!(processor.queue_stats().available, 1);
+    }
+
+    #[test]
+    fn test_avif_depth() {
+        // returns the bits per channel from the AVIF's pixel information box.
+        fn depth(buf: &[u8]) -> u8 {
+            let pos = buf.windows(4).position(|v| v == b"pixi").unwrap();
+            // The box type is followed by its version and flags, and then the
+            // number of channels.
+            buf[pos + 9]
+        }
+
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        }));
+        assert_eq!(depth(&encode_avif(&img, 60, 1, 8).unwrap()), 8);
+
+        let ops = ProcessOptions {
+            out_type: Some(ImageType::Avif),
+            quality: Some(Quality::Value(60)),
+            avif_depth: Some(10),
+            ..Default::default()
+        };
+        let out = process(png(&img), ops).unwrap();
+        assert_eq!(&out.buf[4..12], b"ftypavif");
+        assert_eq!(depth(&out.buf), 10);
+    }
+
+    #[test]
+    fn test_partial_fill() {
+        // Rows of partial CMYK JPEGs that can't be decoded are left the same
+        // gray as RGB ones.
+        for inverted in [false, true] {
+            let fill = cmyk_fill(true, inverted);
+            assert_eq!(cmyk_to_rgb(&fill, inverted), [128; 3], "{inverted}");
+        }
+        assert_eq!(cmyk_fill(false, true), [0; 4]);
+
+        // Other formats ignore the option.
+        let ops = ProcessOptions {
+            width: Some(2),
+            partial: true,
+            ..Default::default()
+        };
+        assert_eq!(process(png(&noise(4, 4)), ops).unwrap().width, 2);
+    }
+
+    #[test]
+    fn test_dimensions_only() {
+        let img = noise(13, 7);
+        let mut transparent = img.to_rgba8();
+        transparent.put_pixel(0, 0, image::Rgba([0; 4]));
+        let transparent = DynamicImage::ImageRgba8(transparent);
+        // ICO files embed RGBA images.
+        let write = |img: &DynamicImage, format| {
+            let mut buf = Vec::new();
+            img.write_to(&mut Cursor::new(&mut buf), format).unwrap();
+            buf
+        };
+        let sources = [
+            ("png", png(&img)),
+            ("tiff", write(&img, ImageFormat::Tiff)),
+            ("ico", write(&transparent, ImageFormat::Ico)),
+            ("webp lossy", encode_webp(&img, Some(80), None).unwrap()),
+            ("webp lossless", encode_webp(&img, None, Some(100)).unwrap()),
+            (
+                "webp alpha",
+                encode_webp(&transparent, Some(80), None).unwrap(),
+            ),
+        ];
+
+        // The header dimensions match those of the decoded image.
+        let dimensions = |buf: &[u8], dimensions_only| {
+            let ops = MetadataOptions {
+                dimensions_only,
+                ..Default::default()
+            };
+            let metadata = metadata(buf.to_vec(), ops).unwrap();
+            (metadata.width, metadata.height)
+        };
+        for (name, buf) in sources {
+            // Only ICO files fall back to decoding.
+            let probed = probe_dimensions(type_from_raw(&buf).unwrap(), &buf);
+            assert_eq!(probed.is_some(), name != "ico", "{name}");
+            assert_eq!(dimensions(&buf, true), (13, 7), "{name}");
+            assert_eq!(dimensions(&buf, false), (13, 7), "{name}");
+        }
+
+        // Including when the image is rotated.
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&[6, 0, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        let rotated = png_with_exif(&img, &tiff);
+        assert_eq!(dimensions(&rotated, true), (7, 13));
+        assert_eq!(dimensions(&rotated, false), (7, 13));
+    }
+
+    #[test]
+    fn test_partial_metadata() {
+        // A PNG with a valid header and EXIF data, but corrupt pixel data.
+        let mut src = png_with_gps();
+        let idat = src.windows(4).position(|v| v == b"IDAT").unwrap();
+        src[idat + 4..idat + 12].fill(0xAB);
+
+        let ops = MetadataOptions {
+            thumbhash: true,
+            ..Default::default()
+        };
+        assert!(metadata(src.clone(), ops).is_err());
+
+        let ops = MetadataOptions {
+            allow_partial: true,
+            ..ops
+        };
+        let partial = metadata(src, ops).unwrap();
+        assert!(partial.partial);
+        assert!(matches!(partial.format, InputImageType::Png));
+        assert_eq!((partial.width, partial.height), (4, 4));
+        assert!(partial.thumbhash.is_none());
+        let data = serde_json::to_value(partial.data.unwrap()).unwrap();
+        assert_eq!(data["make"], "Canon");
+
+        // Images that decode aren't marked as partial.
+        let full = metadata(png(&noise(12, 6)), ops).unwrap();
+        assert!(!full.partial && full.thumbhash.is_some());
+    }
+
+    #[test]
+    fn test_lqip() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(512, 256, |x, y| {
+            let v = if (x / 32 + y / 32) % 2 == 0 { 255 } else { 0 };
+            image::Rgb([v, v, v])
+        }));
+        let out = process(png(&img), ProcessOptions::lqip(false)).unwrap();
+        assert_eq!(out.img_type, ImageType::Webp);
+        assert_eq!((out.width, out.height), (LQIP_SIZE, LQIP_SIZE));
+        assert!(out.buf.len() < 1024, "{}", out.buf.len());
+
+        // The blocks are smoothed out, compared to only resizing.
+        let resized = ProcessOptions {
+            width: Some(LQIP_SIZE),
+            height: Some(LQIP_SIZE),
+            out_type: Some(ImageType::Png),
+            ..Default::default()
+        };
+        let resized = decode_png(&process(png(&img), resized).unwrap().buf).unwrap();
+        let lqip = decode_webp(&out.buf).unwrap();
+        assert_eq!(lqip.dimensions(), resized.dimensions());
+        assert!(std_dev(&lqip) * 4.0 < std_dev(&resized));
+    }
+}
@@ -0,0 +1,203 @@
+/// Returns the orientation of an ISOBMFF-based image, like AVIF, from the
+/// primary item's irot and imir properties. The orientation is returned as the
+/// equivalent EXIF orientation value, or None if the image isn't transformed.
+pub fn orientation(buf: &[u8]) -> Option<u32> {
+    let meta = find_box(buf, b"meta")?;
+    // The meta box is a full box, starting with its version and flags.
+    let meta = meta.get(4..)?;
+    let primary = primary_item(find_box(meta, b"pitm")?)?;
+
+    let iprp = find_box(meta, b"iprp")?;
+    let properties = Boxes(find_box(iprp, b"ipco")?).collect::<Vec<_>>();
+    let indexes = property_indexes(find_box(iprp, b"ipma")?, primary)?;
+
+    let (mut rotation, mut mirror) = (0, None);
+    for index in indexes {
+        // Property indexes are 1-based, with 0 meaning no property.
+        let Some(&(kind, body)) = index.checked_sub(1).and_then(|i| properties.get(i)) else {
+            continue;
+        };
+        match &kind {
+            b"irot" => rotation = body.first()? & 0x03,
+            b"imir" => mirror = Some(body.first()? & 0x01),
+            _ => {}
+        }
+    }
+
+    // The image is rotated anti-clockwise by 90 degree steps, and then
+    // mirrored about either the vertical (0) or horizontal (1) axis.
+    let orientation = match (rotation, mirror) {
+        (0, None) => 1,
+        (0, Some(0)) | (2, Some(1)) => 2,
+        (2, None) => 3,
+        (0, Some(_)) | (2, Some(_)) => 4,
+        (1, Some(1)) | (3, Some(0)) => 5,
+        (3, None) => 6,
+        (1, Some(0)) | (3, Some(_)) => 7,
+        _ => 8,
+    };
+    (orientation != 1).then_some(orientation)
+}
+
+// returns the id of the primary item from the pitm box.
+fn primary_item(pitm: &[u8]) -> Option<u32> {
+    let version = *pitm.first()?;
+    if version == 0 {
+        Some(read_u16(pitm, 4)? as u32)
+    } else {
+        read_u32(pitm, 4)
+    }
+}
+
+// returns the indexes of the properties associated with the item from the
+// ipma box.
+fn property_indexes(ipma: &[u8], item: u32) -> Option<Vec<usize>> {
+    let version = *ipma.first()?;
+    let large_index = read_u32(ipma, 0)? & 0x01 != 0;
+    let count = read_u32(ipma, 4)?;
+
+    let mut pos = 8;
+    for _ in 0..count {
+        let id = if version < 1 {
+            pos += 2;
+            read_u16(ipma, pos - 2)? as u32
+        } else {
+            pos += 4;
+            read_u32(ipma, pos - 4)?
+        };
+        let associations = *ipma.get(pos)? as usize;
+        pos += 1;
+
+        let size = if large_index { 2 } else { 1 };
+        if id == item {
+            let indexes = (0..associations)
+                .map(|i| {
+                    let at = pos + i * size;
+                    // The high bit of each association is the essential flag.
+                    if large_index {
+                        read_u16(ipma, at).map(|v| (v & 0x7fff) as usize)
+                    } else {
+                        ipma.get(at).map(|&v| (v & 0x7f) as usize)
+                    }
+                })
+                .collect();
+            return indexes;
+        }
+        pos += associations * size;
+    }
+    None
+}
+
+fn find_box<'a>(buf: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    Boxes(buf).find(|(k, _)| k == kind).map(|(_, body)| body)
+}
+
+/// An iterator over the type and body of each box in the buffer.
+struct Boxes<'a>(&'a [u8]);
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let buf = self.0;
+        let size = read_u32(buf, 0)? as u64;
+        let kind: [u8; 4] = buf.get(4..8)?.try_into().ok()?;
+        let (header, size) = match size {
+            0 => (8, buf.len() as u64),
+            1 => (16, read_u64(buf, 8)?),
+            _ => (8, size),
+        };
+        let size = usize::try_from(size).ok()?;
+        if size < header || size > buf.len() {
+            return None;
+        }
+        self.0 = &buf[size..];
+        Some((kind, &buf[header..size]))
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = (body.len() as u32 + 8).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    // returns an AVIF-like file with the properties, and the 1-based property
+    // indexes associated with each item.
+    fn avif(primary: u16, properties: &[Vec<u8>], items: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut ipma = vec![0; 4];
+        ipma.extend_from_slice(&(items.len() as u32).to_be_bytes());
+        for &(id, indexes) in items {
+            ipma.extend_from_slice(&id.to_be_bytes());
+            ipma.push(indexes.len() as u8);
+            // Mark each property as essential, which must be ignored.
+            ipma.extend(indexes.iter().map(|&v| v | 0x80));
+        }
+        let iprp = [
+            new_box(b"ipco", &properties.concat()),
+            new_box(b"ipma", &ipma),
+        ];
+
+        let mut pitm = vec![0; 4];
+        pitm.extend_from_slice(&primary.to_be_bytes());
+        let mut meta = vec![0; 4];
+        meta.extend(new_box(b"hdlr", &[0; 24]));
+        meta.extend(new_box(b"pitm", &pitm));
+        meta.extend(new_box(b"iprp", &iprp.concat()));
+
+        let mut out = new_box(b"ftyp", b"avif\0\0\0\0avifmif1");
+        out.extend(new_box(b"meta", &meta));
+        out.extend(new_box(b"mdat", &[0; 16]));
+        out
+    }
+
+    #[test]
+    fn test_orientation() {
+        let ispe = new_box(b"ispe", &[0; 12]);
+        let irot = |rotation: u8| new_box(b"irot", &[rotation]);
+        let imir = |axis: u8| new_box(b"imir", &[axis]);
+
+        let orient = |properties: &[Vec<u8>]| {
+            let indexes: Vec<u8> = (1..=properties.len() as u8).collect();
+            orientation(&avif(1, properties, &[(1, &indexes)]))
+        };
+        assert_eq!(orient(std::slice::from_ref(&ispe)), None);
+        assert_eq!(orient(&[ispe.clone(), irot(0)]), None);
+        assert_eq!(orient(&[ispe.clone(), imir(0)]), Some(2));
+        assert_eq!(orient(&[ispe.clone(), irot(2)]), Some(3));
+        assert_eq!(orient(&[ispe.clone(), imir(1)]), Some(4));
+        assert_eq!(orient(&[irot(1), imir(1)]), Some(5));
+        assert_eq!(orient(&[ispe.clone(), irot(3)]), Some(6));
+        assert_eq!(orient(&[irot(1), imir(0)]), Some(7));
+        assert_eq!(orient(&[ispe.clone(), irot(1)]), Some(8));
+
+        // Only the primary item's properties apply.
+        let properties = [ispe, irot(3)];
+        let buf = avif(2, &properties, &[(1, &[1, 2]), (2, &[1])]);
+        assert_eq!(orientation(&buf), None);
+        let buf = avif(1, &properties, &[(1, &[1, 2]), (2, &[1])]);
+        assert_eq!(orientation(&buf), Some(6));
+
+        // Files without a meta box, or with truncated boxes, have none.
+        assert_eq!(orientation(&new_box(b"ftyp", b"avif")), None);
+        let buf = avif(1, &properties, &[(1, &[1, 2])]);
+        assert_eq!(orientation(&buf[..buf.len() / 2]), None);
+    }
+}
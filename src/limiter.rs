@@ -0,0 +1,195 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use ahash::AHashMap;
+use anyhow::Result;
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+/// A semaphore that tracks the number of tasks waiting for a permit, so that
+/// saturation can be observed and, optionally, load shed.
+pub struct QueuedSemaphore {
+    semaphore: Semaphore,
+    waiting: AtomicUsize,
+    max_waiting: Option<usize>,
+}
+
+/// An error returned when too many tasks are already waiting for a permit.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("server is overloaded")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct QueueStats {
+    /// The number of permits that can be acquired without waiting.
+    pub available: usize,
+    /// The number of tasks waiting for a permit.
+    pub waiting: usize,
+}
+
+impl QueuedSemaphore {
+    pub fn new(permits: usize) -> Self {
+        QueuedSemaphore {
+            semaphore: Semaphore::new(permits),
+            waiting: AtomicUsize::new(0),
+            max_waiting: None,
+        }
+    }
+
+    /// Fails acquires with an Overloaded error, rather than waiting, once the
+    /// provided number of tasks are already waiting.
+    pub fn with_max_waiting(mut self, max_waiting: Option<usize>) -> Self {
+        self.max_waiting = max_waiting;
+        self
+    }
+
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        if let Some(max_waiting) = self.max_waiting {
+            if let Ok(permit) = self.semaphore.try_acquire() {
+                return Ok(permit);
+            }
+            if self.waiting.load(Ordering::Relaxed) >= max_waiting {
+                return Err(Overloaded.into());
+            }
+        }
+
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        // Decrement on drop, so that cancelled waiters are no longer counted.
+        let _waiting = Waiting(&self.waiting);
+        Ok(self.semaphore.acquire().await?)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            available: self.semaphore.available_permits(),
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Limits the number of concurrent operations per host, so that a single slow
+/// host can't consume all available capacity.
+pub struct HostLimiter {
+    limit: usize,
+    hosts: Mutex<AHashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    pub fn new(limit: usize) -> Self {
+        assert!(limit > 0, "per-host limit must be greater than 0");
+        HostLimiter {
+            limit,
+            hosts: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    pub async fn acquire(&self, host: &str) -> Result<HostPermit<'_>> {
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        let permit = semaphore.clone().acquire_owned().await?;
+        Ok(HostPermit {
+            limiter: self,
+            host: host.to_owned(),
+            semaphore,
+            permit: Some(permit),
+        })
+    }
+}
+
+pub struct HostPermit<'a> {
+    limiter: &'a HostLimiter,
+    host: String,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+
+        // Remove the host's entry once nothing else is holding or waiting on
+        // it, which leaves only the references held by the map and this permit.
+        let mut hosts = self.limiter.hosts.lock().unwrap();
+        if Arc::strong_count(&self.semaphore) == 2 {
+            hosts.remove(&self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queued_semaphore() {
+        let semaphore = QueuedSemaphore::new(1).with_max_waiting(Some(1));
+        let permit = semaphore.acquire().await.unwrap();
+
+        // One task may wait for the permit, and the next is shed.
+        let mut waiter = Box::pin(semaphore.acquire());
+        assert!(timeout(Duration::from_millis(10), &mut waiter)
+            .await
+            .is_err());
+        assert_eq!(semaphore.stats().waiting, 1);
+        let err = semaphore.acquire().await.unwrap_err();
+        assert!(err.is::<Overloaded>());
+
+        drop(permit);
+        let permit = waiter.await.unwrap();
+        assert_eq!(semaphore.stats().waiting, 0);
+        assert_eq!(semaphore.stats().available, 0);
+        drop(permit);
+        assert_eq!(semaphore.stats().available, 1);
+    }
+
+    #[tokio::test]
+    async fn test_host_limiter() {
+        let limiter = HostLimiter::new(2);
+        let a1 = limiter.acquire("a.example").await.unwrap();
+        let a2 = limiter.acquire("a.example").await.unwrap();
+
+        // A saturated host doesn't block other hosts.
+        let mut a3 = Box::pin(limiter.acquire("a.example"));
+        assert!(timeout(Duration::from_millis(10), &mut a3).await.is_err());
+        let b1 = limiter.acquire("b.example").await.unwrap();
+
+        drop(a1);
+        let a3 = timeout(Duration::from_secs(1), a3).await.unwrap().unwrap();
+
+        // Entries are removed once no permits are held or waited on.
+        drop(b1);
+        assert_eq!(limiter.hosts.lock().unwrap().len(), 1);
+        drop(a2);
+        drop(a3);
+        assert!(limiter.hosts.lock().unwrap().is_empty());
+    }
+}
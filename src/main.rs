@@ -1,32 +1,102 @@
-use std::time::Duration;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
+use reqwest::Url;
 use serde::Deserialize;
 
 use crate::{
-    cache::{disk::DiskCache, memory::MemoryCache},
-    handler::Handler,
-    image::ImageProccessor,
+    cache::{disk::DiskCache, memory::MemoryCache, source::SourceCache},
+    handler::{Handler, HandlerConfig},
+    image::{DefaultQuality, ImageProccessor, ImageType, ProcessorConfig, MAX_BLUR},
+    resolver::{CachingResolver, PublicResolver},
+    server::{FieldCase, ListenAddr},
     signature::Verifier,
 };
 
 mod cache;
+mod density;
+mod errors;
 mod exif;
 mod handler;
+mod ico;
 mod image;
+mod isobmff;
+mod limiter;
+mod resolver;
 mod server;
 mod signature;
 mod singleflight;
+mod timing;
+mod xmp;
 
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+const DEFAULT_MAX_FRAMES: usize = 1000;
+const DEFAULT_SOURCE_CACHE_SIZE: usize = 64 << 20;
+const DEFAULT_SOURCE_CACHE_TTL_SECS: u64 = 60;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_URL_LEN: usize = 4096;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_DOWNLOAD_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
 #[derive(Deserialize)]
 struct EnvConfig {
+    admin_key: Option<String>,
+    allow_octet_stream: Option<bool>,
+    allowed_output_formats: Option<String>,
+    allowed_schemes: Option<String>,
+    avif_threads: Option<usize>,
+    connect_timeout_secs: Option<u64>,
+    default_output_format: Option<ImageType>,
+    default_quality_avif: Option<u32>,
+    default_quality_jpeg: Option<u32>,
+    default_quality_webp: Option<u32>,
+    deny_private_ips: Option<bool>,
     disk_cache_path: Option<String>,
     disk_cache_size: Option<byte_unit::Byte>,
+    dns_min_ttl_secs: Option<u64>,
+    download_concurrency: Option<usize>,
+    download_timeout_secs: Option<u64>,
+    fallback_output_format: Option<ImageType>,
+    fetch_user_agent: Option<String>,
+    host: Option<String>,
+    host_concurrency: Option<usize>,
+    http_proxy: Option<String>,
+    https_proxy: Option<String>,
+    local_root: Option<PathBuf>,
+    max_blur: Option<u32>,
+    max_frames: Option<usize>,
+    max_output_bytes: Option<byte_unit::Byte>,
+    max_pixels: Option<u64>,
+    max_redirects: Option<usize>,
+    max_queue: Option<usize>,
+    max_url_len: Option<usize>,
     mem_cache_size: Option<byte_unit::Byte>,
+    metadata_case: Option<FieldCase>,
+    no_proxy: Option<String>,
     port: Option<u16>,
+    process_concurrency: Option<usize>,
+    proxy_password: Option<String>,
+    proxy_username: Option<String>,
+    read_timeout_secs: Option<u64>,
+    request_timeout_secs: Option<u64>,
+    security_headers: Option<bool>,
+    shutdown_timeout_secs: Option<u64>,
+    source_cache_size: Option<byte_unit::Byte>,
+    source_cache_ttl_secs: Option<u64>,
+    strict_params: Option<bool>,
+    truncate_frames: Option<bool>,
+    unix_socket: Option<String>,
     verify_keys: Option<String>,
+    verify_keys_file: Option<String>,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -53,36 +123,444 @@ async fn main() {
         .map(MemoryCache::new);
 
     let disk_cache =
-        if let (Some(size), Some(path)) = (config.disk_cache_size, config.disk_cache_path) {
+        if let (Some(size), Some(path)) = (config.disk_cache_size, &config.disk_cache_path) {
             Some(DiskCache::new(path.into(), size.as_u64()).await.unwrap())
         } else {
             None
         };
 
-    let verifier = config.verify_keys.map(|keys| {
-        Verifier::new(keys.split(',').map(ToOwned::to_owned))
-            .expect("invalid verification key provided")
-    });
+    // A source cache size of zero disables it.
+    let source_cache_size = config
+        .source_cache_size
+        .map_or(DEFAULT_SOURCE_CACHE_SIZE, |v| v.as_u64() as usize);
+    let source_cache_ttl = config
+        .source_cache_ttl_secs
+        .unwrap_or(DEFAULT_SOURCE_CACHE_TTL_SECS);
+    let source_cache = (source_cache_size > 0)
+        .then(|| SourceCache::new(source_cache_size, Duration::from_secs(source_cache_ttl)));
 
-    let client = reqwest::Client::builder()
-        .user_agent(server::NAME_VERSION)
-        .timeout(Duration::from_secs(60))
-        .build()
-        .unwrap();
+    let verify_keys_file = config.verify_keys_file.clone().map(PathBuf::from);
+    let verifier = if let Some(path) = &verify_keys_file {
+        Some(Verifier::from_file(path).expect("invalid verification key file provided"))
+    } else {
+        config.verify_keys.as_ref().map(|keys| {
+            Verifier::new(keys.split(',').map(ToOwned::to_owned))
+                .expect("invalid verification key provided")
+        })
+    };
 
-    let workers = std::thread::available_parallelism().unwrap().get();
-    let processor = ImageProccessor::new(workers);
+    let client = or_exit(build_client(&config), "Unable to build the http client");
+    let addr = or_exit(listen_addr(&config), "Invalid listen address");
+    let allowed_output_formats = or_exit(
+        allowed_output_formats(&config),
+        "Invalid allowed output formats",
+    );
+
+    let max_blur = config.max_blur.map_or(MAX_BLUR, |v| v.min(MAX_BLUR));
+    let workers = config
+        .process_concurrency
+        .unwrap_or_else(|| std::thread::available_parallelism().unwrap().get());
+    let processor = ImageProccessor::new(
+        workers,
+        ProcessorConfig {
+            max_pixels: config.max_pixels.unwrap_or(DEFAULT_MAX_PIXELS),
+            default_quality: default_quality(&config),
+            avif_threads: config.avif_threads.unwrap_or(1),
+            max_queue: config.max_queue,
+            max_output_bytes: config.max_output_bytes.map(|v| v.as_u64() as usize),
+            max_frames: config.max_frames.unwrap_or(DEFAULT_MAX_FRAMES),
+            truncate_frames: config.truncate_frames.unwrap_or(false),
+            max_blur,
+        },
+    );
 
     let state = Handler::new(
         mem_cache,
         disk_cache,
+        source_cache,
         client,
         processor,
-        workers * 10,
         verifier,
+        HandlerConfig {
+            download_concurrency: config.download_concurrency.unwrap_or(workers * 10),
+            host_concurrency: config.host_concurrency.filter(|&v| v > 0),
+            request_timeout: Duration::from_secs(
+                config
+                    .request_timeout_secs
+                    .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS),
+            ),
+            allowed_schemes: allowed_schemes(&config),
+            admin_key: config.admin_key,
+            verify_keys_file,
+            allow_octet_stream: config.allow_octet_stream.unwrap_or(false),
+            deny_private_ips: config.deny_private_ips.unwrap_or(false),
+            max_url_len: config.max_url_len.unwrap_or(DEFAULT_MAX_URL_LEN),
+            local_root: config.local_root,
+            shutdown_timeout: config.shutdown_timeout_secs.map(Duration::from_secs),
+            security_headers: config.security_headers.unwrap_or(true),
+            strict_params: config.strict_params.unwrap_or(false),
+            max_blur,
+            default_output_format: config.default_output_format,
+            allowed_output_formats,
+            fallback_output_format: config.fallback_output_format,
+            metadata_case: config.metadata_case.unwrap_or_default(),
+        },
+    );
+
+    or_exit(
+        server::start_server(state, addr).await,
+        "Unable to start the server",
     );
+}
 
+// prints the error and exits, rather than panicking on invalid configuration.
+fn or_exit<T>(res: anyhow::Result<T>, context: &str) -> T {
+    res.unwrap_or_else(|err| {
+        eprintln!("{context}: {err:#}");
+        std::process::exit(1);
+    })
+}
+
+fn listen_addr(config: &EnvConfig) -> anyhow::Result<ListenAddr> {
+    if let Some(path) = &config.unix_socket {
+        return Ok(ListenAddr::Unix(path.into()));
+    }
+    let host = config.host.as_deref().unwrap_or("0.0.0.0");
+    let ip = host
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow::anyhow!("invalid host address: {host}"))?;
     let port = config.port.unwrap_or(8000);
-    let addr = format!("0.0.0.0:{port}");
-    server::start_server(state, &addr).await.unwrap();
+    Ok(ListenAddr::Tcp(SocketAddr::new(ip, port)))
+}
+
+fn default_quality(config: &EnvConfig) -> DefaultQuality {
+    let fallback = DefaultQuality::default();
+    let quality = |v: Option<u32>, fallback: u32| v.map_or(fallback, |v| v.clamp(1, 100));
+    DefaultQuality {
+        avif: quality(config.default_quality_avif, fallback.avif),
+        jpeg: quality(config.default_quality_jpeg, fallback.jpeg),
+        webp: quality(config.default_quality_webp, fallback.webp),
+    }
+}
+
+fn allowed_schemes(config: &EnvConfig) -> Vec<String> {
+    config
+        .allowed_schemes
+        .as_deref()
+        .unwrap_or("http,https")
+        .split(',')
+        .map(|v| v.trim().to_owned())
+        .collect()
+}
+
+fn allowed_output_formats(config: &EnvConfig) -> anyhow::Result<Option<Vec<ImageType>>> {
+    let Some(formats) = config.allowed_output_formats.as_deref() else {
+        return Ok(None);
+    };
+    formats
+        .split(',')
+        .map(|v| {
+            ImageType::parse(v.trim())
+                .ok_or_else(|| anyhow::anyhow!("invalid output format: {}", v.trim()))
+        })
+        .collect::<anyhow::Result<_>>()
+        .map(Some)
+}
+
+/// Builds the client used to fetch source images. Proxies are only used when
+/// explicitly configured, rather than being picked up implicitly by reqwest.
+/// The caching resolver is only used when a minimum TTL is configured, so that
+/// the system resolver is used by default. When private IPs are denied, hosts
+/// that resolve to non-public addresses are refused. Requests sent through a
+/// proxy are resolved by the proxy, so only IP address urls are checked for
+/// them, and the proxy must deny private targets itself.
+fn build_client(config: &EnvConfig) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(
+            config
+                .fetch_user_agent
+                .as_deref()
+                .unwrap_or(server::NAME_VERSION),
+        )
+        .timeout(Duration::from_secs(
+            config
+                .download_timeout_secs
+                .unwrap_or(DEFAULT_DOWNLOAD_TIMEOUT_SECS),
+        ))
+        .connect_timeout(Duration::from_secs(
+            config
+                .connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+        ))
+        .read_timeout(Duration::from_secs(
+            config
+                .read_timeout_secs
+                .unwrap_or(DEFAULT_READ_TIMEOUT_SECS),
+        ))
+        .redirect(redirect_policy(config))
+        .no_proxy();
+
+    let caching = config
+        .dns_min_ttl_secs
+        .map(|min_ttl| CachingResolver::new(Duration::from_secs(min_ttl)))
+        .transpose()?;
+    if config.deny_private_ips.unwrap_or(false) {
+        // Proxies are commonly on a private network, so they're exempt.
+        let proxy_hosts = [&config.http_proxy, &config.https_proxy]
+            .into_iter()
+            .flatten()
+            .filter_map(|proxy| Url::parse(proxy).ok()?.host_str().map(ToOwned::to_owned))
+            .collect();
+        builder = builder.dns_resolver(Arc::new(PublicResolver::new(caching, proxy_hosts)));
+    } else if let Some(resolver) = caching {
+        builder = builder.dns_resolver(Arc::new(resolver));
+    }
+
+    let proxies = [
+        config.http_proxy.as_deref().map(reqwest::Proxy::http),
+        config.https_proxy.as_deref().map(reqwest::Proxy::https),
+    ];
+    for proxy in proxies.into_iter().flatten() {
+        let mut proxy = proxy?.no_proxy(
+            config
+                .no_proxy
+                .as_deref()
+                .and_then(reqwest::NoProxy::from_string),
+        );
+        if let Some(username) = &config.proxy_username {
+            proxy = proxy.basic_auth(username, config.proxy_password.as_deref().unwrap_or(""));
+        }
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build()?)
+}
+
+// returns a redirect policy that limits the number of redirects, and applies
+// the allowed schemes and IP address checks to every redirect rather than only
+// the initial url.
+fn redirect_policy(config: &EnvConfig) -> reqwest::redirect::Policy {
+    let max_redirects = config.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS);
+    let schemes = allowed_schemes(config);
+    let deny_private_ips = config.deny_private_ips.unwrap_or(false);
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            return attempt.error(format!("exceeded the maximum of {max_redirects} redirects"));
+        }
+        let scheme = attempt.url().scheme().to_owned();
+        if !schemes.iter().any(|v| v.eq_ignore_ascii_case(&scheme)) {
+            return attempt.error(format!("url scheme not allowed: {scheme}"));
+        }
+        if deny_private_ips {
+            if let Err(err) = resolver::check_host(attempt.url()) {
+                return attempt.error(err);
+            }
+        }
+        attempt.follow()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // serves a single connection, redirecting every request to `location`.
+    async fn redirect_origin(location: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let res = format!(
+                "HTTP/1.1 302 Found\r\nlocation: {location}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            );
+            stream.write_all(res.as_bytes()).await.unwrap();
+        });
+        addr
+    }
+
+    fn client(vars: &[(&str, &str)]) -> reqwest::Client {
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        let config: EnvConfig = envy::from_iter(vars).unwrap();
+        reqwest::Client::builder()
+            .redirect(redirect_policy(&config))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_private_ip() {
+        // Private addresses are allowed by default.
+        let addr = redirect_origin("http://127.0.0.1:1/").await;
+        let err = client(&[])
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(!err.is_redirect(), "{err}");
+
+        let client = client(&[("DENY_PRIVATE_IPS", "true")]);
+
+        for location in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:1/",
+            "http://[::1]/",
+        ] {
+            let addr = redirect_origin(location).await;
+            let err = client
+                .get(format!("http://{addr}/"))
+                .send()
+                .await
+                .unwrap_err();
+            assert!(err.is_redirect(), "{location}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_listen_addr() {
+        let config = |vars: &[(&str, &str)]| -> EnvConfig {
+            envy::from_iter(vars.iter().map(|(k, v)| (k.to_string(), v.to_string()))).unwrap()
+        };
+
+        let addr = listen_addr(&config(&[("HOST", "::1"), ("PORT", "9000")])).unwrap();
+        assert!(matches!(addr, ListenAddr::Tcp(addr) if addr == "[::1]:9000".parse().unwrap()));
+        let addr = listen_addr(&config(&[("UNIX_SOCKET", "/tmp/imaged.sock")])).unwrap();
+        assert!(
+            matches!(addr, ListenAddr::Unix(path) if path == std::path::Path::new("/tmp/imaged.sock"))
+        );
+
+        let err = listen_addr(&config(&[("HOST", "localhost:8000")]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "invalid host address: localhost:8000");
+    }
+
+    #[test]
+    fn test_allowed_output_formats() {
+        let formats = |v: &str| {
+            let config: EnvConfig =
+                envy::from_iter([("ALLOWED_OUTPUT_FORMATS".to_owned(), v.to_owned())]).unwrap();
+            allowed_output_formats(&config)
+        };
+        assert_eq!(
+            formats("webp, jpg").unwrap(),
+            Some(vec![ImageType::Webp, ImageType::Jpeg])
+        );
+        let err = formats("webp,gif").unwrap_err();
+        assert_eq!(err.to_string(), "invalid output format: gif");
+
+        let config: EnvConfig = envy::from_iter(Vec::<(String, String)>::new()).unwrap();
+        assert_eq!(allowed_output_formats(&config).unwrap(), None);
+    }
+
+    // serves every connection with an empty response, returning the address
+    // and the head of each request received.
+    async fn recording_server() -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.windows(4).any(|v| v == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&head).into_owned());
+                let res = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let (proxy, proxied) = recording_server().await;
+        let (origin, direct) = recording_server().await;
+        let proxy_url = format!("http://{proxy}");
+        let vars = |no_proxy: &str| {
+            [
+                ("HTTP_PROXY", proxy_url.as_str()),
+                ("NO_PROXY", no_proxy),
+                ("PROXY_USERNAME", "user"),
+                ("PROXY_PASSWORD", "pass"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+        };
+
+        let config: EnvConfig = envy::from_iter(vars("other.test")).unwrap();
+        let client = build_client(&config).unwrap();
+        let url = format!("http://{origin}/img.png");
+        client.get(&url).send().await.unwrap();
+        {
+            let proxied = proxied.lock().unwrap();
+            assert_eq!(proxied.len(), 1);
+            assert!(proxied[0].starts_with(&format!("GET {url} HTTP/1.1\r\n")));
+            // "user:pass" in base64.
+            assert!(proxied[0]
+                .to_ascii_lowercase()
+                .contains("proxy-authorization: basic dxnlcjpwyxnz\r\n"));
+        }
+        assert!(direct.lock().unwrap().is_empty());
+
+        // Hosts matching no_proxy are fetched directly.
+        let config: EnvConfig = envy::from_iter(vars("127.0.0.1")).unwrap();
+        let client = build_client(&config).unwrap();
+        client.get(&url).send().await.unwrap();
+        assert_eq!(proxied.lock().unwrap().len(), 1);
+        assert_eq!(direct.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_default_quality() {
+        let config: EnvConfig = envy::from_iter([
+            ("DEFAULT_QUALITY_WEBP".to_owned(), "40".to_owned()),
+            ("DEFAULT_QUALITY_AVIF".to_owned(), "500".to_owned()),
+        ])
+        .unwrap();
+        let quality = default_quality(&config);
+        assert_eq!(quality.webp, 40);
+        assert_eq!(quality.avif, 100);
+        assert_eq!(quality.jpeg, DefaultQuality::default().jpeg);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_agent() {
+        let (origin, requests) = recording_server().await;
+        let url = format!("http://{origin}/img.png");
+        let user_agent = |vars: Vec<(String, String)>| async {
+            let config: EnvConfig = envy::from_iter(vars).unwrap();
+            build_client(&config)
+                .unwrap()
+                .get(&url)
+                .send()
+                .await
+                .unwrap();
+            let head = requests.lock().unwrap().pop().unwrap();
+            let value = head.lines().find_map(|v| v.strip_prefix("user-agent: "));
+            value.unwrap().to_owned()
+        };
+
+        let vars = vec![("FETCH_USER_AGENT".to_owned(), "Custom/1.0".to_owned())];
+        assert_eq!(user_agent(vars).await, "Custom/1.0");
+        assert_eq!(user_agent(Vec::new()).await, server::NAME_VERSION);
+    }
 }
@@ -0,0 +1,282 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use hickory_resolver::{
+    config::{LookupIpStrategy, ResolverConfig, ResolverOpts},
+    system_conf, TokioAsyncResolver,
+};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Url,
+};
+
+const CACHE_SIZE: usize = 1024;
+
+/// A DNS resolver that caches lookups across connections, using the system's
+/// resolver configuration.
+pub struct CachingResolver {
+    resolver: Arc<TokioAsyncResolver>,
+}
+
+impl CachingResolver {
+    /// Creates a new resolver, caching positive lookups for at least `min_ttl`
+    /// regardless of the TTL returned by the name server.
+    pub fn new(min_ttl: Duration) -> Result<Self> {
+        let (config, opts) = system_conf::read_system_conf()?;
+        Ok(Self::with_config(config, opts, min_ttl))
+    }
+
+    fn with_config(config: ResolverConfig, mut opts: ResolverOpts, min_ttl: Duration) -> Self {
+        // Look up both address families so that "happy eyeballs" works.
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        opts.cache_size = CACHE_SIZE;
+        opts.positive_min_ttl = Some(min_ttl);
+        CachingResolver {
+            resolver: Arc::new(TokioAsyncResolver::tokio(config, opts)),
+        }
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.resolver.clone();
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            let addrs: Addrs = Box::new(lookup.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// A DNS resolver that only returns publicly routable addresses, so that
+/// source urls (and the redirects they return) can't reach internal services.
+/// The addresses returned are the ones connected to, so a host can't resolve
+/// to a public address when checked and a private one when connecting.
+pub struct PublicResolver {
+    inner: Option<CachingResolver>,
+    allowed_hosts: Vec<String>,
+}
+
+impl PublicResolver {
+    /// Creates a new resolver that resolves with `inner`, or the system's
+    /// resolver if not provided. Hosts in `allowed_hosts` (e.g. proxies) are
+    /// exempt from filtering.
+    pub fn new(inner: Option<CachingResolver>, allowed_hosts: Vec<String>) -> Self {
+        PublicResolver {
+            inner,
+            allowed_hosts,
+        }
+    }
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self
+            .allowed_hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(name.as_str()));
+        let host = name.as_str().to_owned();
+        let lookup = match &self.inner {
+            Some(inner) => inner.resolve(name),
+            None => Box::pin(async move {
+                let addrs = tokio::net::lookup_host((name.as_str().to_owned(), 0)).await?;
+                let addrs: Addrs = Box::new(addrs);
+                Ok(addrs)
+            }),
+        };
+        Box::pin(async move {
+            let addrs = lookup.await?;
+            if allowed {
+                return Ok(addrs);
+            }
+            let addrs: Vec<_> = addrs.filter(|addr| is_public_ip(addr.ip())).collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("host does not resolve to a public address: {host}"),
+                )
+                .into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Returns an error if the url's host is an IP address that isn't publicly
+/// routable. Hostnames are checked by the [`PublicResolver`] instead.
+pub fn check_host(url: &Url) -> Result<()> {
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let Ok(ip) = host.parse::<IpAddr>() else {
+        return Ok(());
+    };
+    if !is_public_ip(ip) {
+        return Err(anyhow!("address is not public: {ip}"));
+    }
+    Ok(())
+}
+
+/// Returns true if the address is publicly routable.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network", 0.0.0.0/8.
+        || a == 0
+        // shared address space, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments, 192.0.0.0/24.
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking, 198.18.0.0/15.
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(v4);
+    }
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local, fc00::/7.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // link-local, fe80::/10.
+        || (segments[0] & 0xffc0) == 0xfe80
+        // documentation, 2001:db8::/32.
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        // NAT64, 64:ff9b::/96, which can embed any IPv4 address.
+        || (segments[0] == 0x64 && segments[1] == 0xff9b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hickory_resolver::{
+        config::{NameServerConfig, Protocol},
+        proto::{
+            op::{Message, MessageType},
+            rr::{rdata::A, RData, Record, RecordType},
+        },
+    };
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_is_public_ip() {
+        let public = ["1.1.1.1", "8.8.8.8", "2606:4700:4700::1111"];
+        for ip in public {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+
+        let private = [
+            "0.0.0.0",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fc00::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "2001:db8::1",
+        ];
+        for ip in private {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_check_host() {
+        let check = |url: &str| check_host(&Url::parse(url).unwrap());
+        assert!(check("http://example.com/").is_ok());
+        assert!(check("http://1.1.1.1/").is_ok());
+        assert!(check("http://127.0.0.1/").is_err());
+        assert!(check("http://[::1]:8080/").is_err());
+        // The url crate normalizes alternative IPv4 forms.
+        assert!(check("http://0x7f.1/").is_err());
+        assert!(check("http://2130706433/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_public_resolver() {
+        let resolver = PublicResolver::new(None, Vec::new());
+        let name: Name = "localhost".parse().unwrap();
+        assert!(resolver.resolve(name).await.is_err());
+
+        let resolver = PublicResolver::new(None, vec!["localhost".to_owned()]);
+        let name: Name = "localhost".parse().unwrap();
+        let addrs: Vec<_> = resolver.resolve(name).await.unwrap().collect();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn test_caching_resolver() {
+        // A name server that answers A queries with a zero TTL, and returns no
+        // records for anything else.
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf[..n]).unwrap();
+                let mut res = Message::new();
+                res.set_id(req.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_queries(req.queries().to_vec());
+                for query in req.queries() {
+                    if query.query_type() == RecordType::A {
+                        counted.fetch_add(1, Ordering::SeqCst);
+                        let rdata = RData::A(A::new(127, 0, 0, 1));
+                        res.add_answer(Record::from_rdata(query.name().clone(), 0, rdata));
+                    }
+                }
+                socket.send_to(&res.to_vec().unwrap(), peer).await.unwrap();
+            }
+        });
+
+        let mut config = ResolverConfig::new();
+        config.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+        let min_ttl = Duration::from_secs(60);
+        let resolver = CachingResolver::with_config(config, ResolverOpts::default(), min_ttl);
+        for _ in 0..3 {
+            let name: Name = "origin.test.".parse().unwrap();
+            let addrs: Vec<_> = resolver.resolve(name).await.unwrap().collect();
+            assert_eq!(addrs, [SocketAddr::from(([127, 0, 0, 1], 0))]);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+}
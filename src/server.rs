@@ -1,284 +1,2661 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt::Write as _,
+    future::IntoFuture,
+    io::Write,
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
     body::Body,
-    extract::{Query, Request, State},
+    extract::{Json, Query, Request, State},
     http::{response::Builder, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing,
 };
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::Bytes;
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use http_body::Frame;
 use serde::{Deserialize, Serialize};
 use tokio::{
-    net::TcpListener,
+    net::{TcpListener, UnixListener},
     signal::unix::{signal, SignalKind},
+    sync::Notify,
+    task::JoinHandle,
+    time,
 };
 
 use crate::{
-    handler::Handler,
-    image::{ImageOutput, ImageType, InputImageType, ProcessOptions},
+    errors::{self, ErrorCode},
+    handler::{BadRequest, CacheMode, CacheResult, DownloadFailed, Handler, ImageResponse},
+    image::{
+        self, BlurType, BlurhashComponents, Crop, DominantOptions, FilterOrder, ImageType,
+        InputImageType, MetadataOptions, OutputTooLarge, PngLevel, ProcessOptions, Quality, Radius,
+        TiffCompression,
+    },
+    limiter::Overloaded,
 };
 
 pub static NAME_VERSION: &str = concat!("imaged/", env!("CARGO_PKG_VERSION"));
 
 type HandlerState = Arc<Handler>;
 
-pub async fn start_server(handler: Handler, addr: &str) -> Result<()> {
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+pub async fn start_server(handler: Handler, addr: ListenAddr) -> Result<()> {
     let state: HandlerState = Arc::new(handler);
-    let app = axum::Router::new()
+    let mut app = axum::Router::new()
         .route("/", routing::get(get_image))
         .route("/metadata", routing::get(get_image_metadata))
-        .with_state(state);
+        .route("/srcset", routing::get(get_srcset))
+        .route("/placeholder", routing::get(get_placeholder))
+        .route("/warm", routing::post(warm))
+        .route("/version", routing::get(get_version))
+        .route("/stats", routing::get(get_stats))
+        .route("/metrics", routing::get(get_metrics));
+    if state.config.admin_key.is_some() {
+        app = app.route("/sign", routing::post(sign));
+    }
+    tokio::spawn(reload_on_hangup(state.clone()));
+    let shutdown_timeout = state.config.shutdown_timeout;
+    let security_headers = state.config.security_headers;
 
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Starting server on {}", &addr);
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .map_err(Into::into)
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let app = app.with_state(state).layer(middleware::from_fn({
+        let in_flight = in_flight.clone();
+        move |request: Request, next: Next| {
+            let guard = InFlight::new(in_flight.clone());
+            async move {
+                let res = next.run(request).await;
+                drop(guard);
+                res
+            }
+        }
+    }));
+
+    let app = app.layer(middleware::from_fn(errors::localize));
+
+    let app = if security_headers {
+        app.layer(middleware::map_response(set_security_headers))
+    } else {
+        app
+    };
+
+    let shutdown = Arc::new(Notify::new());
+    let signal = {
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.notify_one();
+        }
+    };
+
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|err| anyhow!("unable to listen on {addr}: {err}"))?;
+            println!("Starting server on {addr}");
+            let serve = axum::serve(listener, app).with_graceful_shutdown(signal);
+            drain(serve, &shutdown, shutdown_timeout, &in_flight).await
+        }
+        ListenAddr::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener = UnixListener::bind(&path)
+                .map_err(|err| anyhow!("unable to listen on {}: {err}", path.display()))?;
+            println!("Starting server on unix socket {}", path.display());
+            let serve = axum::serve(listener, app).with_graceful_shutdown(signal);
+            let res = drain(serve, &shutdown, shutdown_timeout, &in_flight).await;
+            _ = std::fs::remove_file(&path);
+            res
+        }
+    }
+}
+
+// runs the server until it has shut down. Once a shutdown signal is received,
+// in-flight requests are given up to the timeout (if any) to complete before
+// the server stops waiting for them.
+async fn drain<F>(
+    serve: F,
+    shutdown: &Notify,
+    timeout: Option<Duration>,
+    in_flight: &AtomicUsize,
+) -> Result<()>
+where
+    F: IntoFuture<Output = std::io::Result<()>>,
+{
+    let serve = serve.into_future();
+    let Some(timeout) = timeout else {
+        return serve.await.map_err(Into::into);
+    };
+
+    tokio::pin!(serve);
+    tokio::select! {
+        res = &mut serve => return res.map_err(Into::into),
+        _ = shutdown.notified() => {}
+    }
+
+    match time::timeout(timeout, serve).await {
+        Ok(res) => res.map_err(Into::into),
+        Err(_) => {
+            eprintln!(
+                "Shutdown timed out, dropping {} in-flight requests",
+                in_flight.load(Ordering::Acquire)
+            );
+            Ok(())
+        }
+    }
+}
+
+// sets headers that prevent browsers from interpreting responses as anything
+// other than their declared content type, as the images are user-controlled.
+async fn set_security_headers(mut res: Response) -> Response {
+    let headers = res.headers_mut();
+    headers.insert(
+        "x-content-type-options",
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        "content-security-policy",
+        HeaderValue::from_static("default-src 'none'"),
+    );
+    headers.insert("referrer-policy", HeaderValue::from_static("no-referrer"));
+    res
+}
+
+/// Tracks a request as in-flight until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::AcqRel);
+        InFlight(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// removes a socket file left behind by a previous run, refusing to remove any
+// other type of file.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path).map_err(Into::into),
+        Ok(_) => Err(anyhow!("{} exists and is not a socket", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
 
 async fn shutdown_signal() {
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
-    let mut sighup = signal(SignalKind::hangup()).unwrap();
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     tokio::select! {
         _ = sigterm.recv() => {}
-        _ = sighup.recv() => {}
         _ = sigint.recv() => {}
     }
 }
 
+async fn reload_on_hangup(state: HandlerState) {
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
+    while sighup.recv().await.is_some() {
+        match state.reload_keys() {
+            Ok(()) => println!("Reloaded verification keys"),
+            Err(err) => eprintln!("Unable to reload verification keys: {err}"),
+        }
+    }
+}
+
+async fn get_version() -> Response {
+    let out = serde_json::to_vec(&Version {
+        version: NAME_VERSION,
+        git_sha: env!("GIT_SHA"),
+        formats: InputImageType::ALL,
+    })
+    .unwrap();
+    new_response()
+        .header("content-type", "application/json")
+        .header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
+}
+
+async fn get_stats(State(state): State<HandlerState>) -> Response {
+    let out = serde_json::to_vec(&state.stats()).unwrap();
+    new_response()
+        .header("content-type", "application/json")
+        .header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
+}
+
+// returns the stats as gauges and counters in the Prometheus text format.
+async fn get_metrics(State(state): State<HandlerState>) -> Response {
+    let stats = state.stats();
+    let gauges = [
+        ("imaged_processing_available", stats.processing.available),
+        ("imaged_processing_waiting", stats.processing.waiting),
+        ("imaged_downloads_available", stats.downloads.available),
+        ("imaged_downloads_waiting", stats.downloads.waiting),
+    ];
+    let mut counters = Vec::new();
+    if let Some(mem) = stats.mem_cache {
+        counters.extend([
+            ("imaged_mem_cache_evictions_total", mem.evictions.evictions),
+            (
+                "imaged_mem_cache_evicted_bytes_total",
+                mem.evictions.evicted_bytes,
+            ),
+            ("imaged_mem_cache_replacements_total", mem.replacements),
+        ]);
+    }
+    if let Some(disk) = stats.disk_cache {
+        counters.extend([
+            ("imaged_disk_cache_evictions_total", disk.evictions),
+            ("imaged_disk_cache_evicted_bytes_total", disk.evicted_bytes),
+        ]);
+    }
+    let mut out = String::with_capacity(1024);
+    for (name, value) in gauges {
+        _ = writeln!(out, "# TYPE {name} gauge\n{name} {value}");
+    }
+    for (name, value) in counters {
+        _ = writeln!(out, "# TYPE {name} counter\n{name} {value}");
+    }
+    if !stats.signature_keys.is_empty() {
+        let name = "imaged_signatures_verified_total";
+        _ = writeln!(out, "# TYPE {name} counter");
+        for key in &stats.signature_keys {
+            let kid = key.id.as_deref().unwrap_or_default();
+            _ = writeln!(out, "{name}{{kid=\"{kid}\"}} {}", key.verified);
+        }
+    }
+    new_response()
+        .header("content-type", "text/plain; version=0.0.4")
+        .header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
+}
+
 async fn get_image(
     headers: HeaderMap,
     Query(query): Query<ImageQuery>,
     State(state): State<HandlerState>,
     request: Request,
 ) -> Response {
+    if let Err(err) = state.check_url_len(&query.url) {
+        return error_response(&err);
+    }
+
     let uri = request.uri();
     if let Err(err) = state.verify(uri.path(), uri.query(), query.s.as_deref()) {
-        return (StatusCode::UNAUTHORIZED, err.to_string()).into_response();
+        return unauthorized_response(&err);
     }
 
-    let result = state
-        .get_image(
-            &query.url,
-            options_from_query(&query, &headers),
-            !query.is_nocache(),
-        )
-        .await;
+    if state.config.strict_params || query.is_strict() {
+        if let Err(err) = query.check_strict(state.config.max_blur) {
+            return error_response(&err);
+        }
+    }
+
+    let options = options_from_query(&state, &query, &headers);
+    let result = async {
+        let image = state.get_image(&query.url, options, query.cache_mode(&headers));
+        if !query.is_multipart_response() {
+            return (image.await, None);
+        }
+        // The source metadata shares the image's download.
+        let metadata_ops = MetadataOptions {
+            skip_orient: options.skip_orient,
+            ..Default::default()
+        };
+        let (image, metadata) = tokio::join!(image, state.get_metadata(&query.url, metadata_ops));
+        (image, Some(metadata))
+    };
+    let Ok((result, metadata)) = time::timeout(state.timeout(query.timeout), result).await else {
+        return timeout_response();
+    };
     let result = match &*result {
         Ok(res) => res,
-        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        Err(err) => return error_response(err),
+    };
+    let metadata = match metadata.transpose() {
+        Ok(metadata) => metadata,
+        Err(err) => return error_response(&err),
     };
 
-    let mut res = new_response().header("content-type", result.output.img_type.mimetype());
+    let mut res = new_response();
 
-    if query.is_timing() {
+    if query
+        .format
+        .as_ref()
+        .is_some_and(ImageFormats::is_negotiated)
+    {
+        res = res.header("vary", "accept");
+    }
+
+    if query.is_timing() && !query.is_timing_trailer() {
         res = res.header("server-timing", &result.timing.header());
     }
 
     if query.is_debug() {
-        let raw = serde_json::to_string(&ImageDebug::new(&result.output)).unwrap();
+        let raw = serde_json::to_string(&ImageDebug::new(result)).unwrap();
         res = res.header("x-image-debug", &raw);
+        let raw = serde_json::to_string(&result.options).unwrap();
+        res = res.header("x-image-options", &raw);
     }
 
     if let Some(cache_result) = result.cache_result {
         res = res.header("x-cache-status", cache_result.as_str());
+        if let CacheResult::Hit = cache_result {
+            let age = result.output.created.elapsed().unwrap_or_default();
+            res = res.header("age", age.as_secs());
+        }
+    }
+
+    let res = res
+        .header("x-image-height", result.output.height)
+        .header("x-image-width", result.output.width);
+
+    if query.is_json_response() {
+        let body = serde_json::to_vec(&ImageJson::new(result)).unwrap();
+        return res
+            .header("content-type", "application/json")
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    if let Some(metadata) = metadata {
+        let boundary = format!("{:032x}", rand::random::<u128>());
+        let json = serde_json::to_vec(&metadata.metadata).unwrap();
+        let body = multipart_body(
+            &boundary,
+            &[
+                ("application/json", &json),
+                (result.mimetype(), &result.output.buf),
+            ],
+        );
+        return res
+            .header(
+                "content-type",
+                format!("multipart/mixed; boundary={boundary}"),
+            )
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    let res = res.header("content-type", result.mimetype());
+    if query.is_timing_trailer() {
+        let mut trailers = HeaderMap::new();
+        let timing = HeaderValue::from_str(&result.timing.header()).unwrap();
+        trailers.insert("server-timing", timing);
+        // The content-length is omitted so that HTTP/1.1 responses are
+        // chunked, which is required to send trailers.
+        return res
+            .header("trailer", "server-timing")
+            .body(Body::new(TrailerBody::new(
+                result.output.buf.clone(),
+                trailers,
+            )))
+            .unwrap();
     }
 
-    res.header("x-image-height", result.output.height)
-        .header("x-image-width", result.output.width)
+    // The output is fully buffered, as it's shared with the caches and other
+    // requests, so the body references it rather than streaming an encoder.
+    res.header("content-length", result.output.buf.len())
         .body(Body::from(result.output.buf.clone()))
         .unwrap()
 }
 
 async fn get_image_metadata(
+    headers: HeaderMap,
     Query(query): Query<MetadataQuery>,
     State(state): State<HandlerState>,
     request: Request,
 ) -> Response {
+    if let Err(err) = state.check_url_len(&query.url) {
+        return error_response(&err);
+    }
+
     let uri = request.uri();
     if let Err(err) = state.verify(uri.path(), uri.query(), query.s.as_deref()) {
-        return (StatusCode::UNAUTHORIZED, err.to_string()).into_response();
+        return unauthorized_response(&err);
     }
 
-    let thumbhash = query.is_thumbhash();
-    let result = match state.get_metadata(&query.url, thumbhash).await {
+    let result = state.get_metadata(&query.url, query.options());
+    let Ok(result) = time::timeout(state.timeout(query.timeout), result).await else {
+        return timeout_response();
+    };
+    let result = match result {
         Ok(res) => res,
-        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        Err(err) => return error_response(&err),
     };
 
-    let mut res = new_response().header("content-type", "application/json");
+    let mut res = new_response()
+        .header("content-type", "application/json")
+        .header("vary", "accept-encoding");
 
     if query.is_timing() {
         res = res.header("server-timing", &result.timing.header());
     }
 
-    let out = if query.is_pretty() {
-        serde_json::to_vec_pretty(&result.metadata)
-    } else {
-        serde_json::to_vec(&result.metadata)
+    let mut out = match query.case.unwrap_or(state.config.metadata_case) {
+        FieldCase::Snake => to_json(&result.metadata, query.is_pretty()),
+        FieldCase::Camel => {
+            let value = serde_json::to_value(&result.metadata).unwrap();
+            to_json(&camel_case_keys(value), query.is_pretty())
+        }
+    };
+
+    if let Some(encoding) = ContentEncoding::negotiate(headers.get("accept-encoding"), out.len()) {
+        out = encoding.encode(&out);
+        res = res.header("content-encoding", encoding.as_str());
     }
-    .unwrap();
-    res.body(Body::from(out)).unwrap()
+
+    res.header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
 }
 
-fn new_response() -> Builder {
-    Response::builder().header("server", NAME_VERSION)
+fn to_json<T: Serialize>(value: &T, pretty: bool) -> Vec<u8> {
+    if pretty {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    }
+    .unwrap()
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct ImageQuery {
-    url: String,
+/// The casing of field names in JSON responses.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldCase {
+    #[default]
+    Snake,
+    Camel,
+}
 
-    #[serde(default)]
-    quality: Option<u32>,
-    #[serde(default)]
-    format: Option<ImageFormats>,
-    #[serde(default)]
-    debug: Option<String>,
-    #[serde(default)]
-    timing: Option<String>,
-    #[serde(default)]
-    height: Option<u32>,
-    #[serde(default)]
-    width: Option<u32>,
-    #[serde(default)]
-    blur: Option<u32>,
-    #[serde(default)]
-    nocache: Option<String>,
-    #[serde(default)]
-    s: Option<String>,
+// recursively renames the snake_case keys of any objects to camelCase.
+fn camel_case_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| (to_camel_case(&key), camel_case_keys(value)))
+            .collect(),
+        serde_json::Value::Array(values) => values.into_iter().map(camel_case_keys).collect(),
+        value => value,
+    }
 }
 
-impl ImageQuery {
-    fn is_debug(&self) -> bool {
-        Self::is_enabled(&self.debug)
+fn to_camel_case(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        if c == '_' {
+            upper = !out.is_empty();
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
     }
+    out
+}
 
-    fn is_timing(&self) -> bool {
-        Self::is_enabled(&self.timing)
+/// Processes the source image at each of the requested widths, returning a
+/// manifest of (signed) image URLs that can be used in a srcset attribute.
+async fn get_srcset(
+    headers: HeaderMap,
+    Query(query): Query<SrcsetQuery>,
+    State(state): State<HandlerState>,
+    request: Request,
+) -> Response {
+    if let Err(err) = state.check_url_len(&query.url) {
+        return error_response(&err);
     }
 
-    fn is_nocache(&self) -> bool {
-        Self::is_enabled(&self.nocache)
+    let uri = request.uri();
+    if let Err(err) = state.verify(uri.path(), uri.query(), query.s.as_deref()) {
+        return unauthorized_response(&err);
     }
 
-    fn is_enabled(v: &Option<String>) -> bool {
-        if let Some(v) = v {
-            v != "false"
-        } else {
-            false
+    let widths = match query.widths() {
+        Ok(widths) => widths,
+        Err(err) => return error_response(&err),
+    };
+
+    let result = srcset_entries(&state, &query, &widths, &headers);
+    let Ok(result) = time::timeout(state.timeout(query.timeout), result).await else {
+        return timeout_response();
+    };
+    let images = match result {
+        Ok(images) => images,
+        Err(err) => return error_response(&err),
+    };
+
+    let srcset = images
+        .iter()
+        .map(|image| format!("{} {}w", image.url, image.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let out = serde_json::to_vec(&SrcsetResponse { srcset, images }).unwrap();
+    new_response()
+        .header("content-type", "application/json")
+        .header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
+}
+
+async fn srcset_entries(
+    state: &Handler,
+    query: &SrcsetQuery,
+    widths: &[u32],
+    headers: &HeaderMap,
+) -> Result<Vec<SrcsetImage>> {
+    let mut images = Vec::with_capacity(widths.len());
+    for width in widths {
+        // Each entry uses the same parameters (and signatures) as the image
+        // endpoint.
+        let mut params = vec![("url", query.url.clone()), ("width", width.to_string())];
+        if let Some(format) = &query.format {
+            params.push(("format", format.clone()));
+        }
+        if let Some(quality) = &query.quality {
+            params.push(("quality", quality.clone()));
+        }
+        let raw_query = serde_urlencoded::to_string(&params)?;
+        let image_query: ImageQuery = serde_urlencoded::from_str(&raw_query)?;
+
+        let options = options_from_query(state, &image_query, headers);
+        let res = state
+            .get_image(&query.url, options, CacheMode::default())
+            .await;
+        let output = match &*res {
+            Ok(res) => &res.output,
+            Err(err) => return Err(anyhow!("{err}")),
+        };
+
+        let mut url = format!("/?{raw_query}");
+        if let Some(signature) = state.signature("/", Some(&raw_query))? {
+            url.push_str("&s=");
+            url.push_str(&signature);
         }
+        images.push(SrcsetImage {
+            url,
+            width: output.width,
+            height: output.height,
+            size: output.buf.len(),
+        });
     }
+    Ok(images)
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
-enum ImageFormats {
-    Format(ImageType),
-    CommaSep(String),
+/// Renders a placeholder image, either from a thumbhash (as returned by the
+/// metadata endpoint) or as a solid image of the source's average color.
+async fn get_placeholder(
+    Query(query): Query<PlaceholderQuery>,
+    State(state): State<HandlerState>,
+    request: Request,
+) -> Response {
+    if let Some(url) = &query.url {
+        if let Err(err) = state.check_url_len(url) {
+            return error_response(&err);
+        }
+    }
+
+    // Both hashes and urls are signed, so that unsigned requests can't be
+    // used to render arbitrary placeholders.
+    let uri = request.uri();
+    if let Err(err) = state.verify(uri.path(), uri.query(), query.s.as_deref()) {
+        return unauthorized_response(&err);
+    }
+
+    let img_type = match state.check_output_format(query.img_type.unwrap_or(ImageType::Png)) {
+        Ok(img_type) => img_type,
+        Err(err) => return error_response(&err),
+    };
+    if let Some(hash) = &query.hash {
+        let out = match image::render_thumbhash(hash, img_type) {
+            Ok(out) => out,
+            Err(err) => return error_response(&err),
+        };
+        return new_response()
+            .header("content-type", img_type.mimetype())
+            .header("content-length", out.len())
+            .body(Body::from(out))
+            .unwrap();
+    }
+
+    let Some(url) = &query.url else {
+        let err = BadRequest("either hash or url must be provided".to_owned());
+        return error_response(&err.into());
+    };
+
+    let size = query.size.unwrap_or(1).clamp(1, MAX_PLACEHOLDER_SIZE);
+    let options = ProcessOptions {
+        width: Some(size),
+        height: Some(size),
+        out_type: Some(img_type),
+        average: true,
+        ..Default::default()
+    };
+    let result = state.get_image(url, options, CacheMode::default());
+    let Ok(result) = time::timeout(state.timeout(query.timeout), result).await else {
+        return timeout_response();
+    };
+    let result = match &*result {
+        Ok(res) => res,
+        Err(err) => return error_response(err),
+    };
+
+    new_response()
+        .header("content-type", result.mimetype())
+        .header("content-length", result.output.buf.len())
+        .body(Body::from(result.output.buf.clone()))
+        .unwrap()
 }
 
-impl ImageFormats {
-    fn format(&self, accept: Option<&HeaderValue>) -> Option<ImageType> {
-        match self {
-            ImageFormats::Format(fmt) => Some(*fmt),
-            ImageFormats::CommaSep(v) => v
-                .split(',')
-                .filter_map(ImageType::parse)
-                .collect::<Vec<ImageType>>()
-                .split_last()
-                .map(|(last, fmts)| {
-                    fmts.iter()
-                        .find(|&v| {
-                            accept
-                                .and_then(|accept| {
-                                    memchr::memmem::find(accept.as_bytes(), v.mimetype().as_bytes())
-                                })
-                                .is_some()
-                        })
-                        .unwrap_or(last)
-                        .to_owned()
-                }),
+/// Processes each of the provided entries, populating the caches without
+/// returning the resulting images.
+async fn warm(State(state): State<HandlerState>, Json(entries): Json<Vec<WarmEntry>>) -> Response {
+    // Entries are processed in order, with a bounded number in flight, so that
+    // a large request can't spawn an unbounded number of tasks.
+    let mut pending = VecDeque::with_capacity(WARM_CONCURRENCY);
+    let mut results = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if pending.len() == WARM_CONCURRENCY {
+            results.push(join_warm_entry(pending.pop_front().unwrap()).await);
         }
+        let url = entry.url.clone();
+        let state = state.clone();
+        let handle = tokio::spawn(async move { warm_entry(&state, &entry).await });
+        pending.push_back((url, handle));
+    }
+    for pending in pending {
+        results.push(join_warm_entry(pending).await);
+    }
+
+    let out = serde_json::to_vec(&results).unwrap();
+    new_response()
+        .header("content-type", "application/json")
+        .header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
+}
+
+// waits for the entry's task, returning an error result if it panicked.
+async fn join_warm_entry((url, handle): (String, JoinHandle<WarmResult>)) -> WarmResult {
+    handle.await.unwrap_or_else(|err| WarmResult {
+        url,
+        ok: false,
+        error: Some(format!("unable to process image: {err}")),
+    })
+}
+
+async fn warm_entry(state: &Handler, entry: &WarmEntry) -> WarmResult {
+    let result = |err: Option<String>| WarmResult {
+        url: entry.url.clone(),
+        ok: err.is_none(),
+        error: err,
+    };
+
+    if let Err(err) = state.check_url_len(&entry.url) {
+        return result(Some(err.to_string()));
+    }
+
+    // Entries use the same parameters (and signatures) as the image endpoint.
+    let mut params = entry
+        .options
+        .iter()
+        .map(|(k, v)| match v {
+            serde_json::Value::String(v) => (k.as_str(), v.to_owned()),
+            v => (k.as_str(), v.to_string()),
+        })
+        .collect::<Vec<_>>();
+    params.push(("url", entry.url.clone()));
+    let raw_query = serde_urlencoded::to_string(&params).unwrap();
+    let query: ImageQuery = match serde_urlencoded::from_str(&raw_query) {
+        Ok(query) => query,
+        Err(err) => return result(Some(err.to_string())),
+    };
+
+    if let Err(err) = state.verify("/", Some(&raw_query), query.s.as_deref()) {
+        return result(Some(err.to_string()));
+    }
+
+    let options = options_from_query(state, &query, &HeaderMap::new());
+    let res = state.get_image(&query.url, options, CacheMode::default());
+    match time::timeout(state.timeout(query.timeout), res).await {
+        Ok(res) => result(res.as_ref().as_ref().err().map(ToString::to_string)),
+        Err(_) => result(Some("request timed out".to_owned())),
+    }
+}
+
+/// Returns the signature for the provided path and query, authenticated with
+/// the admin key as a bearer token.
+async fn sign(
+    headers: HeaderMap,
+    State(state): State<HandlerState>,
+    Json(req): Json<SignRequest>,
+) -> Response {
+    let admin_key = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    let path = req.path.as_deref().unwrap_or("/");
+    let query = req.query.as_deref().filter(|v| !v.is_empty());
+    let signature = match state.sign(admin_key, path, query) {
+        Ok(signature) => signature,
+        Err(err) => return unauthorized_response(&err),
+    };
+
+    let mut url = path.to_owned();
+    url.push('?');
+    if let Some(query) = query {
+        url.push_str(query);
+        url.push('&');
     }
+    url.push_str("s=");
+    url.push_str(&signature);
+
+    let out = serde_json::to_vec(&SignResponse { signature, url }).unwrap();
+    new_response()
+        .header("content-type", "application/json")
+        .header("content-length", out.len())
+        .body(Body::from(out))
+        .unwrap()
 }
 
 #[derive(Deserialize)]
-struct MetadataQuery {
+struct SignRequest {
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    query: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SignResponse {
+    signature: String,
     url: String,
+}
+
+// The maximum width and height of a solid placeholder image.
+const MAX_PLACEHOLDER_SIZE: u32 = 64;
 
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    /// The source image formats that can be decoded.
+    formats: [InputImageType; 6],
+}
+
+#[derive(Deserialize)]
+struct PlaceholderQuery {
     #[serde(default)]
-    pretty: Option<String>,
+    hash: Option<String>,
     #[serde(default)]
-    thumbhash: Option<String>,
+    url: Option<String>,
     #[serde(default)]
-    timing: Option<String>,
+    size: Option<u32>,
+    #[serde(default, rename = "type")]
+    img_type: Option<ImageType>,
+    #[serde(default)]
+    timeout: Option<u64>,
     #[serde(default)]
     s: Option<String>,
 }
 
-impl MetadataQuery {
-    fn is_pretty(&self) -> bool {
-        Self::is_enabled(&self.pretty)
-    }
+// The maximum number of widths that can be requested in a single srcset.
+const MAX_SRCSET_WIDTHS: usize = 16;
 
-    fn is_timing(&self) -> bool {
-        Self::is_enabled(&self.timing)
-    }
+// The maximum number of warm entries processed concurrently.
+const WARM_CONCURRENCY: usize = 8;
 
-    fn is_thumbhash(&self) -> bool {
-        Self::is_enabled(&self.thumbhash)
-    }
+#[derive(Deserialize)]
+struct SrcsetQuery {
+    url: String,
+    widths: String,
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    quality: Option<String>,
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    s: Option<String>,
+}
 
-    fn is_enabled(v: &Option<String>) -> bool {
-        if let Some(v) = v {
-            v != "false"
-        } else {
-            false
+impl SrcsetQuery {
+    fn widths(&self) -> Result<Vec<u32>> {
+        let mut widths = self
+            .widths
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().ok().filter(|&v| v > 0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| BadRequest("widths must be a list of positive integers".to_owned()))?;
+        widths.sort_unstable();
+        widths.dedup();
+        if widths.len() > MAX_SRCSET_WIDTHS {
+            return Err(BadRequest(format!(
+                "a maximum of {MAX_SRCSET_WIDTHS} widths can be requested"
+            ))
+            .into());
         }
+        Ok(widths)
     }
 }
 
 #[derive(Serialize)]
-struct ImageDebug {
+struct SrcsetResponse {
+    srcset: String,
+    images: Vec<SrcsetImage>,
+}
+
+#[derive(Serialize)]
+struct SrcsetImage {
+    url: String,
+    width: u32,
+    height: u32,
+    size: usize,
+}
+
+#[derive(Deserialize)]
+struct WarmEntry {
+    url: String,
+    #[serde(default)]
+    options: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct WarmResult {
+    url: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// returns a multipart body containing each of the provided content types and
+// bodies, separated by the boundary.
+fn multipart_body(boundary: &str, parts: &[(&str, &[u8])]) -> Vec<u8> {
+    let len = parts.iter().map(|(_, body)| body.len() + 128).sum();
+    let mut out = Vec::with_capacity(len);
+    for (content_type, body) in parts {
+        out.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+        out.extend_from_slice(format!("content-type: {content_type}\r\n").as_bytes());
+        out.extend_from_slice(format!("content-length: {}\r\n\r\n", body.len()).as_bytes());
+        out.extend_from_slice(body);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    out
+}
+
+/// A response body that sends its trailers after the data.
+struct TrailerBody {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl TrailerBody {
+    fn new(data: Bytes, trailers: HeaderMap) -> Self {
+        Self {
+            data: Some(data),
+            trailers: Some(trailers),
+        }
+    }
+}
+
+impl http_body::Body for TrailerBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        let frame = match self.data.take() {
+            Some(data) => Some(Frame::data(data)),
+            None => self.trailers.take().map(Frame::trailers),
+        };
+        Poll::Ready(frame.map(Ok))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none() && self.trailers.is_none()
+    }
+}
+
+fn new_response() -> Builder {
+    Response::builder().header("server", NAME_VERSION)
+}
+
+// How long clients should wait before retrying when the server is overloaded.
+const RETRY_AFTER_SECS: u64 = 1;
+
+fn error_response(err: &anyhow::Error) -> Response {
+    if err.is::<Overloaded>() {
+        let headers = [("retry-after", RETRY_AFTER_SECS.to_string())];
+        let res = (StatusCode::SERVICE_UNAVAILABLE, headers, err.to_string());
+        return coded_response(ErrorCode::Overloaded, res);
+    }
+    let is_unsupported = matches!(
+        err.downcast_ref::<::image::ImageError>(),
+        Some(::image::ImageError::Unsupported(_))
+    );
+    let (status, code) = if err.is::<BadRequest>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest)
+    } else if err.is::<OutputTooLarge>() {
+        (StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::OutputTooLarge)
+    } else if is_unsupported {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::UnsupportedFormat,
+        )
+    } else if err.is::<DownloadFailed>() {
+        (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::DownloadFailed)
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal)
+    };
+    coded_response(code, (status, err.to_string()))
+}
+
+fn unauthorized_response(err: &anyhow::Error) -> Response {
+    coded_response(
+        ErrorCode::Unauthorized,
+        (StatusCode::UNAUTHORIZED, err.to_string()),
+    )
+}
+
+fn timeout_response() -> Response {
+    coded_response(
+        ErrorCode::Timeout,
+        (StatusCode::GATEWAY_TIMEOUT, "request timed out"),
+    )
+}
+
+// adds the error code to the response, both as a header for clients and as an
+// extension so that the message can be localized.
+fn coded_response(code: ErrorCode, res: impl IntoResponse) -> Response {
+    let mut res = res.into_response();
+    res.headers_mut()
+        .insert("x-error-code", HeaderValue::from_static(code.as_str()));
+    res.extensions_mut().insert(code);
+    res
+}
+
+#[derive(Clone, Copy)]
+enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    // Bodies smaller than this aren't worth compressing.
+    const MIN_SIZE: usize = 512;
+
+    /// Returns the preferred encoding supported by both the client and server,
+    /// if any.
+    fn negotiate(accept: Option<&HeaderValue>, size: usize) -> Option<Self> {
+        if size < Self::MIN_SIZE {
+            return None;
+        }
+        let accept = accept?.to_str().ok()?;
+
+        let accepted = |name: &str| {
+            accept.split(',').any(|v| {
+                let mut parts = v.split(';').map(str::trim);
+                parts.next().is_some_and(|v| v.eq_ignore_ascii_case(name))
+                    && parts.all(|param| {
+                        param
+                            .strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .is_none_or(|q| q > 0.0)
+                    })
+            })
+        };
+
+        if accepted("gzip") {
+            Some(Self::Gzip)
+        } else if accepted("deflate") {
+            Some(Self::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, buf: &[u8]) -> Vec<u8> {
+        let out = Vec::with_capacity(buf.len() / 2);
+        let level = Compression::default();
+        match self {
+            ContentEncoding::Gzip => {
+                let mut enc = GzEncoder::new(out, level);
+                enc.write_all(buf).unwrap();
+                enc.finish().unwrap()
+            }
+            ContentEncoding::Deflate => {
+                let mut enc = ZlibEncoder::new(out, level);
+                enc.write_all(buf).unwrap();
+                enc.finish().unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ImageQuery {
+    url: String,
+
+    #[serde(default)]
+    quality: Option<String>,
+    #[serde(default)]
+    format: Option<ImageFormats>,
+    #[serde(default)]
+    debug: Option<String>,
+    #[serde(default)]
+    timing: Option<String>,
+    #[serde(default)]
+    height: Option<u32>,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    blur: Option<u32>,
+    #[serde(default)]
+    blur_type: Option<BlurType>,
+    #[serde(default)]
+    blur_pct: Option<u32>,
+    #[serde(default)]
+    order: Option<FilterOrder>,
+    #[serde(default)]
+    radius: Option<String>,
+    #[serde(default)]
+    nocache: Option<String>,
+    #[serde(default)]
+    fresh: Option<String>,
+    #[serde(default)]
+    if_larger: Option<String>,
+    #[serde(default)]
+    cx: Option<u32>,
+    #[serde(default)]
+    cy: Option<u32>,
+    #[serde(default)]
+    cw: Option<u32>,
+    #[serde(default)]
+    ch: Option<u32>,
+    #[serde(default)]
+    crop: Option<String>,
+    #[serde(default)]
+    dpi: Option<u32>,
+    #[serde(default)]
+    png_level: Option<String>,
+    #[serde(default)]
+    tiff_compression: Option<TiffCompression>,
+    #[serde(default)]
+    avif_depth: Option<u8>,
+    #[serde(default)]
+    near_lossless: Option<u32>,
+    #[serde(default)]
+    partial: Option<String>,
+    #[serde(default)]
+    strip_gps: Option<String>,
+    #[serde(default)]
+    lqip: Option<String>,
+    #[serde(default)]
+    auto_orient: Option<String>,
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    response: Option<String>,
+    #[serde(default)]
+    strict: Option<String>,
+    #[serde(default)]
+    s: Option<String>,
+}
+
+impl ImageQuery {
+    // rejects out-of-range parameters that would otherwise be clamped or
+    // ignored.
+    fn check_strict(&self, max_blur: u32) -> Result<()> {
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err(BadRequest("width and height must be greater than 0".to_owned()).into());
+        }
+        if let Some(quality) = &self.quality {
+            let valid = match quality.parse::<u32>() {
+                Ok(v) => (1..=100).contains(&v),
+                Err(_) => Quality::parse(quality).is_some(),
+            };
+            if !valid {
+                return Err(BadRequest(format!("invalid quality: {quality}")).into());
+            }
+        }
+        if self.blur.is_some_and(|blur| blur > max_blur) {
+            return Err(BadRequest(format!("blur must not exceed {max_blur}")).into());
+        }
+        let max_blur_pct = image::max_blur_pct(max_blur);
+        if self.blur_pct.is_some_and(|pct| pct > max_blur_pct) {
+            return Err(BadRequest(format!("blur_pct must not exceed {max_blur_pct}")).into());
+        }
+        if self
+            .avif_depth
+            .is_some_and(|depth| !is_valid_avif_depth(depth))
+        {
+            return Err(BadRequest("avif_depth must be 8 or 10".to_owned()).into());
+        }
+        if self.near_lossless.is_some_and(|level| level > 100) {
+            return Err(BadRequest("near_lossless must not exceed 100".to_owned()).into());
+        }
+        Ok(())
+    }
+
+    fn is_strict(&self) -> bool {
+        Self::is_enabled(&self.strict)
+    }
+
+    fn is_json_response(&self) -> bool {
+        self.response.as_deref() == Some("json")
+    }
+
+    fn is_multipart_response(&self) -> bool {
+        self.response.as_deref() == Some("multipart")
+    }
+
+    fn is_debug(&self) -> bool {
+        Self::is_enabled(&self.debug)
+    }
+
+    fn is_timing(&self) -> bool {
+        Self::is_enabled(&self.timing)
+    }
+
+    // returns true if timing should be sent as a trailer after the body,
+    // rather than as a header.
+    fn is_timing_trailer(&self) -> bool {
+        self.timing.as_deref() == Some("trailer")
+    }
+
+    fn is_nocache(&self) -> bool {
+        Self::is_enabled(&self.nocache)
+    }
+
+    fn is_fresh(&self) -> bool {
+        Self::is_enabled(&self.fresh)
+    }
+
+    // returns how the request uses the caches. The no-cache directive (or the
+    // fresh param) forces the image to be processed again, and no-store (or
+    // the nocache param) also prevents the result from being cached.
+    fn cache_mode(&self, headers: &HeaderMap) -> CacheMode {
+        let has_directive = |name: &str| {
+            headers
+                .get_all("cache-control")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case(name))
+        };
+        let no_store = has_directive("no-store");
+        CacheMode {
+            read: !(self.is_fresh() || no_store || has_directive("no-cache")),
+            write: !(self.is_nocache() || no_store),
+        }
+    }
+
+    fn is_if_larger(&self) -> bool {
+        Self::is_enabled(&self.if_larger)
+    }
+
+    fn is_partial(&self) -> bool {
+        Self::is_enabled(&self.partial)
+    }
+
+    fn is_strip_gps(&self) -> bool {
+        Self::is_enabled(&self.strip_gps)
+    }
+
+    fn is_lqip(&self) -> bool {
+        Self::is_enabled(&self.lqip)
+    }
+
+    // returns the crop rectangle, preferring a pixel crop (which requires a
+    // non-zero width and height) over a relative one.
+    fn crop(&self) -> Option<Crop> {
+        if let (Some(width), Some(height)) =
+            (self.cw.filter(|&v| v > 0), self.ch.filter(|&v| v > 0))
+        {
+            return Some(Crop::Pixels {
+                x: self.cx.unwrap_or(0),
+                y: self.cy.unwrap_or(0),
+                width,
+                height,
+            });
+        }
+        self.crop.as_deref().and_then(Crop::parse_relative)
+    }
+
+    fn is_enabled(v: &Option<String>) -> bool {
+        if let Some(v) = v {
+            v != "false"
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum ImageFormats {
+    Format(ImageType),
+    CommaSep(String),
+}
+
+impl ImageFormats {
+    // returns true if the output format may depend on the accept header.
+    fn is_negotiated(&self) -> bool {
+        matches!(self, ImageFormats::CommaSep(v) if v != "orig")
+    }
+
+    // returns true if the source image's format was explicitly requested.
+    fn is_orig(&self) -> bool {
+        matches!(self, ImageFormats::CommaSep(v) if v == "orig")
+    }
+
+    // returns the output format, only negotiating between allowed formats. An
+    // explicit format is returned even if it isn't allowed, so that it can be
+    // rejected or replaced.
+    fn format(
+        &self,
+        accept: Option<&HeaderValue>,
+        allowed: impl Fn(ImageType) -> bool,
+    ) -> Option<ImageType> {
+        match self {
+            ImageFormats::Format(fmt) => Some(*fmt),
+            // Picks the format the client most prefers, favoring smaller
+            // formats for equal preferences, and falling back to the default
+            // output format.
+            ImageFormats::CommaSep(v) if v == "auto" => Self::preferred(
+                accept,
+                [ImageType::Avif, ImageType::Webp, ImageType::Jpeg]
+                    .into_iter()
+                    .filter(|&fmt| allowed(fmt)),
+            ),
+            ImageFormats::CommaSep(v) => v
+                .split(',')
+                .filter_map(ImageType::parse)
+                .filter(|&fmt| allowed(fmt))
+                .collect::<Vec<ImageType>>()
+                .split_last()
+                .map(|(&last, fmts)| Self::preferred(accept, fmts.iter().copied()).unwrap_or(last)),
+        }
+    }
+
+    // returns the format with the highest quality value in the accept header,
+    // with ties going to the earliest format.
+    fn preferred(
+        accept: Option<&HeaderValue>,
+        fmts: impl Iterator<Item = ImageType>,
+    ) -> Option<ImageType> {
+        let accept = accept?.to_str().ok()?;
+        let mut best: Option<(ImageType, f32)> = None;
+        for fmt in fmts {
+            let Some(weight) = Self::weight(accept, fmt) else {
+                continue;
+            };
+            if best.is_none_or(|(_, best)| weight > best) {
+                best = Some((fmt, weight));
+            }
+        }
+        best.map(|(fmt, _)| fmt)
+    }
+
+    // returns the quality value of the format's mimetype in the accept header,
+    // or None if it isn't explicitly accepted. Wildcards are ignored, as
+    // clients send them regardless of the image formats they support.
+    fn weight(accept: &str, fmt: ImageType) -> Option<f32> {
+        accept.split(',').find_map(|v| {
+            let mut parts = v.split(';').map(str::trim);
+            if !parts
+                .next()
+                .is_some_and(|v| v.eq_ignore_ascii_case(fmt.mimetype()))
+            {
+                return None;
+            }
+            let weight = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            (weight > 0.0).then_some(weight)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct MetadataQuery {
+    url: String,
+
+    #[serde(default)]
+    pretty: Option<String>,
+    #[serde(default)]
+    thumbhash: Option<String>,
+    #[serde(default)]
+    blurhash: Option<String>,
+    #[serde(default)]
+    blurhash_x: Option<u32>,
+    #[serde(default)]
+    blurhash_y: Option<u32>,
+    #[serde(default)]
+    dominant: Option<String>,
+    #[serde(default)]
+    palette: Option<u32>,
+    #[serde(default)]
+    strip_gps: Option<String>,
+    #[serde(default)]
+    auto_orient: Option<String>,
+    #[serde(default)]
+    dimensions_only: Option<String>,
+    #[serde(default)]
+    case: Option<FieldCase>,
+    #[serde(default)]
+    partial: Option<String>,
+    #[serde(default)]
+    timing: Option<String>,
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    s: Option<String>,
+}
+
+impl MetadataQuery {
+    fn options(&self) -> MetadataOptions {
+        MetadataOptions {
+            thumbhash: self.is_thumbhash(),
+            blurhash: self
+                .is_blurhash()
+                .then(|| BlurhashComponents::new(self.blurhash_x, self.blurhash_y)),
+            dominant: self
+                .is_dominant()
+                .then(|| DominantOptions::new(self.palette)),
+            strip_gps: self.is_strip_gps(),
+            skip_orient: !is_auto_orient(&self.auto_orient),
+            dimensions_only: self.is_dimensions_only(),
+            allow_partial: self.is_partial(),
+        }
+    }
+
+    fn is_pretty(&self) -> bool {
+        Self::is_enabled(&self.pretty)
+    }
+
+    fn is_timing(&self) -> bool {
+        Self::is_enabled(&self.timing)
+    }
+
+    fn is_thumbhash(&self) -> bool {
+        Self::is_enabled(&self.thumbhash)
+    }
+
+    fn is_blurhash(&self) -> bool {
+        Self::is_enabled(&self.blurhash)
+    }
+
+    fn is_dominant(&self) -> bool {
+        Self::is_enabled(&self.dominant)
+    }
+
+    fn is_strip_gps(&self) -> bool {
+        Self::is_enabled(&self.strip_gps)
+    }
+
+    fn is_dimensions_only(&self) -> bool {
+        Self::is_enabled(&self.dimensions_only)
+    }
+
+    fn is_partial(&self) -> bool {
+        Self::is_enabled(&self.partial)
+    }
+
+    fn is_enabled(v: &Option<String>) -> bool {
+        if let Some(v) = v {
+            v != "false"
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImageDebug {
     original_height: u32,
     original_width: u32,
     original_size: u64,
     original_format: InputImageType,
+    output_size: u64,
+    /// The percentage of bytes saved relative to the original, which is
+    /// negative when the output is larger.
+    savings_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_path: Option<PathBuf>,
 }
 
 impl ImageDebug {
-    fn new(output: &ImageOutput) -> Self {
+    fn new(res: &ImageResponse) -> Self {
+        let output = &res.output;
+        let output_size = output.buf.len() as u64;
+        let savings_percent = if output.orig_size == 0 {
+            0.0
+        } else {
+            let savings = 1.0 - output_size as f64 / output.orig_size as f64;
+            (savings * 10_000.0).round() / 100.0
+        };
         ImageDebug {
             original_height: output.orig_height,
             original_width: output.orig_width,
             original_size: output.orig_size,
             original_format: output.orig_type,
+            output_size,
+            savings_percent,
+            cache_path: res.cache_path.clone(),
         }
     }
 }
 
-fn options_from_query(query: &ImageQuery, headers: &HeaderMap) -> ProcessOptions {
+// returns whether the EXIF orientation should be applied, which is enabled
+// unless explicitly disabled.
+fn is_auto_orient(v: &Option<String>) -> bool {
+    v.as_deref() != Some("false")
+}
+
+/// The response body used when the image is requested as JSON, allowing small
+/// images to be inlined as a data URI.
+#[derive(Serialize)]
+struct ImageJson {
+    data: String,
+    width: u32,
+    height: u32,
+}
+
+impl ImageJson {
+    fn new(res: &ImageResponse) -> Self {
+        let output = &res.output;
+        ImageJson {
+            data: format!(
+                "data:{};base64,{}",
+                res.mimetype(),
+                STANDARD.encode(&output.buf)
+            ),
+            width: output.width,
+            height: output.height,
+        }
+    }
+}
+
+fn is_valid_avif_depth(depth: u8) -> bool {
+    matches!(depth, 8 | 10)
+}
+
+fn options_from_query(state: &Handler, query: &ImageQuery, headers: &HeaderMap) -> ProcessOptions {
+    // Placeholders ignore the other options, so that they share a cache entry
+    // for each source image.
+    if query.is_lqip() {
+        return ProcessOptions::lqip(!is_auto_orient(&query.auto_orient));
+    }
+
     let width = query
         .width
         .and_then(|width| if width == 0 { None } else { Some(width) });
     let height = query
         .height
         .and_then(|height| if height == 0 { None } else { Some(height) });
-    let quality = query.quality.map(|quality| quality.clamp(1, 100));
+    let quality = query.quality.as_deref().and_then(Quality::parse);
     let blur = query
         .blur
-        .and_then(|blur| if blur == 0 { None } else { Some(blur) });
+        .and_then(|blur| if blur == 0 { None } else { Some(blur) })
+        .map(|blur| blur.min(state.config.max_blur));
+    let blur_pct = query
+        .blur_pct
+        .filter(|&pct| pct > 0)
+        .map(|pct| pct.min(image::max_blur_pct(state.config.max_blur)));
 
     let accept = headers.get("accept");
     ProcessOptions {
         width,
         height,
-        out_type: query.format.as_ref().and_then(|v| v.format(accept)),
+        out_type: match &query.format {
+            Some(v) if v.is_orig() => None,
+            Some(v) => v
+                .format(accept, |fmt| state.allows_format(fmt))
+                .or(state.config.default_output_format),
+            None => state.config.default_output_format,
+        },
         quality,
         blur,
+        blur_pct,
+        blur_type: blur.or(blur_pct).and(query.blur_type),
+        order: blur.or(blur_pct).and(query.order),
+        radius: query.radius.as_deref().and_then(Radius::parse),
+        if_larger: (width.is_some() || height.is_some()) && query.is_if_larger(),
+        crop: query.crop(),
+        dpi: query.dpi.filter(|&dpi| dpi > 0),
+        average: false,
+        skip_orient: !is_auto_orient(&query.auto_orient),
+        png_level: query.png_level.as_deref().and_then(PngLevel::parse),
+        tiff_compression: query.tiff_compression,
+        avif_depth: query.avif_depth.filter(|&depth| is_valid_avif_depth(depth)),
+        near_lossless: query.near_lossless.map(|level| level.min(100)),
+        partial: query.is_partial(),
+        // Requesting the original format without any processing returns the
+        // source image untouched.
+        passthrough: query.format.as_ref().is_some_and(ImageFormats::is_orig),
+        strip_gps: query.is_strip_gps(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::disk::DiskCache, handler::tests as handler, signature::Verifier};
+
+    const KEY: &str = "00112233445566778899aabbccddeeff";
+
+    fn signed_state() -> HandlerState {
+        let verifier = Verifier::new([KEY.to_owned()].into_iter()).unwrap();
+        Arc::new(handler::handler(handler::config(), Some(verifier)))
+    }
+
+    async fn placeholder(state: &HandlerState, query: &str) -> Response {
+        let request = Request::builder()
+            .uri(format!("/placeholder?{query}"))
+            .body(Body::empty())
+            .unwrap();
+        let params = serde_urlencoded::from_str(query).unwrap();
+        get_placeholder(Query(params), State(state.clone()), request).await
+    }
+
+    async fn image(state: &HandlerState, query: &str, headers: HeaderMap) -> Response {
+        let request = Request::builder()
+            .uri(format!("/?{query}"))
+            .body(Body::empty())
+            .unwrap();
+        let params = serde_urlencoded::from_str(query).unwrap();
+        get_image(headers, Query(params), State(state.clone()), request).await
+    }
+
+    async fn image_metadata(state: &HandlerState, query: &str, headers: HeaderMap) -> Response {
+        let request = Request::builder()
+            .uri(format!("/metadata?{query}"))
+            .body(Body::empty())
+            .unwrap();
+        let params = serde_urlencoded::from_str(query).unwrap();
+        get_image_metadata(headers, Query(params), State(state.clone()), request).await
+    }
+
+    async fn body(res: Response) -> Bytes {
+        axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    // returns a handler that reads "local:img.png" from a new local root.
+    fn local_state(name: &str) -> (HandlerState, PathBuf) {
+        let root = handler::local_png(name);
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            ..handler::config()
+        };
+        (Arc::new(handler::handler(config, None)), root)
+    }
+
+    #[tokio::test]
+    async fn test_placeholder_hash_requires_signature() {
+        let state = signed_state();
+        let hash = STANDARD.encode(thumbhash::rgba_to_thumb_hash(2, 2, &[255; 16]));
+        let query = serde_urlencoded::to_string([("hash", hash)]).unwrap();
+
+        let res = placeholder(&state, &query).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let verifier = Verifier::new([KEY.to_owned()].into_iter()).unwrap();
+        let sig = verifier.sign("/placeholder", Some(&query)).unwrap();
+        let res = placeholder(&state, &format!("{query}&s={sig}")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_warm() {
+        let root = handler::local_png("warm");
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            ..handler::config()
+        };
+        let state = Arc::new(handler::handler(config, None));
+
+        // More entries than are processed concurrently, with results in order.
+        let entries = (0..WARM_CONCURRENCY * 2 + 1)
+            .map(|i| {
+                let url = if i % 2 == 0 {
+                    "local:img.png"
+                } else {
+                    "local:missing.png"
+                };
+                serde_json::from_value(serde_json::json!({"url": url, "options": {"width": i + 1}}))
+                    .unwrap()
+            })
+            .collect();
+        let res = warm(State(state), Json(entries)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(results.len(), WARM_CONCURRENCY * 2 + 1);
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result["ok"], i % 2 == 0, "{i}: {result}");
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_check_strict_blur() {
+        let query = |raw: &str| serde_urlencoded::from_str::<ImageQuery>(raw).unwrap();
+        assert!(query("url=a&blur=20").check_strict(20).is_ok());
+        assert!(query("url=a&blur=21").check_strict(20).is_err());
+        assert!(query("url=a&blur_pct=5").check_strict(20).is_ok());
+        assert!(query("url=a&blur_pct=6").check_strict(20).is_err());
+        assert!(query("url=a&blur_pct=25")
+            .check_strict(image::MAX_BLUR)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_listen_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = handler::handler(handler::config(), None);
+        let err = start_server(state, ListenAddr::Tcp(addr))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("unable to listen on {addr}: ")),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_length() {
+        let (state, root) = local_state("content-length");
+
+        let res = image(&state, "url=local:img.png&width=2", HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let len = res.headers()["content-length"].to_str().unwrap().to_owned();
+        assert_eq!(len, body(res).await.len().to_string());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        let query = "url=local:img.png&thumbhash&pretty";
+        let res = image_metadata(&state, query, headers).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let len = res.headers()["content-length"].to_str().unwrap().to_owned();
+        assert_eq!(len, body(res).await.len().to_string());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_preferred_format() {
+        let format = |fmts: &str, accept: &str| {
+            let fmts = serde_urlencoded::from_str::<ImageQuery>(&format!("url=a&format={fmts}"))
+                .unwrap()
+                .format
+                .unwrap();
+            let accept = HeaderValue::from_str(accept).unwrap();
+            fmts.format(Some(&accept), |fmt| fmt != ImageType::Tiff)
+        };
+
+        let chrome = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+        assert_eq!(format("auto", chrome), Some(ImageType::Avif));
+        assert_eq!(format("auto", "image/webp"), Some(ImageType::Webp));
+        assert_eq!(
+            format("auto", "image/avif;q=0.5,image/webp;q=0.9"),
+            Some(ImageType::Webp)
+        );
+        // Wildcards and rejected formats aren't treated as accepted.
+        assert_eq!(format("auto", "*/*"), None);
+        assert_eq!(format("auto", "image/avif;q=0,image/*"), None);
+
+        // Listed formats fall back to the last, which needn't be accepted.
+        assert_eq!(format("webp,png", chrome), Some(ImageType::Webp));
+        assert_eq!(format("webp,png", "*/*"), Some(ImageType::Png));
+        assert_eq!(format("avif,webp,png", "image/webp"), Some(ImageType::Webp));
+        // Formats that aren't allowed are skipped, unless explicit.
+        assert_eq!(format("webp,tiff", "*/*"), Some(ImageType::Webp));
+        assert_eq!(format("tiff", "*/*"), Some(ImageType::Tiff));
+        assert_eq!(format("gif", "*/*"), None);
+
+        assert_eq!(
+            ImageFormats::preferred(None, [ImageType::Webp].into_iter()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metadata_encoding() {
+        let (state, root) = local_state("metadata-encoding");
+        // A colorful image, so the metadata is large enough to compress.
+        let img = ::image::RgbImage::from_fn(32, 32, |x, y| {
+            ::image::Rgb([x as u8 * 8, y as u8 * 8, (x * y) as u8])
+        });
+        img.save(root.join("img.png")).unwrap();
+        let query =
+            "url=local:img.png&blurhash&blurhash_x=9&blurhash_y=9&palette=16&dominant&pretty";
+
+        let res = image_metadata(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key("content-encoding"));
+        let plain = body(res).await;
+        assert!(plain.len() >= ContentEncoding::MIN_SIZE);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-encoding", HeaderValue::from_static("br, gzip"));
+        let res = image_metadata(&state, query, headers).await;
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(res.headers()["vary"], "accept-encoding");
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::GzDecoder::new(&body(res).await[..]),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, plain);
+
+        // Image bytes are never compressed.
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        let res = image(&state, "url=local:img.png&format=png", headers).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key("content-encoding"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let negotiate = |accept: &str, size: usize| {
+            ContentEncoding::negotiate(Some(&HeaderValue::from_str(accept).unwrap()), size)
+                .map(ContentEncoding::as_str)
+        };
+        let size = ContentEncoding::MIN_SIZE;
+        assert_eq!(negotiate("gzip, deflate", size), Some("gzip"));
+        assert_eq!(negotiate("deflate", size), Some("deflate"));
+        assert_eq!(negotiate("GZIP;q=0.5", size), Some("gzip"));
+        assert_eq!(negotiate("gzip;q=0, deflate", size), Some("deflate"));
+        assert_eq!(negotiate("br, identity", size), None);
+        assert_eq!(negotiate("gzip", size - 1), None);
+        assert!(ContentEncoding::negotiate(None, size).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("imaged-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("imaged.sock");
+
+        // A regular file at the path is left alone.
+        std::fs::write(&path, "").unwrap();
+        let state = handler::handler(handler::config(), None);
+        let err = start_server(state, ListenAddr::Unix(path.clone()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().ends_with("is not a socket"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+
+        // A stale socket left behind by a previous server is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let state = handler::handler(handler::config(), None);
+        let server = tokio::spawn(start_server(state, ListenAddr::Unix(path.clone())));
+
+        let res = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                use std::io::Read as _;
+
+                let mut stream = loop {
+                    match std::os::unix::net::UnixStream::connect(&path) {
+                        Ok(stream) => break stream,
+                        Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                    }
+                };
+                stream
+                    .write_all(
+                        b"GET /version HTTP/1.1\r\nhost: imaged\r\nconnection: close\r\n\r\n",
+                    )
+                    .unwrap();
+                let mut res = String::new();
+                stream.read_to_string(&mut res).unwrap();
+                res
+            }
+        })
+        .await
+        .unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+        assert!(res.contains(NAME_VERSION), "{res}");
+
+        server.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // An origin that accepts connections but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                streams.push(listener.accept().await.unwrap());
+            }
+        });
+        let query = format!("url=http://{addr}/img.png&width=2");
+
+        let config = crate::handler::HandlerConfig {
+            request_timeout: Duration::from_millis(100),
+            ..handler::config()
+        };
+        let state = Arc::new(handler::handler(config, None));
+        let res = image(&state, &query, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        // Clients can shorten the deadline, but not extend it.
+        let state = Arc::new(handler::handler(handler::config(), None));
+        assert_eq!(state.timeout(None), Duration::from_secs(10));
+        assert_eq!(state.timeout(Some(0)), Duration::from_secs(1));
+        assert_eq!(state.timeout(Some(5)), Duration::from_secs(5));
+        assert_eq!(state.timeout(Some(60)), Duration::from_secs(10));
+
+        let start = std::time::Instant::now();
+        let res = image(&state, &format!("{query}&timeout=1"), HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_sign() {
+        let verifier = || Verifier::new([KEY.to_owned()].into_iter()).unwrap();
+        let config = crate::handler::HandlerConfig {
+            admin_key: Some("admin".to_owned()),
+            ..handler::config()
+        };
+        let state = Arc::new(handler::handler(config, Some(verifier())));
+        let query = "width=10&url=https://example.com/a.png";
+        let request = || {
+            Json(SignRequest {
+                path: Some("/metadata".to_owned()),
+                query: Some(query.to_owned()),
+            })
+        };
+        let auth = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "authorization",
+                HeaderValue::from_str(&format!("Bearer {key}")).unwrap(),
+            );
+            headers
+        };
+
+        let res = sign(HeaderMap::new(), State(state.clone()), request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = sign(auth("wrong"), State(state.clone()), request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = sign(auth("admin"), State(state.clone()), request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        let signature = res["signature"].as_str().unwrap();
+        verifier()
+            .verify("/metadata", Some(query), signature.as_bytes())
+            .unwrap();
+        assert_eq!(res["url"], format!("/metadata?{query}&s={signature}"));
+
+        // Signing is disabled without an admin key.
+        let state = Arc::new(handler::handler(handler::config(), Some(verifier())));
+        let res = sign(auth("admin"), State(state), request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_max_url_len() {
+        let state = Arc::new(handler::handler(handler::config(), None));
+        let (url, requests) = handler::origin("image/png", handler::png()).await;
+        let long_url = format!("{url}?{}", "a".repeat(4096));
+        let query = serde_urlencoded::to_string([("url", &long_url)]).unwrap();
+
+        let res = image(&state, &query, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = image_metadata(&state, &query, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+        let query = serde_urlencoded::to_string([("url", &url)]).unwrap();
+        let res = image_metadata(&state, &query, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_json_response() {
+        let (state, root) = local_state("json-response");
+        let query = "url=local:img.png&width=2&format=png";
+
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.headers()["content-type"], "image/png");
+        let raw = body(res).await;
+
+        let res = image(&state, &format!("{query}&response=json"), HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let json: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(
+            (json["width"].as_u64(), json["height"].as_u64()),
+            (Some(2), Some(2))
+        );
+        let data = json["data"].as_str().unwrap();
+        let data = data.strip_prefix("data:image/png;base64,").unwrap();
+        assert_eq!(STANDARD.decode(data).unwrap(), raw);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_srcset() {
+        let root = handler::local_png("srcset");
+        ::image::DynamicImage::new_rgb8(8, 4)
+            .save(root.join("img.png"))
+            .unwrap();
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            ..handler::config()
+        };
+        let verifier = Verifier::new([KEY.to_owned()].into_iter()).unwrap();
+        let state = Arc::new(handler::handler(config, Some(verifier)));
+        let srcset = |query: &str| {
+            let verifier = Verifier::new([KEY.to_owned()].into_iter()).unwrap();
+            let sig = verifier.sign("/srcset", Some(query)).unwrap();
+            let query = format!("{query}&s={sig}");
+            let request = Request::builder()
+                .uri(format!("/srcset?{query}"))
+                .body(Body::empty())
+                .unwrap();
+            let params = serde_urlencoded::from_str(&query).unwrap();
+            get_srcset(
+                HeaderMap::new(),
+                Query(params),
+                State(state.clone()),
+                request,
+            )
+        };
+
+        let res = srcset("url=local:img.png&widths=4,2,4&format=png").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        let images = json["images"].as_array().unwrap();
+        assert_eq!(images.len(), 2);
+        for (entry, (width, height)) in images.iter().zip([(2, 1), (4, 2)]) {
+            assert_eq!(entry["width"], width);
+            assert_eq!(entry["height"], height);
+
+            // Each url is signed, and serves the described image.
+            let url = entry["url"].as_str().unwrap();
+            let query = url.strip_prefix("/?").unwrap();
+            let res = image(&state, query, HeaderMap::new()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["x-image-width"], width.to_string());
+            assert_eq!(
+                body(res).await.len(),
+                entry["size"].as_u64().unwrap() as usize
+            );
+        }
+        let want = format!(
+            "{} 2w, {} 4w",
+            images[0]["url"].as_str().unwrap(),
+            images[1]["url"].as_str().unwrap()
+        );
+        assert_eq!(json["srcset"], want);
+
+        let res = srcset("url=local:img.png&widths=4,0").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_age() {
+        let root = handler::local_png("age");
+        let cache_dir = root.join("cache");
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            ..handler::config()
+        };
+        let state = Arc::new(Handler::new(
+            None,
+            Some(DiskCache::new(cache_dir.clone(), 1 << 20).await.unwrap()),
+            None,
+            reqwest::Client::new(),
+            handler::processor(),
+            None,
+            config,
+        ));
+        let query = "url=local:img.png&width=2&format=png";
+
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.headers()["x-cache-status"], "MISS");
+        assert!(!res.headers().contains_key("age"));
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.headers()["x-cache-status"], "HIT");
+        let age: u64 = res.headers()["age"].to_str().unwrap().parse().unwrap();
+        assert!(age <= 1, "{age}");
+
+        // The age grows as the cached entry gets older.
+        let hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+        let mut dirs = vec![cache_dir];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let file = std::fs::File::options().write(true).open(path).unwrap();
+                    file.set_modified(hour_ago).unwrap();
+                }
+            }
+        }
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.headers()["x-cache-status"], "HIT");
+        let age: u64 = res.headers()["age"].to_str().unwrap().parse().unwrap();
+        assert!((3600..3602).contains(&age), "{age}");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_options_from_query() {
+        let state = handler::handler(handler::config(), None);
+        let options = |raw: &str| {
+            let query = serde_urlencoded::from_str::<ImageQuery>(raw).unwrap();
+            options_from_query(&state, &query, &HeaderMap::new())
+        };
+
+        assert_eq!(options("url=a"), ProcessOptions::default());
+        assert!(!options("url=a&auto_orient=true").skip_orient);
+        assert!(options("url=a&auto_orient=false").skip_orient);
+        assert!(options("url=a&lqip&auto_orient=false").skip_orient);
+        // Other options are ignored for placeholders.
+        assert_eq!(
+            options("url=a&lqip&width=500&format=png"),
+            ProcessOptions::lqip(false)
+        );
+        assert_eq!(options("url=a&png_level=9").png_level, Some(PngLevel::Best));
+        assert_eq!(options("url=a&png_level=11").png_level, None);
+        assert_eq!(
+            options("url=a&blur=5&order=filter_first").order,
+            Some(FilterOrder::FilterFirst)
+        );
+        assert_eq!(options("url=a&order=filter_first").order, None);
+        assert_eq!(options("url=a&avif_depth=10").avif_depth, Some(10));
+        assert_eq!(options("url=a&avif_depth=12").avif_depth, None);
+        assert!(options("url=a&partial").partial);
+        assert!(!options("url=a&partial=false").partial);
+        let ops = options("url=a&format=orig");
+        assert!(ops.passthrough && ops.out_type.is_none());
+        assert!(!options("url=a&format=png").passthrough);
+
+        // if_larger only applies when resizing.
+        assert!(options("url=a&width=10&if_larger").if_larger);
+        assert!(options("url=a&height=10&if_larger=true").if_larger);
+        assert!(!options("url=a&width=10&if_larger=false").if_larger);
+        assert!(!options("url=a&if_larger").if_larger);
+
+        // A pixel crop needs a width and height.
+        let ops = options("url=a&cx=1&cy=2&cw=3&ch=4");
+        assert_eq!(
+            ops.crop,
+            Some(Crop::Pixels {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+            })
+        );
+        assert_eq!(options("url=a&cw=3").crop, None);
+        assert_eq!(options("url=a&cw=0&ch=4").crop, None);
+
+        // A pixel crop takes precedence over a relative one.
+        let ops = options("url=a&cx=1&cy=2&cw=3&ch=4&crop=0,0,1,1");
+        assert!(matches!(ops.crop, Some(Crop::Pixels { .. })));
+        let ops = options("url=a&cw=0&ch=4&crop=0,0,1,1");
+        assert!(matches!(ops.crop, Some(Crop::Relative { .. })));
+        assert_eq!(options("url=a&crop=0.5,0.5,1,1").crop, None);
+    }
+
+    #[tokio::test]
+    async fn test_average_placeholder() {
+        let (state, root) = local_state("average");
+        // A gradient with an average color of roughly (128, 64, 200).
+        let img = ::image::RgbImage::from_fn(16, 16, |x, _| {
+            ::image::Rgb([(x * 17) as u8, (x * 8 + 4) as u8, 200])
+        });
+        img.save(root.join("img.png")).unwrap();
+
+        let res = placeholder(&state, "url=local:img.png&type=png&size=2").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "image/png");
+        let out = ::image::load_from_memory(&body(res).await)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(out.dimensions(), (2, 2));
+        let pixel = out.get_pixel(0, 0).0;
+        assert!(out.pixels().all(|p| p.0 == pixel));
+        for (got, want) in pixel.into_iter().zip([128, 64, 200]) {
+            assert!(got.abs_diff(want) <= 2, "{pixel:?}");
+        }
+
+        let res = placeholder(&state, "type=png").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_savings() {
+        let (state, root) = local_state("debug-savings");
+        let img = ::image::RgbImage::from_fn(32, 32, |x, y| {
+            ::image::Rgb([(x * 8) as u8, (y * 8) as u8, (x * y) as u8])
+        });
+        img.save(root.join("img.png")).unwrap();
+        let orig_size = std::fs::metadata(root.join("img.png")).unwrap().len();
+        let debug = |res: &Response| -> serde_json::Value {
+            serde_json::from_str(res.headers()["x-image-debug"].to_str().unwrap()).unwrap()
+        };
+
+        let res = image(
+            &state,
+            "url=local:img.png&width=8&format=png&debug",
+            HeaderMap::new(),
+        )
+        .await;
+        let json = debug(&res);
+        let size = body(res).await.len() as u64;
+        assert!(size < orig_size);
+        assert_eq!(json["original_size"], orig_size);
+        assert_eq!(json["output_size"], size);
+        let savings = ((1.0 - size as f64 / orig_size as f64) * 10_000.0).round() / 100.0;
+        assert_eq!(json["savings_percent"], savings);
+        assert!(savings > 0.0);
+
+        // Passing through the source saves nothing.
+        let res = image(&state, "url=local:img.png&debug", HeaderMap::new()).await;
+        let json = debug(&res);
+        assert_eq!(json["output_size"], orig_size);
+        assert_eq!(json["savings_percent"], 0.0);
+
+        // Outputs larger than the source have negative savings.
+        let query = "url=local:img.png&format=tiff&tiff_compression=none&debug";
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert!(debug(&res)["savings_percent"].as_f64().unwrap() < 0.0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multipart_response() {
+        let (state, root) = local_state("multipart");
+        let query = "url=local:img.png&width=2&format=png&response=multipart";
+
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let content_type = res.headers()["content-type"].to_str().unwrap().to_owned();
+        let boundary = content_type
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap();
+        let body = body(res).await;
+
+        // Splits each part into its content type and body.
+        let mut rest = &body[..];
+        let mut parts = Vec::new();
+        let delimiter = format!("--{boundary}\r\n");
+        while let Some(part) = rest.strip_prefix(delimiter.as_bytes()) {
+            let end = part.windows(4).position(|v| v == b"\r\n\r\n").unwrap();
+            let head = std::str::from_utf8(&part[..end]).unwrap();
+            let header = |name: &str| {
+                head.lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                    .unwrap()
+                    .to_owned()
+            };
+            let len: usize = header("content-length").parse().unwrap();
+            let data = &part[end + 4..end + 4 + len];
+            parts.push((header("content-type"), data.to_vec()));
+            rest = part[end + 4 + len..].strip_prefix(b"\r\n").unwrap();
+        }
+        assert_eq!(rest, format!("--{boundary}--\r\n").as_bytes());
+        assert_eq!(parts.len(), 2);
+
+        // The metadata describes the source, and the image is the output.
+        let (content_type, json) = &parts[0];
+        assert_eq!(content_type, "application/json");
+        let metadata: serde_json::Value = serde_json::from_slice(json).unwrap();
+        assert_eq!(metadata["format"], "png");
+        assert_eq!(
+            (metadata["width"].as_u64(), metadata["height"].as_u64()),
+            (Some(4), Some(4))
+        );
+        let (content_type, buf) = &parts[1];
+        assert_eq!(content_type, "image/png");
+        let img = ::image::load_from_memory(buf).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 2));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        // A server that never finishes, as a request is hanging.
+        let hanging = || std::future::pending::<std::io::Result<()>>();
+        let shutdown = Notify::new();
+        let in_flight = AtomicUsize::new(1);
+
+        shutdown.notify_one();
+        let start = std::time::Instant::now();
+        let timeout = Duration::from_millis(50);
+        drain(hanging(), &shutdown, Some(timeout), &in_flight)
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= timeout && elapsed < Duration::from_secs(5));
+
+        // Servers that finish in time aren't affected.
+        shutdown.notify_one();
+        let serve = async { Err(std::io::Error::other("closed")) };
+        let err = drain(serve, &shutdown, Some(timeout), &in_flight)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "closed");
+
+        // Without a timeout, the server is waited on indefinitely.
+        let res = time::timeout(timeout, drain(hanging(), &shutdown, None, &in_flight)).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let res = get_version().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let json: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(
+            json["version"],
+            format!("imaged/{}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(json["git_sha"], env!("GIT_SHA"));
+        assert!(json["formats"].as_array().unwrap().contains(&"png".into()));
+    }
+
+    #[tokio::test]
+    async fn test_security_headers() {
+        // returns the response headers for an image from a running server.
+        async fn headers(security_headers: bool) -> HeaderMap {
+            let root = handler::local_png(&format!("security-{security_headers}"));
+            let config = crate::handler::HandlerConfig {
+                local_root: Some(root.clone()),
+                security_headers,
+                ..handler::config()
+            };
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let state = handler::handler(config, None);
+            let server = tokio::spawn(start_server(state, ListenAddr::Tcp(addr)));
+
+            let url = format!("http://{addr}/?url=local:img.png&width=2&format=png");
+            let res = loop {
+                match reqwest::get(&url).await {
+                    Ok(res) => break res,
+                    Err(_) => time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            assert_eq!(res.status(), StatusCode::OK);
+            server.abort();
+            std::fs::remove_dir_all(root).unwrap();
+            res.headers().clone()
+        }
+
+        let res = headers(true).await;
+        assert_eq!(res["x-content-type-options"], "nosniff");
+        assert_eq!(res["content-security-policy"], "default-src 'none'");
+        assert_eq!(res["referrer-policy"], "no-referrer");
+
+        let res = headers(false).await;
+        assert!(!res.contains_key("x-content-type-options"));
+        assert!(!res.contains_key("content-security-policy"));
+        assert!(!res.contains_key("referrer-policy"));
+    }
+
+    #[tokio::test]
+    async fn test_vary_accept() {
+        let (state, root) = local_state("vary-accept");
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", HeaderValue::from_static("image/webp"));
+
+        // Only formats chosen using the Accept header vary on it.
+        for (format, vary) in [("auto", true), ("webp,png", true), ("webp", false)] {
+            let query = format!("url=local:img.png&format={format}");
+            let res = image(&state, &query, headers.clone()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{format}");
+            assert_eq!(res.headers().get("content-type").unwrap(), "image/webp");
+            let expected = vary.then(|| HeaderValue::from_static("accept"));
+            assert_eq!(res.headers().get("vary"), expected.as_ref(), "{format}");
+        }
+        let res = image(&state, "url=local:img.png", headers).await;
+        assert!(res.headers().get("vary").is_none());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_overloaded_response() {
+        let res = error_response(&Overloaded.into());
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "1");
+        assert_eq!(
+            res.headers().get("x-error-code").unwrap(),
+            ErrorCode::Overloaded.as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timing_trailer() {
+        let (state, root) = local_state("timing-trailer");
+        let res = image(
+            &state,
+            "url=local:img.png&width=2&timing=trailer",
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["trailer"], "server-timing");
+        assert!(res.headers().get("server-timing").is_none());
+        assert!(res.headers().get("content-length").is_none());
+
+        let mut body = res.into_body();
+        let (mut data, mut trailers) = (Vec::new(), None);
+        while let Some(frame) =
+            std::future::poll_fn(|cx| http_body::Body::poll_frame(Pin::new(&mut body), cx)).await
+        {
+            match frame.unwrap().into_data() {
+                Ok(buf) => data.extend_from_slice(&buf),
+                Err(frame) => trailers = frame.into_trailers().ok(),
+            }
+        }
+        assert_eq!(::image::load_from_memory(&data).unwrap().width(), 2);
+        let timing = trailers.unwrap()["server-timing"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(timing.contains("process"), "{timing}");
+
+        // Otherwise, timing is sent as a header.
+        let res = image(&state, "url=local:img.png&width=2&timing", HeaderMap::new()).await;
+        assert!(res.headers().get("trailer").is_none());
+        assert!(res.headers()["server-timing"]
+            .to_str()
+            .unwrap()
+            .contains("process"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_default_output_format() {
+        let root = handler::local_png("default-output-format");
+        ::image::RgbImage::new(4, 4)
+            .save(root.join("img.tiff"))
+            .unwrap();
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            default_output_format: Some(ImageType::Webp),
+            ..handler::config()
+        };
+        let state = Arc::new(handler::handler(config, None));
+        let content_type = |res: &Response| res.headers()["content-type"].clone();
+
+        // The default is used without a format or a useful Accept header, and
+        // the source format is kept with format=orig.
+        let res = image(&state, "url=local:img.tiff", HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(content_type(&res), "image/webp");
+        let res = image(&state, "url=local:img.tiff&format=orig", HeaderMap::new()).await;
+        assert_eq!(content_type(&res), "image/tiff");
+        let res = image(&state, "url=local:img.tiff&format=auto", HeaderMap::new()).await;
+        assert_eq!(content_type(&res), "image/webp");
+        let res = image(&state, "url=local:img.tiff&format=png", HeaderMap::new()).await;
+        assert_eq!(content_type(&res), "image/png");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_cache_path() {
+        let root = handler::local_png("debug-cache-path");
+        let cache_dir = root.join("cache");
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            ..handler::config()
+        };
+        let state = Arc::new(Handler::new(
+            None,
+            Some(DiskCache::new(cache_dir.clone(), 1 << 20).await.unwrap()),
+            None,
+            reqwest::Client::new(),
+            handler::processor(),
+            None,
+            config,
+        ));
+        let debug = |res: &Response| -> serde_json::Value {
+            serde_json::from_slice(res.headers()["x-image-debug"].as_bytes()).unwrap()
+        };
+
+        // The path is that of the cached file, relative to the cache directory.
+        let query = "url=local:img.png&width=2&debug";
+        let res = image(&state, query, HeaderMap::new()).await;
+        let path = debug(&res)["cache_path"].as_str().unwrap().to_owned();
+        assert!(std::path::Path::new(&path).is_relative());
+        let cached = std::fs::read(cache_dir.join(&path)).unwrap();
+        assert!(!cached.is_empty());
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(res.headers()["x-cache-status"], "HIT");
+        assert_eq!(debug(&res)["cache_path"], path.as_str());
+
+        // Other requests are cached at other paths, and it's only included in
+        // the debug output.
+        let res = image(&state, "url=local:img.png&width=3&debug", HeaderMap::new()).await;
+        assert_ne!(debug(&res)["cache_path"], path.as_str());
+        let res = image(&state, "url=local:img.png&width=2", HeaderMap::new()).await;
+        assert!(res.headers().get("x-image-debug").is_none());
+
+        // Without a disk cache, there's no path.
+        let (state, local_root) = local_state("debug-cache-path-none");
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert!(debug(&res).get("cache_path").is_none());
+
+        std::fs::remove_dir_all(local_root).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_image_options_header() {
+        let (state, root) = local_state("image-options");
+        let options = |res: &Response| -> serde_json::Value {
+            serde_json::from_slice(res.headers()["x-image-options"].as_bytes()).unwrap()
+        };
+
+        // The defaulted quality and output format are included.
+        let query = "url=local:img.png&width=2&format=webp&debug";
+        let res = image(&state, query, HeaderMap::new()).await;
+        let resolved = state
+            .processor
+            .resolve_options(ProcessOptions::default(), ImageType::Webp);
+        let quality = serde_json::to_value(resolved.quality.unwrap()).unwrap();
+        assert_eq!(
+            options(&res),
+            serde_json::json!({"width": 2, "out_type": "webp", "quality": quality})
+        );
+        let query = "url=local:img.png&width=2&format=webp&quality=50&debug";
+        let res = image(&state, query, HeaderMap::new()).await;
+        assert_eq!(options(&res)["quality"], 50);
+
+        // Lossless formats have no quality, and the header requires debug.
+        let res = image(&state, "url=local:img.png&width=2&debug", HeaderMap::new()).await;
+        assert_eq!(
+            options(&res),
+            serde_json::json!({"width": 2, "out_type": "png"})
+        );
+        let res = image(&state, "url=local:img.png&width=2", HeaderMap::new()).await;
+        assert!(res.headers().get("x-image-options").is_none());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metadata_case() {
+        // A little-endian TIFF structure with an f-number of 4 in its EXIF
+        // IFD, which is at offset 26 with its value at offset 44.
+        let ifd = |tiff: &mut Vec<u8>, tag: u16, field_type: u16, value: u32| {
+            tiff.extend_from_slice(&1u16.to_le_bytes());
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&field_type.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+            tiff.extend_from_slice(&0u32.to_le_bytes());
+        };
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        ifd(&mut tiff, 0x8769, 4, 26);
+        ifd(&mut tiff, 0x829d, 5, 44);
+        tiff.extend_from_slice(&4u32.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+
+        // Insert an eXIf chunk after the PNG signature and IHDR chunk.
+        let mut chunk = (tiff.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"eXIf");
+        chunk.extend_from_slice(&tiff);
+        chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+        let (state, root) = local_state("metadata-case");
+        let mut png = handler::png();
+        png.splice(33..33, chunk);
+        std::fs::write(root.join("img.png"), png).unwrap();
+
+        async fn json(state: &HandlerState, query: &str) -> serde_json::Value {
+            let res = image_metadata(state, query, HeaderMap::new()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            serde_json::from_slice(&body(res).await).unwrap()
+        }
+        let value = json(&state, "url=local:img.png&dominant").await;
+        assert_eq!(value["data"]["f_number"], 4.0);
+        assert!(value.get("dominant_color").is_some());
+        let value = json(&state, "url=local:img.png&dominant&case=camel").await;
+        assert_eq!(value["data"]["fNumber"], 4.0);
+        assert!(value["data"].get("f_number").is_none());
+        assert!(value.get("dominantColor").is_some());
+
+        // The configured default can be overridden per request.
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            metadata_case: FieldCase::Camel,
+            ..handler::config()
+        };
+        let state = Arc::new(handler::handler(config, None));
+        let value = json(&state, "url=local:img.png").await;
+        assert_eq!(value["data"]["fNumber"], 4.0);
+        let value = json(&state, "url=local:img.png&case=snake").await;
+        assert_eq!(value["data"]["f_number"], 4.0);
+
+        assert_eq!(to_camel_case("date_time_original"), "dateTimeOriginal");
+        assert_eq!(to_camel_case("_private"), "private");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_format_orig() {
+        let root = handler::local_png("format-orig");
+        let img = ::image::DynamicImage::new_rgba8(8, 4);
+        img.save(root.join("img.tiff")).unwrap();
+        img.save(root.join("img.ico")).unwrap();
+        let config = crate::handler::HandlerConfig {
+            local_root: Some(root.clone()),
+            ..handler::config()
+        };
+        let verifier = || Verifier::new([KEY.to_owned()].into_iter()).unwrap();
+        let state = Arc::new(handler::handler(config, Some(verifier())));
+        let verifier = verifier();
+        let signed = |query: &str| {
+            let sig = verifier.sign("/", Some(query)).unwrap();
+            format!("{query}&s={sig}")
+        };
+
+        // Sources are returned byte-for-byte, with their own mimetype.
+        for (name, mimetype) in [
+            ("img.png", "image/png"),
+            ("img.tiff", "image/tiff"),
+            ("img.ico", "image/x-icon"),
+        ] {
+            let query = signed(&format!("url=local:{name}&format=orig"));
+            let res = image(&state, &query, HeaderMap::new()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{name}");
+            assert_eq!(res.headers()["content-type"], mimetype);
+            let src = std::fs::read(root.join(name)).unwrap();
+            assert_eq!(body(res).await, src, "{name}");
+        }
+
+        // Signatures are still required.
+        let res = image(&state, "url=local:img.tiff&format=orig", HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // With other options, the source format is kept while processing.
+        let query = signed("url=local:img.tiff&format=orig&width=4");
+        let res = image(&state, &query, HeaderMap::new()).await;
+        assert_eq!(res.headers()["content-type"], "image/tiff");
+        let out = ::image::load_from_memory(&body(res).await).unwrap();
+        assert_eq!((out.width(), out.height()), (4, 2));
+        std::fs::remove_dir_all(root).unwrap();
     }
 }
@@ -1,32 +1,94 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use anyhow::{anyhow, Result};
 use hex::decode;
 use hmac::{Hmac, Mac};
+use serde::Serialize;
 use sha2::Sha256;
 
 type HmacSha256 = Hmac<Sha256>;
-type Key = Vec<u8>;
+
+struct Key {
+    id: Option<String>,
+    secret: Vec<u8>,
+    // the number of signatures verified by this key.
+    verified: AtomicU64,
+}
+
+impl Key {
+    // parses a hex key, optionally prefixed by its id, like "id:key".
+    fn parse(input: &str) -> Result<Self> {
+        let (id, hex) = match input.split_once(':') {
+            Some((id, hex)) => (Some(id.to_owned()), hex),
+            None => (None, input),
+        };
+        Ok(Key {
+            id,
+            secret: decode(hex)?,
+            verified: AtomicU64::new(0),
+        })
+    }
+}
 
 pub struct Verifier {
     keys: Vec<Key>,
 }
 
+/// The number of signatures verified by a key, which shows when a rotated out
+/// key is no longer in use.
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub verified: u64,
+}
+
 impl Verifier {
     pub fn new(input: impl Iterator<Item = String>) -> Result<Self> {
-        let keys = input.map(decode).collect::<Result<_, _>>()?;
+        let keys = input.map(|key| Key::parse(&key)).collect::<Result<_>>()?;
         Ok(Verifier { keys })
     }
 
+    /// Reads the hex keys from the file at the provided path. Keys can be
+    /// separated by commas or whitespace.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        let keys = raw
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|key| !key.is_empty())
+            .map(ToOwned::to_owned);
+        Self::new(keys)
+    }
+
     pub fn verify(&self, path: &str, query: Option<&str>, hex_sig: &[u8]) -> Result<()> {
         let msg = Self::get_message(path, query)
             .map_err(|err| anyhow!(format!("parsing query string: {}", err)))?;
 
+        // Signatures prefixed with a key id, like "id:signature", are only
+        // checked against that key.
+        let (id, hex_sig) = match hex_sig.iter().position(|&b| b == b':') {
+            Some(i) => (Some(&hex_sig[..i]), &hex_sig[i + 1..]),
+            None => (None, hex_sig),
+        };
         let sig = decode(hex_sig).map_err(|_| anyhow!("invalid hex signature"))?;
-        for key in &self.keys {
-            let mut mac = HmacSha256::new_from_slice(key).unwrap();
+        let mut keys = self
+            .keys
+            .iter()
+            .filter(|key| id.is_none_or(|id| key.id.as_deref().map(str::as_bytes) == Some(id)))
+            .peekable();
+        if keys.peek().is_none() {
+            return Err(anyhow!("unknown signature key id"));
+        }
+
+        for key in keys {
+            let mut mac = HmacSha256::new_from_slice(&key.secret).unwrap();
             mac.update(msg.as_bytes());
             if mac.verify_slice(&sig).is_ok() {
+                key.verified.fetch_add(1, Ordering::Relaxed);
                 return Ok(());
             }
         }
@@ -34,6 +96,34 @@ impl Verifier {
         Err(anyhow!("invalid signature provided"))
     }
 
+    /// Returns the number of signatures verified by each key, in the order the
+    /// keys were provided.
+    pub fn stats(&self) -> Vec<KeyStats> {
+        self.keys
+            .iter()
+            .map(|key| KeyStats {
+                id: key.id.clone(),
+                verified: key.verified.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns the hex signature for the provided path and query using the
+    /// first configured key, prefixed by the key's id if it has one.
+    pub fn sign(&self, path: &str, query: Option<&str>) -> Result<String> {
+        let msg = Self::get_message(path, query)
+            .map_err(|err| anyhow!(format!("parsing query string: {}", err)))?;
+        let key = self.keys.first().ok_or_else(|| anyhow!("no signing key"))?;
+
+        let mut mac = HmacSha256::new_from_slice(&key.secret).unwrap();
+        mac.update(msg.as_bytes());
+        let sig = hex::encode(mac.finalize().into_bytes());
+        Ok(match &key.id {
+            Some(id) => format!("{id}:{sig}"),
+            None => sig,
+        })
+    }
+
     fn get_message(path: &str, query: Option<&str>) -> Result<String> {
         let mut out = String::with_capacity(128);
 
@@ -56,3 +146,48 @@ impl Verifier {
         Ok(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "00112233445566778899aabbccddeeff";
+    const NEW: &str = "ffeeddccbbaa99887766554433221100";
+
+    fn verifier(keys: &[String]) -> Verifier {
+        Verifier::new(keys.iter().cloned()).unwrap()
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = verifier(&[format!("old:{OLD}")]);
+        let new = verifier(&[format!("new:{NEW}")]);
+        let both = verifier(&[format!("new:{NEW}"), format!("old:{OLD}")]);
+
+        let query = Some("url=https%3A%2F%2Fexample.com%2Fimg.png&width=100");
+        let old_sig = old.sign("/", query).unwrap();
+        let new_sig = new.sign("/", query).unwrap();
+        assert!(old_sig.starts_with("old:") && new_sig.starts_with("new:"));
+
+        // Signatures from both keys verify during a rotation.
+        both.verify("/", query, old_sig.as_bytes()).unwrap();
+        both.verify("/", query, new_sig.as_bytes()).unwrap();
+        both.verify("/", query, new_sig.as_bytes()).unwrap();
+        let stats = both.stats();
+        assert_eq!(stats[0].id.as_deref(), Some("new"));
+        assert_eq!(stats[0].verified, 2);
+        assert_eq!(stats[1].id.as_deref(), Some("old"));
+        assert_eq!(stats[1].verified, 1);
+
+        // The signature without its key id is checked against every key.
+        let (_, unprefixed) = old_sig.split_once(':').unwrap();
+        both.verify("/", query, unprefixed.as_bytes()).unwrap();
+
+        // Once the old key is removed, its signatures no longer verify.
+        assert!(new.verify("/", query, old_sig.as_bytes()).is_err());
+        assert!(new.verify("/", query, unprefixed.as_bytes()).is_err());
+        assert!(both
+            .verify("/", Some("width=100"), old_sig.as_bytes())
+            .is_err());
+    }
+}
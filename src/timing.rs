@@ -0,0 +1,48 @@
+use std::{fmt::Write, time::SystemTime};
+
+#[derive(Clone)]
+pub struct ServerTiming {
+    vals: Vec<TimingValue>,
+}
+
+#[derive(Clone)]
+struct TimingValue {
+    name: &'static str,
+    dur: f32,
+}
+
+impl ServerTiming {
+    pub fn new() -> Self {
+        Self {
+            vals: Vec::with_capacity(16),
+        }
+    }
+
+    pub fn push(&mut self, name: &'static str, start: SystemTime) {
+        let dur = Self::ms_since(start);
+        self.vals.push(TimingValue { name, dur });
+    }
+
+    pub fn extend(&mut self, other: ServerTiming) {
+        self.vals.extend(other.vals);
+    }
+
+    pub fn header(&self) -> String {
+        let mut out = String::with_capacity(128);
+        for val in &self.vals {
+            if !out.is_empty() {
+                out.push(',');
+            }
+            _ = write!(&mut out, "{};dur={:.1}", val.name, val.dur);
+        }
+        out
+    }
+
+    fn ms_since(start: SystemTime) -> f32 {
+        SystemTime::now()
+            .duration_since(start)
+            .unwrap_or_default()
+            .as_secs_f32()
+            * 1000.0
+    }
+}
@@ -0,0 +1,156 @@
+use memchr::memmem;
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Data {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rating: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+impl Data {
+    /// Locates an uncompressed XMP packet anywhere in the provided buffer and
+    /// extracts a few common fields. Returns None if no packet is found or if
+    /// it contains none of the supported fields.
+    pub fn new(buf: &[u8]) -> Option<Self> {
+        let packet = find_packet(buf)?;
+        let data = Data {
+            creator: get_value(packet, "dc:creator"),
+            rating: get_value(packet, "xmp:Rating").and_then(|v| v.trim().parse().ok()),
+            title: get_value(packet, "dc:title"),
+            description: get_value(packet, "dc:description"),
+        };
+
+        if data.creator.is_none()
+            && data.rating.is_none()
+            && data.title.is_none()
+            && data.description.is_none()
+        {
+            return None;
+        }
+        Some(data)
+    }
+}
+
+fn find_packet(buf: &[u8]) -> Option<&str> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+
+    let start = memmem::find(buf, START)?;
+    let end = memmem::find(&buf[start..], END)? + start + END.len();
+    std::str::from_utf8(&buf[start..end]).ok()
+}
+
+// returns the value for the provided property, which can either be written as
+// an attribute or as an element. For array values, the first item is used.
+fn get_value(packet: &str, name: &str) -> Option<String> {
+    if let Some(value) = get_attribute(packet, name) {
+        return Some(value);
+    }
+
+    let mut content = get_element(packet, name)?;
+    if let Some(item) = get_element(content, "rdf:li") {
+        content = item;
+    }
+    let content = content.trim();
+    if content.is_empty() || content.starts_with('<') {
+        return None;
+    }
+    Some(unescape(content))
+}
+
+fn get_attribute(packet: &str, name: &str) -> Option<String> {
+    let mut rest = packet;
+    while let Some(idx) = rest.find(name) {
+        rest = &rest[idx + name.len()..];
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            continue;
+        }
+        let value = &value[1..];
+        let end = value.find(quote)?;
+        return Some(unescape(&value[..end]));
+    }
+    None
+}
+
+fn get_element<'a>(packet: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}");
+    let close = format!("</{name}>");
+
+    let mut rest = packet;
+    while let Some(idx) = rest.find(&open) {
+        rest = &rest[idx + open.len()..];
+        // Ensure the full element name matched, and not just a prefix.
+        if !rest.starts_with(['>', ' ', '\t', '\r', '\n']) {
+            continue;
+        }
+        let start = rest.find('>')?;
+        if rest[..start].ends_with('/') {
+            continue;
+        }
+        let content = &rest[start + 1..];
+        let end = content.find(&close)?;
+        return Some(&content[..end]);
+    }
+    None
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // returns the start of a JPEG with the XMP packet in an APP1 segment.
+    fn jpeg(packet: &str) -> Vec<u8> {
+        let mut payload = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+        payload.extend_from_slice(packet.as_bytes());
+        let mut buf = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        buf.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        buf.extend_from_slice(&[0xFF, 0xDA]);
+        buf
+    }
+
+    #[test]
+    fn test_packet() {
+        let packet = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmp:Rating = '4'>
+   <dc:creator><rdf:Seq><rdf:li>Jane &amp; John</rdf:li></rdf:Seq></dc:creator>
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Harbor</rdf:li></rdf:Alt></dc:title>
+   <dc:description/>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+        let data = Data::new(&jpeg(packet)).unwrap();
+        assert_eq!(data.rating, Some(4));
+        assert_eq!(data.creator.as_deref(), Some("Jane & John"));
+        assert_eq!(data.title.as_deref(), Some("Harbor"));
+        assert_eq!(data.description, None);
+
+        // Packets without any of the fields, and images without a packet, are
+        // skipped.
+        let empty = r#"<x:xmpmeta><rdf:Description xmp:CreatorTool="x"/></x:xmpmeta>"#;
+        assert!(Data::new(&jpeg(empty)).is_none());
+        assert!(Data::new(&[0xFF, 0xD8, 0xFF, 0xDA]).is_none());
+    }
+}